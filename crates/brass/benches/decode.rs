@@ -0,0 +1,28 @@
+//! Decode time of `example/bin/test.hl`, in place (`read`, `read_lazy`)
+//! against the old per-item `Code` copying (`read_cloning`).
+//!
+//! Run with `cargo bench -p brass --features decode-baseline --bench decode`.
+
+use brass::Code;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+static TEST_HL: &[u8] = include_bytes!("../../../example/bin/test.hl");
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode test.hl");
+    group.bench_function("read", |b| {
+        b.iter(|| Code::read(black_box(TEST_HL)).unwrap())
+    });
+    group.bench_function("read_lazy", |b| {
+        b.iter(|| Code::read_lazy(black_box(TEST_HL)).unwrap())
+    });
+    // Each baseline iteration takes around half a second.
+    group.sample_size(10);
+    group.bench_function("read_cloning (baseline)", |b| {
+        b.iter(|| Code::read_cloning(black_box(TEST_HL)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,1300 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A line based assembly language for HashLink bytecode, so that tests can
+//! build a `Code` without the Haxe compiler.
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! version 4                                ; optional, 4 by default
+//! type Point = obj Point { x: i32, y: i32 } ; named, usable before this line
+//! global Point                             ; globals, in index order
+//! native std log 2 : fun(bytes) -> void    ; lib, name, findex and type
+//! entry 0                                  ; optional, the first function
+//!
+//! fun 0 : fun(i32, i32) -> i32
+//!     regs i32, i32, i32                   ; arguments come first
+//!     add r2, r0, r1
+//!     jslt r2, r0, done                    ; jumps take labels or offsets
+//!     incr r2
+//! done:
+//!     ret r2
+//! end
+//! ```
+//!
+//! Mnemonics are the `Op` names without their `O`, in lower case, and
+//! operands follow the opcode layout. Constants are written inline and
+//! added to their pool: `int r0, 42`, `float r0, 1.5`, `string r0, "hi"`,
+//! `bytes r0, "raw"`, `type r0, null<i32>`, `dynget r0, r1, "name"`.
+//! `switch r0, [a, b], end` takes a list of jump targets.
+//!
+//! Types are `void`, `u8`, `u16`, `i32`, `i64`, `f32`, `f64`, `bool`,
+//! `bytes`, `dynamic`, `array`, `type`, `dynobj`, `fun(T, ...) -> T`,
+//! `method(T, ...) -> T`, `ref<T>`, `null<T>`, `packed<T>`,
+//! `virtual { name: T, ... }`, `abstract Name`, `enum Name { A, B(T, ...) }`
+//! and `obj Name extends T { field: T, method name = findex @pindex,
+//! bind field = findex }`, `struct` being written like `obj`.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crc32fast::hash;
+
+use crate::code::Code;
+use crate::errors::{AsmError, AsmErrorKind};
+use crate::instr::Instr;
+use crate::native::Native;
+use crate::op::{Op, Opcode, OP_NARGS};
+use crate::types::{
+    Constant, EnumConstruct, HLFunction, ObjField, ObjProto, TypeId, TypeKind, ValueType,
+    ValueTypeU,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i64),
+    Float(f64),
+    /// The bytes of a quoted literal, which `\xNN` escapes can make
+    /// invalid UTF-8.
+    Str(Vec<u8>),
+    Punct(char),
+    Arrow,
+}
+
+impl Token {
+    fn text(&self) -> String {
+        match self {
+            Token::Ident(s) => s.clone(),
+            Token::Int(i) => i.to_string(),
+            Token::Float(f) => f.to_string(),
+            Token::Str(s) => format!("{:?}", String::from_utf8_lossy(s)),
+            Token::Punct(c) => c.to_string(),
+            Token::Arrow => "->".to_string(),
+        }
+    }
+}
+
+fn tokenize(text: &str, line: usize) -> Result<Vec<Token>, AsmError> {
+    let error = |kind| AsmError::new(kind, line);
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut s = Vec::new();
+                let mut buf = [0; 4];
+                loop {
+                    match chars
+                        .next()
+                        .ok_or_else(|| error(AsmErrorKind::UnterminatedString))?
+                    {
+                        '"' => break,
+                        '\\' => {
+                            let escape = chars
+                                .next()
+                                .ok_or_else(|| error(AsmErrorKind::UnterminatedString))?;
+                            let c = match escape {
+                                'n' => '\n',
+                                't' => '\t',
+                                'r' => '\r',
+                                '0' => '\0',
+                                'x' => {
+                                    let hex: String = chars.by_ref().take(2).collect();
+                                    s.push(u8::from_str_radix(&hex, 16).map_err(|_| {
+                                        error(AsmErrorKind::InvalidNumber).with_token(hex)
+                                    })?);
+                                    continue;
+                                }
+                                c => c,
+                            };
+                            s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                        }
+                        c => s.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            '-' if text_after(&chars, '>') => {
+                chars.next();
+                chars.next();
+                tokens.push(Token::Arrow);
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut s = String::new();
+                s.push(c);
+                chars.next();
+                while let Some(&c) = chars.peek() {
+                    let exponent = (c == '-' || c == '+') && s.ends_with(['e', 'E']);
+                    if c.is_ascii_alphanumeric() || c == '.' || c == '_' || exponent {
+                        s.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(
+                    parse_number(&s)
+                        .ok_or_else(|| error(AsmErrorKind::InvalidNumber).with_token(s))?,
+                );
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let mut s = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '$' || c == '.' {
+                        s.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(s));
+            }
+            c => {
+                chars.next();
+                tokens.push(Token::Punct(c));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn text_after(chars: &std::iter::Peekable<std::str::Chars<'_>>, next: char) -> bool {
+    let mut ahead = chars.clone();
+    ahead.next();
+    ahead.next() == Some(next)
+}
+
+fn parse_number(s: &str) -> Option<Token> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let int = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => digits.parse::<i64>().ok(),
+    };
+    match int {
+        Some(i) => Some(Token::Int(if negative { -i } else { i })),
+        None => s.parse::<f64>().ok().map(Token::Float),
+    }
+}
+
+/// The tokens of one line.
+struct Cursor {
+    tokens: Vec<Token>,
+    pos: usize,
+    line: usize,
+}
+
+impl Cursor {
+    fn error(&self, kind: AsmErrorKind) -> AsmError {
+        let e = AsmError::new(kind, self.line);
+        match self.tokens.get(self.pos) {
+            Some(t) => e.with_token(t.text()),
+            None => e,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, AsmError> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| AsmError::new(AsmErrorKind::UnexpectedEndOfLine, self.line))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn finish(&self) -> Result<(), AsmError> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            Err(self.error(AsmErrorKind::UnexpectedToken))
+        }
+    }
+
+    /// Consumes `c` if it is the next token.
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), AsmError> {
+        if self.eat(c) {
+            Ok(())
+        } else if self.at_end() {
+            Err(AsmError::new(AsmErrorKind::UnexpectedEndOfLine, self.line))
+        } else {
+            Err(self.error(AsmErrorKind::UnexpectedToken))
+        }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Ident(s)) if s == keyword) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn ident(&mut self) -> Result<String, AsmError> {
+        match self.peek() {
+            Some(Token::Ident(_)) => match self.next()? {
+                Token::Ident(s) => Ok(s),
+                _ => unreachable!(),
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    /// A name, either bare or quoted.
+    fn name(&mut self) -> Result<String, AsmError> {
+        match self.peek() {
+            Some(Token::Str(_)) => match self.next()? {
+                Token::Str(s) => self.utf8(s),
+                _ => unreachable!(),
+            },
+            _ => self.ident(),
+        }
+    }
+
+    fn int(&mut self) -> Result<i64, AsmError> {
+        match self.peek() {
+            Some(Token::Int(i)) => {
+                let i = *i;
+                self.pos += 1;
+                Ok(i)
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    /// Text of a quoted literal, which must be valid UTF-8.
+    fn utf8(&self, s: Vec<u8>) -> Result<String, AsmError> {
+        String::from_utf8(s).map_err(|e| {
+            AsmError::new(AsmErrorKind::InvalidString, self.line)
+                .with_token(Token::Str(e.into_bytes()).text())
+        })
+    }
+
+    fn unexpected(&self) -> AsmError {
+        if self.at_end() {
+            AsmError::new(AsmErrorKind::UnexpectedEndOfLine, self.line)
+        } else {
+            self.error(AsmErrorKind::UnexpectedToken)
+        }
+    }
+
+    /// Comma separated items up to the end of the line.
+    fn list_until_end<T>(
+        &mut self,
+        mut item: impl FnMut(&mut Self) -> Result<T, AsmError>,
+    ) -> Result<Vec<T>, AsmError> {
+        let mut items = Vec::new();
+        while !self.at_end() {
+            items.push(item(self)?);
+            if !self.at_end() {
+                self.expect(',')?;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Parses `item` repeatedly up to the closing `close`, with commas in
+    /// between.
+    fn list<T>(
+        &mut self,
+        close: char,
+        mut item: impl FnMut(&mut Self) -> Result<T, AsmError>,
+    ) -> Result<Vec<T>, AsmError> {
+        let mut items = Vec::new();
+        if self.eat(close) {
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.eat(close) {
+                return Ok(items);
+            }
+            self.expect(',')?;
+        }
+    }
+}
+
+/// Where a jump offset goes once its label is known.
+#[derive(Clone, Copy)]
+enum Slot {
+    P1,
+    P2,
+    P3,
+    Extra(usize),
+}
+
+struct Fixup {
+    op: usize,
+    slot: Slot,
+    label: String,
+    line: usize,
+}
+
+struct Function {
+    f: HLFunction,
+    labels: HashMap<String, usize>,
+    fixups: Vec<Fixup>,
+    line: usize,
+}
+
+/// How an operand is written in the source.
+#[derive(Clone, Copy, PartialEq)]
+enum Operand {
+    /// A register, index or plain number.
+    Raw,
+    Int,
+    Float,
+    String,
+    Bytes,
+    Type,
+    Jump,
+    /// A bracketed list of jumps.
+    Jumps,
+    /// Any number of registers.
+    Args,
+}
+
+fn operands(op: Op) -> Vec<Operand> {
+    use Operand::*;
+    match op {
+        Op::OInt => vec![Raw, Int],
+        Op::OFloat => vec![Raw, Float],
+        Op::OString => vec![Raw, String],
+        Op::OBytes => vec![Raw, Bytes],
+        Op::OType => vec![Raw, Type],
+        Op::ODynGet => vec![Raw, Raw, String],
+        Op::ODynSet => vec![Raw, String, Raw],
+        Op::OJTrue | Op::OJFalse | Op::OJNull | Op::OJNotNull | Op::OTrap => vec![Raw, Jump],
+        Op::OJSLt
+        | Op::OJSGte
+        | Op::OJSGt
+        | Op::OJSLte
+        | Op::OJULt
+        | Op::OJUGte
+        | Op::OJNotLt
+        | Op::OJNotGte
+        | Op::OJEq
+        | Op::OJNotEq => vec![Raw, Raw, Jump],
+        Op::OJAlways => vec![Jump],
+        Op::OSwitch => vec![Raw, Jumps, Jump],
+        Op::OCallN | Op::OCallMethod | Op::OCallThis | Op::OCallClosure | Op::OMakeEnum => {
+            vec![Raw, Raw, Args]
+        }
+        op => vec![Raw; OP_NARGS[op as usize] as usize],
+    }
+}
+
+/// A label, resolved at the end of the function, or a raw offset.
+fn jump(c: &mut Cursor, slot: Slot, jumps: &mut Vec<(Slot, String)>) -> Result<i32, AsmError> {
+    match c.next()? {
+        Token::Int(offset) => i32::try_from(offset).map_err(|_| {
+            AsmError::new(AsmErrorKind::InvalidNumber, c.line).with_token(offset.to_string())
+        }),
+        Token::Ident(label) => {
+            jumps.push((slot, label));
+            Ok(0)
+        }
+        _ => {
+            c.pos -= 1;
+            Err(c.error(AsmErrorKind::UnexpectedToken))
+        }
+    }
+}
+
+fn mnemonic(op: Op) -> String {
+    let name: &'static str = op.into();
+    name[1..].to_lowercase()
+}
+
+struct Assembler {
+    code: Code,
+    named: HashMap<String, TypeId>,
+    mnemonics: HashMap<String, Op>,
+    entry: Option<u32>,
+}
+
+impl Code {
+    /// Builds a `Code` from assembly source, in the format described in
+    /// the `asm` module.
+    pub fn assemble(source: &str) -> Result<Code, AsmError> {
+        let mut lines = Vec::new();
+        for (i, text) in source.lines().enumerate() {
+            let tokens = tokenize(text, i + 1)?;
+            if !tokens.is_empty() {
+                lines.push(Cursor {
+                    tokens,
+                    pos: 0,
+                    line: i + 1,
+                });
+            }
+        }
+
+        let mut asm = Assembler {
+            code: Code::new(),
+            named: HashMap::new(),
+            mnemonics: (0..Op::OLast as u8)
+                .filter_map(|n| Op::try_from(n).ok())
+                .map(|op| (mnemonic(op), op))
+                .collect(),
+            entry: None,
+        };
+        asm.code.version = 4;
+        asm.declare_types(&mut lines)?;
+
+        let mut current: Option<Function> = None;
+        for mut c in lines {
+            match current.as_mut() {
+                Some(f) => {
+                    if c.eat_keyword("end") {
+                        c.finish()?;
+                        let f = current.take().unwrap();
+                        asm.end_function(f)?;
+                    } else {
+                        asm.function_line(f, &mut c)?;
+                    }
+                }
+                None => {
+                    if let Some(f) = asm.directive(&mut c)? {
+                        current = Some(f);
+                    }
+                }
+            }
+        }
+        if let Some(f) = current {
+            return Err(AsmError::new(AsmErrorKind::OutsideFunction, f.line).with_token("fun"));
+        }
+        Ok(asm.finish())
+    }
+}
+
+impl Assembler {
+    /// Reserves the named types first, so they can be referred to before
+    /// their declaration.
+    fn declare_types(&mut self, lines: &mut [Cursor]) -> Result<(), AsmError> {
+        for c in lines.iter() {
+            if let [Token::Ident(kw), Token::Ident(name), Token::Punct('='), ..] = &c.tokens[..] {
+                if kw == "type" {
+                    if self.named.contains_key(name) {
+                        return Err(AsmError::new(AsmErrorKind::DuplicateType, c.line)
+                            .with_token(name.clone()));
+                    }
+                    self.named
+                        .insert(name.clone(), TypeId(self.code.types.len() as u32));
+                    self.code.types.push(ValueType::default());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, c: &mut Cursor) -> Result<Option<Function>, AsmError> {
+        let keyword = c.ident()?;
+        match keyword.as_str() {
+            "version" => {
+                let v = c.int()?;
+                if !(2..=5).contains(&v) {
+                    return Err(AsmError::new(AsmErrorKind::InvalidNumber, c.line)
+                        .with_token(v.to_string()));
+                }
+                self.code.version = v as u8;
+            }
+            "type" => {
+                let name = c.ident()?;
+                c.expect('=')?;
+                let t = self.parse_type_value(c)?;
+                self.code.types[self.named[&name].index()] = t;
+            }
+            "global" => {
+                let t = self.parse_type(c)?;
+                self.code.globals.push(t);
+            }
+            "native" => {
+                let lib = c.name()?;
+                let name = c.name()?;
+                let findex = c.int()? as usize;
+                c.expect(':')?;
+                let t = self.parse_type(c)?;
+                self.string(&lib);
+                self.string(&name);
+                self.code.natives.push(Native {
+                    lib,
+                    name,
+                    t,
+                    findex,
+                });
+            }
+            "entry" => self.entry = Some(c.int()? as u32),
+            "constant" => {
+                let global = c.int()? as u32;
+                let mut fields = Vec::new();
+                while !c.at_end() {
+                    fields.push(c.int()? as u32);
+                }
+                self.code.constants.push(Constant {
+                    global,
+                    nfields: fields.len(),
+                    fields,
+                });
+            }
+            "fun" => {
+                let findex = c.int()? as usize;
+                c.expect(':')?;
+                let t = self.parse_type(c)?;
+                c.finish()?;
+                return Ok(Some(Function {
+                    f: HLFunction {
+                        t,
+                        findex,
+                        nregs: 0,
+                        nops: 0,
+                        rf: 0,
+                        regs: Vec::new(),
+                        ops: Vec::new(),
+                        debug: Vec::new(),
+                        assigns: Vec::new(),
+                        obj: None,
+                        field: None,
+                    },
+                    labels: HashMap::new(),
+                    fixups: Vec::new(),
+                    line: c.line,
+                }));
+            }
+            "end" => {
+                return Err(AsmError::new(AsmErrorKind::OutsideFunction, c.line).with_token("end"))
+            }
+            _ => {
+                c.pos -= 1;
+                return Err(if self.mnemonics.contains_key(&keyword) {
+                    AsmError::new(AsmErrorKind::OutsideFunction, c.line).with_token(keyword)
+                } else {
+                    c.error(AsmErrorKind::UnknownDirective)
+                });
+            }
+        }
+        c.finish()?;
+        Ok(None)
+    }
+
+    fn function_line(&mut self, f: &mut Function, c: &mut Cursor) -> Result<(), AsmError> {
+        if let [Token::Ident(label), Token::Punct(':')] = &c.tokens[..] {
+            if f.labels.insert(label.clone(), f.f.ops.len()).is_some() {
+                return Err(
+                    AsmError::new(AsmErrorKind::DuplicateLabel, c.line).with_token(label.clone())
+                );
+            }
+            return Ok(());
+        }
+        let name = c.ident()?;
+        if name == "regs" {
+            let mut regs = c.list_until_end(|c| self.parse_type(c))?;
+            f.f.regs.append(&mut regs);
+            return Ok(());
+        }
+        let op = match self.mnemonics.get(&name) {
+            Some(op) => *op,
+            None => {
+                c.pos -= 1;
+                return Err(c.error(AsmErrorKind::UnknownMnemonic));
+            }
+        };
+        let o = self.parse_op(f, op, c)?;
+        f.f.ops.push(o);
+        Ok(())
+    }
+
+    fn parse_op(&mut self, f: &mut Function, op: Op, c: &mut Cursor) -> Result<Opcode, AsmError> {
+        let line = c.line;
+        let pos = f.f.ops.len();
+        let wrong_count =
+            || AsmError::new(AsmErrorKind::OperandCount, line).with_token(mnemonic(op));
+        let layout = operands(op);
+        let mut values: Vec<i32> = Vec::new();
+        let mut args: Vec<i32> = Vec::new();
+        let mut jumps: Vec<(Slot, String)> = Vec::new();
+        let mut targets: Vec<i32> = Vec::new();
+
+        for (i, kind) in layout.iter().enumerate() {
+            if c.at_end() && *kind == Operand::Args {
+                break;
+            }
+            if i > 0 {
+                if c.at_end() {
+                    return Err(wrong_count());
+                }
+                c.expect(',')?;
+            } else if c.at_end() {
+                return Err(wrong_count());
+            }
+            let slot = match values.len() {
+                0 => Slot::P1,
+                1 => Slot::P2,
+                2 => Slot::P3,
+                n => Slot::Extra(n - 3),
+            };
+            match kind {
+                Operand::Raw => values.push(self.raw(c)?),
+                Operand::Int => {
+                    let v = c.int()?;
+                    let v = i32::try_from(v).map_err(|_| {
+                        AsmError::new(AsmErrorKind::InvalidNumber, line).with_token(v.to_string())
+                    })?;
+                    values.push(self.int(v));
+                }
+                Operand::Float => {
+                    let v = match c.next()? {
+                        Token::Float(v) => v,
+                        Token::Int(v) => v as f64,
+                        _ => {
+                            c.pos -= 1;
+                            return Err(c.error(AsmErrorKind::UnexpectedToken));
+                        }
+                    };
+                    values.push(self.float(v));
+                }
+                Operand::String => {
+                    let s = self.quoted(c)?;
+                    let s = c.utf8(s)?;
+                    values.push(self.string(&s) as i32);
+                }
+                Operand::Bytes => {
+                    let s = self.quoted(c)?;
+                    let index = if self.code.version < 5 {
+                        let s = c.utf8(s)?;
+                        self.string(&s)
+                    } else {
+                        self.bytes(&s)
+                    };
+                    values.push(index as i32);
+                }
+                Operand::Type => values.push(self.parse_type(c)?.0 as i32),
+                Operand::Jump => {
+                    // the end of a switch follows its targets
+                    let slot = if op == Op::OSwitch { Slot::P3 } else { slot };
+                    let offset = jump(c, slot, &mut jumps)?;
+                    values.push(offset);
+                }
+                Operand::Jumps => {
+                    c.expect('[')?;
+                    let mut index = 0;
+                    targets = c.list(']', |c| {
+                        let offset = jump(c, Slot::Extra(index), &mut jumps)?;
+                        index += 1;
+                        Ok(offset)
+                    })?;
+                }
+                Operand::Args => {
+                    args.push(self.raw(c)?);
+                    while c.eat(',') {
+                        args.push(self.raw(c)?);
+                    }
+                }
+            }
+        }
+        if !c.at_end() {
+            return Err(if c.peek() == Some(&Token::Punct(',')) {
+                wrong_count()
+            } else {
+                c.error(AsmErrorKind::UnexpectedToken)
+            });
+        }
+
+        let mut o = Opcode {
+            op,
+            ..Opcode::default()
+        };
+        match op {
+            Op::OSwitch => {
+                o.p1 = Some(values[0]);
+                o.p2 = Some(targets.len() as i32);
+                o.extra = targets.iter().map(|t| *t as isize).collect();
+                o.p3 = Some(values[1]);
+            }
+            Op::OCallN | Op::OCallMethod | Op::OCallThis | Op::OCallClosure | Op::OMakeEnum => {
+                if args.len() > u8::MAX as usize {
+                    return Err(wrong_count());
+                }
+                o.p1 = Some(values[0]);
+                o.p2 = Some(values[1]);
+                o.p3 = Some(args.len() as i32);
+                o.extra = args.iter().map(|a| *a as isize).collect();
+            }
+            _ => {
+                let mut values = values.into_iter();
+                o.p1 = values.next();
+                o.p2 = values.next();
+                o.p3 = values.next();
+                o.extra = values.map(|v| v as isize).collect();
+            }
+        }
+        f.fixups
+            .extend(jumps.into_iter().map(|(slot, label)| Fixup {
+                op: pos,
+                slot,
+                label,
+                line,
+            }));
+        Ok(o)
+    }
+
+    /// A register such as `r3`, a number, or `true` and `false`.
+    fn raw(&self, c: &mut Cursor) -> Result<i32, AsmError> {
+        let value = match c.next()? {
+            Token::Int(i) => i,
+            Token::Ident(s) if s == "true" => 1,
+            Token::Ident(s) if s == "false" => 0,
+            Token::Ident(s) if s.starts_with('r') && s[1..].parse::<u32>().is_ok() => {
+                s[1..].parse().unwrap()
+            }
+            _ => {
+                c.pos -= 1;
+                return Err(c.error(AsmErrorKind::UnexpectedToken));
+            }
+        };
+        i32::try_from(value).map_err(|_| {
+            AsmError::new(AsmErrorKind::InvalidNumber, c.line).with_token(value.to_string())
+        })
+    }
+
+    fn quoted(&self, c: &mut Cursor) -> Result<Vec<u8>, AsmError> {
+        match c.next()? {
+            Token::Str(s) => Ok(s),
+            _ => {
+                c.pos -= 1;
+                Err(c.error(AsmErrorKind::UnexpectedToken))
+            }
+        }
+    }
+
+    fn end_function(&mut self, mut f: Function) -> Result<(), AsmError> {
+        for fixup in &f.fixups {
+            let target = *f.labels.get(&fixup.label).ok_or_else(|| {
+                AsmError::new(AsmErrorKind::UnknownLabel, fixup.line)
+                    .with_token(fixup.label.clone())
+            })?;
+            let offset = target as i32 - (fixup.op as i32 + 1);
+            let o = &mut f.f.ops[fixup.op];
+            match fixup.slot {
+                Slot::P1 => o.p1 = Some(offset),
+                Slot::P2 => o.p2 = Some(offset),
+                Slot::P3 => o.p3 = Some(offset),
+                Slot::Extra(i) => o.extra[i] = offset as isize,
+            }
+        }
+        f.f.nregs = f.f.regs.len();
+        f.f.nops = f.f.ops.len();
+        debug_assert!(f.f.ops.iter().all(|o| Instr::try_from(o).is_ok()));
+        self.code.functions.push(f.f);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Code {
+        let c = &mut self.code;
+        c.entrypoint = self
+            .entry
+            .or_else(|| c.functions.first().map(|f| f.findex as u32))
+            .unwrap_or(0);
+        c.ntypes = c.types.len();
+        c.nints = c.ints.len();
+        c.nfloats = c.floats.len();
+        c.nstrings = c.strings.len();
+        c.strings_lens = c.strings.iter().map(|s| s.len()).collect();
+        c.ustrings = vec![OnceLock::new(); c.nstrings];
+        c.nbytes = c.bytes_pos.len();
+        c.nglobals = c.globals.len();
+        c.nnatives = c.natives.len();
+        c.nfunctions = c.functions.len();
+        c.nconstants = c.constants.len();
+        self.code
+    }
+
+    fn int(&mut self, v: i32) -> i32 {
+        let ints = &mut self.code.ints;
+        match ints.iter().position(|i| *i == v) {
+            Some(i) => i as i32,
+            None => {
+                ints.push(v);
+                ints.len() as i32 - 1
+            }
+        }
+    }
+
+    fn float(&mut self, v: f64) -> i32 {
+        let floats = &mut self.code.floats;
+        match floats.iter().position(|f| f.to_bits() == v.to_bits()) {
+            Some(i) => i as i32,
+            None => {
+                floats.push(v);
+                floats.len() as i32 - 1
+            }
+        }
+    }
+
+    fn string(&mut self, s: &str) -> usize {
+        let strings = &mut self.code.strings;
+        match strings.iter().position(|x| x == s) {
+            Some(i) => i,
+            None => {
+                strings.push(s.to_string());
+                strings.len() - 1
+            }
+        }
+    }
+
+    /// Adds a bytes constant, from version 5 on. Before, bytes constants live
+    /// in the string pool.
+    fn bytes(&mut self, s: &[u8]) -> usize {
+        let existing = (0..self.code.bytes_pos.len()).find(|i| self.code.bytes_constant(*i) == s);
+        existing.unwrap_or_else(|| {
+            self.code.bytes_pos.push(self.code.bytes.len());
+            self.code.bytes.extend_from_slice(s);
+            self.code.bytes_pos.len() - 1
+        })
+    }
+
+    /// Parses a type and returns its id, adding it to the table unless an
+    /// equal type is already there.
+    fn parse_type(&mut self, c: &mut Cursor) -> Result<TypeId, AsmError> {
+        if let Some(Token::Ident(name)) = c.peek() {
+            if let Some(t) = self.named.get(name) {
+                let t = *t;
+                c.pos += 1;
+                return Ok(t);
+            }
+        }
+        let t = self.parse_type_value(c)?;
+        let types = &mut self.code.types;
+        Ok(match types.iter().position(|x| *x == t) {
+            Some(i) => TypeId(i as u32),
+            None => {
+                types.push(t);
+                TypeId(types.len() as u32 - 1)
+            }
+        })
+    }
+
+    fn parse_type_value(&mut self, c: &mut Cursor) -> Result<ValueType, AsmError> {
+        let keyword = c.ident()?;
+        let simple = |kind| ValueType {
+            kind,
+            ..ValueType::default()
+        };
+        let kind = match keyword.as_str() {
+            "void" => TypeKind::HVOID,
+            "u8" => TypeKind::HUI8,
+            "u16" => TypeKind::HUI16,
+            "i32" => TypeKind::HI32,
+            "i64" => TypeKind::HI64,
+            "f32" => TypeKind::HF32,
+            "f64" => TypeKind::HF64,
+            "bool" => TypeKind::HBOOL,
+            "bytes" => TypeKind::HBYTES,
+            "dynamic" => TypeKind::HDYN,
+            "array" => TypeKind::HARRAY,
+            "type" => TypeKind::HTYPE,
+            "dynobj" => TypeKind::HDYNOBJ,
+            "fun" | "method" => {
+                let kind = if keyword == "fun" {
+                    TypeKind::HFUN
+                } else {
+                    TypeKind::HMETHOD
+                };
+                c.expect('(')?;
+                let args = c.list(')', |c| self.parse_type(c))?;
+                match c.next()? {
+                    Token::Arrow => {}
+                    _ => {
+                        c.pos -= 1;
+                        return Err(c.error(AsmErrorKind::UnexpectedToken));
+                    }
+                }
+                let ret = self.parse_type(c)?;
+                return Ok(ValueType {
+                    kind,
+                    union: ValueTypeU::FuncType {
+                        nargs: args.len(),
+                        args,
+                        ret,
+                    },
+                    ..ValueType::default()
+                });
+            }
+            "ref" | "null" | "packed" => {
+                let kind = match keyword.as_str() {
+                    "ref" => TypeKind::HREF,
+                    "null" => TypeKind::HNULL,
+                    _ => TypeKind::HPACKED,
+                };
+                c.expect('<')?;
+                let param = self.parse_type(c)?;
+                c.expect('>')?;
+                return Ok(ValueType {
+                    kind,
+                    tparam: Some(param),
+                    ..ValueType::default()
+                });
+            }
+            "abstract" => {
+                let name = c.name()?;
+                self.string(&name);
+                return Ok(ValueType {
+                    kind: TypeKind::HABSTRACT,
+                    abs_name: Some(name),
+                    ..ValueType::default()
+                });
+            }
+            "virtual" => {
+                c.expect('{')?;
+                let fields = c.list('}', |c| self.parse_field(c))?;
+                return Ok(ValueType {
+                    kind: TypeKind::HVIRTUAL,
+                    union: ValueTypeU::VirtualType {
+                        nfields: fields.len(),
+                        fields,
+                    },
+                    ..ValueType::default()
+                });
+            }
+            "enum" => return self.parse_enum(c),
+            "obj" | "struct" => {
+                let kind = if keyword == "obj" {
+                    TypeKind::HOBJ
+                } else {
+                    TypeKind::HSTRUCT
+                };
+                return self.parse_obj(c, kind);
+            }
+            _ => {
+                c.pos -= 1;
+                return Err(c.error(AsmErrorKind::UnknownType));
+            }
+        };
+        Ok(simple(kind))
+    }
+
+    fn parse_field(&mut self, c: &mut Cursor) -> Result<ObjField, AsmError> {
+        let name = c.name()?;
+        c.expect(':')?;
+        let t = self.parse_type(c)?;
+        self.string(&name);
+        Ok(ObjField {
+            hashed_name: hash(name.as_bytes()),
+            name,
+            t,
+        })
+    }
+
+    fn parse_enum(&mut self, c: &mut Cursor) -> Result<ValueType, AsmError> {
+        let name = c.name()?;
+        self.string(&name);
+        c.expect('{')?;
+        let constructs = c.list('}', |c| {
+            let name = c.name()?;
+            let params = if c.eat('(') {
+                c.list(')', |c| self.parse_type(c))?
+            } else {
+                Vec::new()
+            };
+            self.string(&name);
+            Ok(EnumConstruct {
+                name,
+                nparams: params.len(),
+                params,
+                size: 0,
+                hasptr: false,
+                offsets: Vec::new(),
+            })
+        })?;
+        Ok(ValueType {
+            kind: TypeKind::HENUM,
+            union: ValueTypeU::EnumType {
+                name,
+                nconstructs: constructs.len(),
+                constructs,
+                global_value: vec![0],
+            },
+            ..ValueType::default()
+        })
+    }
+
+    fn parse_obj(&mut self, c: &mut Cursor, kind: TypeKind) -> Result<ValueType, AsmError> {
+        let name = c.name()?;
+        self.string(&name);
+        let super_type = if c.eat_keyword("extends") {
+            Some(self.parse_type(c)?)
+        } else {
+            None
+        };
+        let mut fields = Vec::new();
+        let mut proto = Vec::new();
+        let mut bindings = Vec::new();
+        c.expect('{')?;
+        c.list('}', |c| {
+            if c.eat_keyword("method") {
+                let name = c.name()?;
+                c.expect('=')?;
+                let findex = c.int()? as usize;
+                let pindex = if c.eat('@') { c.int()? as i32 } else { -1 };
+                self.string(&name);
+                proto.push(ObjProto {
+                    hashed_name: hash(name.as_bytes()),
+                    name,
+                    findex,
+                    pindex,
+                });
+            } else if c.eat_keyword("bind") {
+                let field = c.int()? as u32;
+                c.expect('=')?;
+                let findex = c.int()? as u32;
+                bindings.extend([field, findex]);
+            } else {
+                fields.push(self.parse_field(c)?);
+            }
+            Ok(())
+        })?;
+        Ok(ValueType {
+            kind,
+            union: ValueTypeU::ObjType {
+                name,
+                super_type,
+                nfields: fields.len(),
+                nproto: proto.len(),
+                nbindings: bindings.len() / 2,
+                fields,
+                proto,
+                bindings,
+                global_value: vec![0],
+                rt: None,
+            },
+            ..ValueType::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::Code;
+    use crate::errors::AsmErrorKind;
+    use crate::instr::{FunIdx, Instr, Reg};
+    use crate::types::{TypeKind, ValueTypeU};
+    use crate::verify::verify;
+
+    const ADD: &str = "
+        fun 0 : fun(i32, i32) -> i32
+            regs i32, i32, i32
+            int r2, 40
+            add r2, r0, r1
+            ret r2
+        end
+    ";
+
+    #[test]
+    fn assemble_function() {
+        let code = Code::assemble(ADD).unwrap();
+        assert_eq!(code.types.len(), 2);
+        assert_eq!(code.ints, [40]);
+        assert_eq!(code.entrypoint, 0);
+        let f = &code.functions[0];
+        assert_eq!(code.type_name(f.t), "(i32, i32) -> i32");
+        assert_eq!(
+            f.instrs().unwrap(),
+            [
+                Instr::Int {
+                    dst: Reg(2),
+                    index: 0
+                },
+                Instr::Add {
+                    dst: Reg(2),
+                    a: Reg(0),
+                    b: Reg(1)
+                },
+                Instr::Ret { reg: Reg(2) },
+            ]
+        );
+        assert!(verify(&code).is_empty());
+
+        // the result can be serialized and read back
+        let read = Code::read(&code.write().unwrap()).unwrap();
+        assert_eq!(read.types, code.types);
+        assert_eq!(read.functions, code.functions);
+    }
+
+    #[test]
+    fn labels_and_switch() {
+        let code = Code::assemble(
+            "
+            fun 3 : fun(i32) -> i32
+                regs i32
+            top:
+                label
+                switch r0, [a, b], out
+            a:
+                decr r0
+                jalways top
+            b:
+                jslt r0, r0, 1
+                nop
+            out:
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let instrs = code.functions[0].instrs().unwrap();
+        assert_eq!(
+            instrs[1],
+            Instr::Switch {
+                reg: Reg(0),
+                targets: vec![0, 2],
+                end: 4
+            }
+        );
+        assert_eq!(instrs[3], Instr::JAlways { offset: -4 });
+        assert_eq!(
+            instrs[4],
+            Instr::JSLt {
+                a: Reg(0),
+                b: Reg(0),
+                offset: 1
+            }
+        );
+        assert!(verify(&code).is_empty(), "{:?}", verify(&code));
+    }
+
+    #[test]
+    fn types_natives_and_constants() {
+        let code = Code::assemble(
+            r#"
+            version 5
+            type Point = obj Point extends Base { z: f64, method length = 1 @0 }
+            type Base = obj Base { x: i32, y: i32 }
+            type Shape = enum Shape { Empty, Circle(Point, f64) }
+            global Point
+            native std log 2 : fun(bytes) -> void
+
+            fun 1 : fun(Point) -> f64
+                regs Point, f64, bytes, void
+                field r1, r0, 2
+                bytes r2, "a\x00b\xffé"
+                string r2, "log"
+                call1 r3, 2, r2
+                float r1, 0.5
+                ret r1
+            end
+            fun 0 : fun() -> void
+                regs void, Shape, null<i32>, type
+                makeenum r1, 0
+                type r3, ref<Point>
+                null r2
+                ret r0
+            end
+            entry 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(code.version, 5);
+        assert_eq!(code.entrypoint, 0);
+        assert_eq!(code.natives[0].name, "log");
+        // a \x escape is one byte, other characters their UTF-8
+        assert_eq!(code.bytes_constant(0), b"a\0b\xff\xc3\xa9");
+        assert_eq!(code.floats, [0.5]);
+        let point = code.globals[0];
+        match &code.get_type(point).union {
+            ValueTypeU::ObjType {
+                super_type: Some(s),
+                proto,
+                ..
+            } => {
+                assert_eq!(code.type_name(*s), "Base");
+                assert_eq!(proto[0].pindex, 0);
+            }
+            t => panic!("{:?}", t),
+        }
+        assert_eq!(code.type_field(point, 2).unwrap().name, "z");
+        assert!(matches!(
+            code.functions[1].instrs().unwrap()[1],
+            Instr::Type { ty, .. } if code.get_type(ty).kind == TypeKind::HREF
+        ));
+        assert!(verify(&code).is_empty(), "{:?}", verify(&code));
+
+        let read = Code::read(&code.write().unwrap()).unwrap();
+        assert_eq!(read.types, code.types);
+        assert_eq!(read.strings, code.strings);
+        assert_eq!(
+            read.functions[0].instrs().unwrap()[3],
+            Instr::Call1 {
+                dst: Reg(3),
+                fun: FunIdx(2),
+                a: Reg(2)
+            }
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |source: &str| Code::assemble(source).unwrap_err();
+
+        let e = error("fun 0 : fun() -> void\n  regs void\n  addd r0, r0, r0\nend");
+        assert_eq!(e.kind(), AsmErrorKind::UnknownMnemonic);
+        assert_eq!(e.to_string(), "line 3: unknown mnemonic `addd`");
+
+        let e = error("fun 0 : fun() -> void\n  add r0, r0\nend");
+        assert_eq!(e.to_string(), "line 2: wrong number of operands for `add`");
+
+        let e = error("fun 0 : fun() -> void\n  jalways nowhere\nend");
+        assert_eq!((e.kind(), e.line()), (AsmErrorKind::UnknownLabel, 2));
+
+        let e = error("global Missing");
+        assert_eq!(e.kind(), AsmErrorKind::UnknownType);
+
+        assert_eq!(
+            error("fun 0 : fun() -> void").kind(),
+            AsmErrorKind::OutsideFunction
+        );
+        assert_eq!(error("ret r0").kind(), AsmErrorKind::OutsideFunction);
+        assert_eq!(
+            error("string \"abc").kind(),
+            AsmErrorKind::UnterminatedString
+        );
+        // only bytes literals may hold bytes that are not UTF-8
+        let e = error("fun 0 : fun() -> void\n  regs bytes\n  string r0, \"\\xff\"\nend");
+        assert_eq!((e.kind(), e.line()), (AsmErrorKind::InvalidString, 3));
+    }
+}
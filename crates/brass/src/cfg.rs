@@ -0,0 +1,483 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Control-flow graph of a function: basic blocks, their edges, dominators
+//! and natural loops.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use crate::disasm::jump_offset;
+use crate::errors::DecodeError;
+use crate::instr::Instr;
+use crate::types::HLFunction;
+
+/// How control reaches the target of an edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EdgeKind {
+    /// Falls through to the next op, including the default case of a switch.
+    Fallthrough,
+    /// Taken branch of a jump.
+    Jump,
+    /// One of the cases of a switch.
+    Switch,
+    /// An exception thrown inside a trap, caught by its handler.
+    Exception,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Edge {
+    pub target: usize,
+    pub kind: EdgeKind,
+}
+
+/// A run of ops entered only at its first op and left only after its last.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub ops: Range<usize>,
+    pub succs: Vec<Edge>,
+    /// Blocks with an edge to this one, in block order.
+    pub preds: Vec<usize>,
+}
+
+/// A natural loop, the union of the loops of all back edges to `header`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    /// Blocks with a back edge to `header`.
+    pub latches: Vec<usize>,
+    /// Every block of the loop, `header` included, in block order.
+    pub blocks: Vec<usize>,
+}
+
+/// Blocks are numbered in op order, so block 0 is the entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    block_of: Vec<usize>,
+}
+
+impl HLFunction {
+    /// Splits the ops of this function into basic blocks.
+    pub fn cfg(&self) -> Result<Cfg, DecodeError> {
+        Ok(Cfg::new(&self.instrs()?))
+    }
+}
+
+impl Cfg {
+    /// Builds the graph of `instrs`. Jumps leaving the function are dropped.
+    ///
+    /// Every block between a trap and its handler gets an exception edge to
+    /// the handler, even when it follows an `OEndTrap` on some path, which
+    /// errs on the side of too many edges.
+    pub fn new(instrs: &[Instr]) -> Cfg {
+        let len = instrs.len();
+        let target = |pos: usize, offset: i32| {
+            let t = pos as i64 + 1 + offset as i64;
+            (t >= 0 && t < len as i64).then_some(t as usize)
+        };
+
+        let mut leader = vec![false; len + 1];
+        leader[0] = true;
+        for (pos, instr) in instrs.iter().enumerate() {
+            if let Some(t) = jump_offset(instr).and_then(|offset| target(pos, offset)) {
+                leader[t] = true;
+            }
+            if let Instr::Switch { targets, .. } = instr {
+                for t in targets.iter().filter_map(|t| target(pos, *t as i32)) {
+                    leader[t] = true;
+                }
+            }
+            if ends_block(instr) {
+                leader[pos + 1] = true;
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = vec![0; len];
+        let mut start = 0;
+        for pos in 1..=len {
+            if leader[pos] {
+                block_of[start..pos].fill(blocks.len());
+                blocks.push(BasicBlock {
+                    ops: start..pos,
+                    succs: Vec::new(),
+                    preds: Vec::new(),
+                });
+                start = pos;
+            }
+        }
+
+        // innermost trap handler covering each op
+        let mut handler = vec![None; len];
+        let mut traps: Vec<usize> = Vec::new();
+        for (pos, instr) in instrs.iter().enumerate() {
+            while traps.last().is_some_and(|h| *h <= pos) {
+                traps.pop();
+            }
+            handler[pos] = traps.last().copied();
+            if let Instr::Trap { offset, .. } = *instr {
+                if let Some(h) = target(pos, offset).filter(|h| *h > pos) {
+                    traps.push(h);
+                }
+            }
+        }
+
+        for b in 0..blocks.len() {
+            let last = blocks[b].ops.end - 1;
+            let mut succs = Vec::new();
+            let mut edge = |target: Option<usize>, kind| {
+                if let Some(target) = target {
+                    succs.push(Edge {
+                        target: block_of[target],
+                        kind,
+                    });
+                }
+            };
+            let next = (last + 1 < len).then_some(last + 1);
+            let instr = &instrs[last];
+            match *instr {
+                Instr::JAlways { offset } => edge(target(last, offset), EdgeKind::Jump),
+                Instr::Ret { .. } | Instr::Throw { .. } | Instr::Rethrow { .. } => {}
+                Instr::Switch { ref targets, .. } => {
+                    for t in targets {
+                        edge(target(last, *t as i32), EdgeKind::Switch);
+                    }
+                    edge(next, EdgeKind::Fallthrough);
+                }
+                Instr::Trap { offset, .. } => {
+                    edge(next, EdgeKind::Fallthrough);
+                    edge(target(last, offset), EdgeKind::Exception);
+                }
+                _ => {
+                    if let Some(offset) = jump_offset(instr) {
+                        edge(target(last, offset), EdgeKind::Jump);
+                    }
+                    edge(next, EdgeKind::Fallthrough);
+                }
+            }
+            edge(handler[blocks[b].ops.start], EdgeKind::Exception);
+            succs.sort();
+            succs.dedup();
+            blocks[b].succs = succs;
+        }
+
+        for b in 0..blocks.len() {
+            for i in 0..blocks[b].succs.len() {
+                let s = blocks[b].succs[i].target;
+                if blocks[s].preds.last() != Some(&b) {
+                    blocks[s].preds.push(b);
+                }
+            }
+        }
+
+        Cfg { blocks, block_of }
+    }
+
+    /// The block holding the op at `op_index`.
+    pub fn block_of(&self, op_index: usize) -> usize {
+        self.block_of[op_index]
+    }
+
+    /// Distinct successors of block `b`, whatever the kind of their edges.
+    pub fn successors(&self, b: usize) -> impl Iterator<Item = usize> + '_ {
+        let succs = &self.blocks[b].succs;
+        succs
+            .iter()
+            .enumerate()
+            .filter(move |(i, e)| !succs[..*i].iter().any(|p| p.target == e.target))
+            .map(|(_, e)| e.target)
+    }
+
+    pub fn predecessors(&self, b: usize) -> &[usize] {
+        &self.blocks[b].preds
+    }
+
+    /// Blocks reachable from the entry, in reverse postorder.
+    pub fn reverse_postorder(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.blocks.len());
+        if self.blocks.is_empty() {
+            return order;
+        }
+        let mut visited = vec![false; self.blocks.len()];
+        let mut stack = vec![(0, self.successors(0).collect::<Vec<_>>())];
+        visited[0] = true;
+        while let Some((b, succs)) = stack.last_mut() {
+            match succs.pop() {
+                Some(s) if !visited[s] => {
+                    visited[s] = true;
+                    let next = self.successors(s).collect::<Vec<_>>();
+                    stack.push((s, next));
+                }
+                Some(_) => {}
+                None => {
+                    order.push(*b);
+                    stack.pop();
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    /// Immediate dominator of every block, `None` for the entry and for
+    /// unreachable blocks.
+    pub fn dominators(&self) -> Vec<Option<usize>> {
+        // Cooper, Harvey and Kennedy, "A Simple, Fast Dominance Algorithm"
+        let rpo = self.reverse_postorder();
+        let mut rank = vec![usize::MAX; self.blocks.len()];
+        for (i, b) in rpo.iter().enumerate() {
+            rank[*b] = i;
+        }
+        let mut idom: Vec<Option<usize>> = vec![None; self.blocks.len()];
+        if let Some(entry) = rpo.first() {
+            idom[*entry] = Some(*entry);
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &b in rpo.iter().skip(1) {
+                let mut new_idom = None;
+                for &p in &self.blocks[b].preds {
+                    if idom[p].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => p,
+                        Some(mut other) => {
+                            let mut p = p;
+                            while p != other {
+                                while rank[p] > rank[other] {
+                                    p = idom[p].unwrap();
+                                }
+                                while rank[other] > rank[p] {
+                                    other = idom[other].unwrap();
+                                }
+                            }
+                            p
+                        }
+                    });
+                }
+                if new_idom != idom[b] {
+                    idom[b] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        if let Some(entry) = rpo.first() {
+            idom[*entry] = None;
+        }
+        idom
+    }
+
+    /// Whether block `a` dominates block `b`, given the result of `dominators`.
+    pub fn dominates(idom: &[Option<usize>], a: usize, mut b: usize) -> bool {
+        loop {
+            if a == b {
+                return true;
+            }
+            match idom[b] {
+                Some(d) => b = d,
+                None => return false,
+            }
+        }
+    }
+
+    /// Natural loops, ordered by header. Exception edges never close a loop.
+    pub fn loops(&self) -> Vec<Loop> {
+        let idom = self.dominators();
+        let reachable = self.reverse_postorder();
+        let mut loops: Vec<Loop> = Vec::new();
+        for &b in &reachable {
+            for e in &self.blocks[b].succs {
+                let h = e.target;
+                if e.kind == EdgeKind::Exception || !Cfg::dominates(&idom, h, b) {
+                    continue;
+                }
+                let i = match loops.iter().position(|l| l.header == h) {
+                    Some(i) => i,
+                    None => {
+                        loops.push(Loop {
+                            header: h,
+                            latches: Vec::new(),
+                            blocks: vec![h],
+                        });
+                        loops.len() - 1
+                    }
+                };
+                let l = &mut loops[i];
+                if !l.latches.contains(&b) {
+                    l.latches.push(b);
+                }
+                // walk backwards from the latch until the header
+                let mut stack = vec![b];
+                while let Some(n) = stack.pop() {
+                    if l.blocks.contains(&n) {
+                        continue;
+                    }
+                    l.blocks.push(n);
+                    stack.extend(self.blocks[n].preds.iter().filter(|p| idom[**p].is_some()));
+                }
+            }
+        }
+        for l in &mut loops {
+            l.latches.sort_unstable();
+            l.blocks.sort_unstable();
+        }
+        loops.sort_by_key(|l| l.header);
+        loops
+    }
+
+    /// Renders the graph in Graphviz DOT, one node per block listing its ops.
+    pub fn to_dot(&self, f: &HLFunction) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph fun{} {{", f.findex).unwrap();
+        writeln!(out, "  node [shape=box, fontname=monospace];").unwrap();
+        for (b, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("b{}\\l", b);
+            for pos in block.ops.clone() {
+                let name: &'static str = f.ops[pos].op.into();
+                write!(label, "{}: {}\\l", pos, name).unwrap();
+            }
+            writeln!(out, "  b{} [label=\"{}\"];", b, label).unwrap();
+        }
+        for (b, block) in self.blocks.iter().enumerate() {
+            for e in &block.succs {
+                let style = match e.kind {
+                    EdgeKind::Fallthrough => "",
+                    EdgeKind::Jump => " [color=blue]",
+                    EdgeKind::Switch => " [color=darkgreen]",
+                    EdgeKind::Exception => " [style=dashed, color=red]",
+                };
+                writeln!(out, "  b{} -> b{}{};", b, e.target, style).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+fn ends_block(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::Ret { .. }
+            | Instr::Throw { .. }
+            | Instr::Rethrow { .. }
+            | Instr::Switch { .. }
+            | Instr::Trap { .. }
+            | Instr::EndTrap { .. }
+    ) || jump_offset(instr).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cfg, EdgeKind};
+    use crate::code::Code;
+
+    fn cfg(source: &str) -> Cfg {
+        let code = Code::assemble(source).unwrap();
+        code.functions[0].cfg().unwrap()
+    }
+
+    #[test]
+    fn branches_and_loops() {
+        let cfg = cfg(ASM_LOOP);
+        // entry, loop header, body, exit
+        assert_eq!(cfg.blocks.len(), 4);
+        assert_eq!(cfg.predecessors(1), &[0, 2]);
+        let succs: Vec<_> = cfg.successors(1).collect();
+        assert_eq!(succs, [2, 3]);
+        assert_eq!(cfg.dominators(), [None, Some(0), Some(1), Some(1)]);
+
+        let loops = cfg.loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!(loops[0].header, 1);
+        assert_eq!(loops[0].latches, [2]);
+        assert_eq!(loops[0].blocks, [1, 2]);
+    }
+
+    #[test]
+    fn traps_and_switches() {
+        let cfg = cfg(ASM_TRAP);
+        let kinds: Vec<_> = cfg.blocks[0].succs.iter().map(|e| e.kind).collect();
+        assert_eq!(kinds, [EdgeKind::Fallthrough, EdgeKind::Exception]);
+        let handler = cfg.blocks[0].succs[1].target;
+        // the switch inside the trap may throw to the handler too
+        let switch = cfg.block_of(1);
+        assert!(cfg.blocks[switch]
+            .succs
+            .iter()
+            .any(|e| e.kind == EdgeKind::Exception && e.target == handler));
+        assert_eq!(
+            cfg.blocks[switch]
+                .succs
+                .iter()
+                .filter(|e| e.kind == EdgeKind::Switch)
+                .count(),
+            2
+        );
+        assert!(cfg.loops().is_empty());
+
+        let dot = cfg.to_dot(&Code::assemble(ASM_TRAP).unwrap().functions[0]);
+        assert!(dot.starts_with("digraph fun0 {"));
+        assert!(dot.contains("[style=dashed, color=red]"));
+    }
+
+    #[test]
+    fn example_functions() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let code = Code::from_path(path).unwrap();
+        for f in &code.functions {
+            let cfg = f.cfg().unwrap();
+            let ops: usize = cfg.blocks.iter().map(|b| b.ops.len()).sum();
+            assert_eq!(ops, f.ops.len());
+            let idom = cfg.dominators();
+            for b in cfg.reverse_postorder() {
+                assert!(Cfg::dominates(&idom, 0, b));
+            }
+        }
+    }
+
+    const ASM_LOOP: &str = "
+        fun 0 : fun(i32) -> i32
+            regs i32, i32
+            int r1, 0
+        top:
+            label
+            jsgte r1, r0, out
+            incr r1
+            jalways top
+        out:
+            ret r1
+        end
+    ";
+
+    const ASM_TRAP: &str = "
+        fun 0 : fun(i32) -> i32
+            regs i32, dynamic
+            trap r1, catch
+            switch r0, [a, b], out
+            endtrap 0
+            ret r0
+        a:
+            incr r0
+        b:
+            endtrap 0
+            ret r0
+        catch:
+        out:
+            ret r0
+        end
+    ";
+}
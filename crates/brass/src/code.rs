@@ -1,14 +1,23 @@
 #![allow(arithmetic_overflow)]
 
 
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+
 use crc32fast::hash;
 
 use crate::decoder::{Decode, Decoder};
-use crate::errors::{DecodeError, DecodeErrorKind};
+use crate::encoder::Encoder;
+use crate::errors::{
+    DecodeError, DecodeErrorKind, DecodeSection, EncodeError, EncodeErrorKind, LoadError,
+};
 use crate::native::Native;
 use crate::op::{Op, Opcode, OP_NARGS};
 use crate::types::{
-    Constant, EnumConstruct, HLFunction, ObjField, ObjProto, TypeKind, ValueType, ValueTypeU,
+    Constant, EnumConstruct, HLFunction, ObjField, ObjProto, TypeId, TypeKind, ValueType,
+    ValueTypeU, VarAssign,
 };
 
 // Copyright 2022 Zenturi Software Co.
@@ -25,58 +34,64 @@ use crate::types::{
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-static HLVERSION: u32 = 0x010C00;
-static mut ERRORS:Option<DecodeError> = None;
+#[derive(Clone, Debug)]
+pub struct Code {
+    pub(crate) types: Vec<ValueType>,
+    pub(crate) ntypes: usize,
+    pub(crate) strings: Vec<String>,
+    pub(crate) strings_lens: Vec<usize>,
+    /// UTF-16 copies of `strings`, built on first use by `get_ustring`.
+    pub(crate) ustrings: Vec<OnceLock<Vec<u16>>>,
+    pub(crate) nstrings: usize,
+    pub(crate) nints: usize,
+    pub(crate) ints: Vec<i32>,
+    pub(crate) nfloats: usize,
+    pub(crate) floats: Vec<f64>,
+    pub(crate) nbytes: usize,
+    pub(crate) nfunctions: usize,
+    pub(crate) functions: Vec<HLFunction>,
+    pub(crate) nconstants: usize,
+    pub(crate) constants: Vec<Constant>,
+    pub(crate) entrypoint: u32,
+    pub(crate) nglobals: usize,
+    pub(crate) globals: Vec<TypeId>,
+    pub(crate) nnatives: usize,
+    pub(crate) natives: Vec<Native>,
+    pub(crate) hasdebug: u32,
+    pub(crate) version: u8,
+    pub(crate) bytes: Vec<u8>,
+    pub(crate) bytes_pos: Vec<usize>,
+    pub(crate) ndebugfiles: usize,
+    pub(crate) debugfiles: Vec<String>,
+    pub(crate) debugfiles_lens: Vec<usize>,
+    lazy: Option<LazyFunctions>,
+}
 
-fn UINDEX(decoder: &mut Decoder) -> Result<usize, DecodeError> {
-    let i = INDEX(decoder).map_err(Code::map_err).unwrap();
-    if i < 0 {
-        // println!("Negative Index {}", i);
-        unsafe {
-            ERRORS = Some(DecodeError::with_info(
-                DecodeErrorKind::NegativeIndex,
-                decoder.file_position,
-            ));
-        }
-        return Ok(0);
-    }
-    Ok(i.try_into().unwrap())
+/// A line in one of the debug source files.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceLocation<'a> {
+    pub file: &'a str,
+    pub line: u32,
 }
 
-fn INDEX(decoder: &mut Decoder) -> Result<i32, DecodeError> {
-    let i = decoder.read_index().unwrap();
-    Ok(i)
+/// Function bodies left undecoded by `Code::read_lazy`.
+#[derive(Clone, Debug)]
+struct LazyFunctions {
+    source: Arc<[u8]>,
+    bodies: Vec<LazyBody>,
 }
 
 #[derive(Clone, Debug)]
-pub struct Code {
-    pub types: Vec<ValueType>,
-    pub ntypes: usize,
-    pub strings: Vec<String>,
-    pub strings_lens: Vec<usize>,
-    pub ustrings: Vec<Option<String>>,
-    pub nstrings: usize,
-    pub nints: usize,
-    pub ints: Vec<i32>,
-    pub nfloats: usize,
-    pub floats: Vec<f64>,
-    pub nbytes: usize,
-    pub nfunctions: usize,
-    pub functions: Vec<HLFunction>,
-    pub nconstants: usize,
-    pub constants: Vec<Constant>,
-    pub entrypoint: u32,
-    pub nglobals: usize,
-    pub globals: Vec<ValueType>,
-    pub nnatives: usize,
-    pub natives: Vec<Native>,
-    pub hasdebug: u32,
-    pub version: u8,
-    pub bytes: Vec<u8>,
-    pub bytes_pos: Vec<usize>,
-    pub ndebugfiles: usize,
-    pub debugfiles: Vec<String>,
-    pub debugfiles_lens: Vec<usize>,
+struct LazyBody {
+    /// Bytes of the function, its debug infos and assigns in `source`.
+    range: Range<usize>,
+    function: OnceLock<HLFunction>,
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Code {
@@ -109,424 +124,550 @@ impl Code {
             functions: Vec::new(),
             constants: Vec::new(),
             strings_lens: Vec::new(),
+            lazy: None,
         }
     }
 
-    pub fn read_string(decoder: &mut crate::decoder::Decoder) -> Result<String, DecodeError> {
-        let index = INDEX(decoder).map_err(Code::map_err).unwrap();
-        if index < 0 || index >= decoder.code.nstrings.try_into().unwrap() {
-            unsafe {
-                ERRORS = Some(DecodeError::with_info(
-                    DecodeErrorKind::InvalidStringIndex,
-                    decoder.file_position,
-                ));
-            }
-            Ok(String::new())
-        } else {
-            let strings = decoder.code.strings.clone();
-            Ok(strings.into_iter().nth(index.try_into().unwrap()).unwrap())
-        }
+    /// Bytecode format version, from 2 to 5.
+    pub fn version(&self) -> u8 {
+        self.version
     }
 
-    pub fn get_ustring(&mut self, index: usize) -> String {
-        let ustr = self.ustrings.clone().into_iter().nth(index).unwrap();
+    /// Whether functions carry debug line tables and variable assigns.
+    pub fn has_debug(&self) -> bool {
+        self.hasdebug != 0
+    }
 
-        match ustr {
-            None => {
-                let s = self.strings.clone().into_iter().nth(index);
-                match s {
-                    None => {
-                        self.ustrings.insert(index, None);
-                        String::new()
-                    }
-                    Some(res) => {
-                        let ss = res.clone();
-                        self.ustrings.insert(index, Some(res));
-                        ss
-                    }
-                }
-            }
-            Some(s) => s,
-        }
+    /// Findex of the function that runs the program.
+    pub fn entrypoint(&self) -> u32 {
+        self.entrypoint
+    }
+
+    /// Every type, indexed by `TypeId`.
+    pub fn types(&self) -> &[ValueType] {
+        &self.types
+    }
+
+    pub fn ints(&self) -> &[i32] {
+        &self.ints
+    }
+
+    pub fn floats(&self) -> &[f64] {
+        &self.floats
+    }
+
+    pub fn strings(&self) -> &[String] {
+        &self.strings
+    }
+
+    /// Names of the source files that debug line tables refer to.
+    pub fn debugfiles(&self) -> &[String] {
+        &self.debugfiles
+    }
+
+    /// Type of every global, by global index.
+    pub fn globals(&self) -> &[TypeId] {
+        &self.globals
+    }
+
+    pub fn natives(&self) -> &[Native] {
+        &self.natives
+    }
+
+    /// Bytecode functions in file order. With `read_lazy` only their
+    /// headers are decoded; `function` returns complete functions.
+    pub fn functions(&self) -> &[HLFunction] {
+        &self.functions
+    }
+
+    pub fn constants(&self) -> &[Constant] {
+        &self.constants
+    }
+
+    /// Reads a string index and resolves it against the string table.
+    pub(crate) fn read_string(&self, decoder: &mut Decoder) -> Result<&str, DecodeError> {
+        let position = decoder.file_position;
+        let index = decoder.read_index()?;
+        usize::try_from(index)
+            .ok()
+            .and_then(|i| self.strings.get(i))
+            .map(String::as_str)
+            .ok_or_else(|| {
+                DecodeError::with_info(DecodeErrorKind::InvalidStringIndex, position)
+                    .with_value(index)
+            })
+    }
+
+    /// String `index` as UTF-16, the representation of HashLink strings at
+    /// runtime, without the trailing NUL.
+    pub fn get_ustring(&self, index: usize) -> Option<&[u16]> {
+        let s = self.strings.get(index)?;
+        let slot = self.ustrings.get(index)?;
+        Some(slot.get_or_init(|| s.encode_utf16().collect()))
+    }
+
+    /// Resolves a type id. Ids handed out by the decoder are always in range.
+    pub fn get_type(&self, id: TypeId) -> &ValueType {
+        &self.types[id.index()]
     }
 
-    pub fn read_ustring(decoder: &mut crate::decoder::Decoder) -> Result<String, DecodeError> {
-        let index = INDEX(decoder).unwrap();
-        if index < 0 || index >= decoder.code.nstrings.try_into().unwrap() {
-            unsafe {
-                ERRORS = Some(DecodeError::with_info(
-                    DecodeErrorKind::InvalidStringIndex,
-                    decoder.file_position,
-                ));
+    /// `t` followed by its supertypes.
+    pub fn type_hierarchy(&self, t: TypeId) -> Vec<TypeId> {
+        let mut chain = vec![t];
+        while chain.len() <= self.types.len() {
+            match self.types.get(chain[chain.len() - 1].index()).map(|ty| &ty.union) {
+                Some(ValueTypeU::ObjType {
+                    super_type: Some(s),
+                    ..
+                }) => chain.push(*s),
+                _ => break,
             }
-            Ok(String::new())
-        } else {
-            let string = decoder.code.get_ustring(index.try_into().unwrap());
-            Ok(string)
         }
+        chain
     }
 
-    pub fn get_type(decoder: &mut crate::decoder::Decoder) -> Result<ValueType, DecodeError> {
-        let mut index = INDEX(decoder).unwrap();
-        if index < 0 || index >= decoder.code.ntypes.try_into().unwrap() {
-            unsafe {
-                ERRORS = Some(DecodeError::with_info(
-                    DecodeErrorKind::InvalidTypeIndex,
-                    decoder.file_position,
-                ));
-            }
-            index = 0;
+    /// Field `fid` of an object or virtual type. Object fields are numbered
+    /// across the hierarchy, starting with those of the root class.
+    pub fn type_field(&self, t: TypeId, fid: usize) -> Option<&ObjField> {
+        match &self.types.get(t.index())?.union {
+            ValueTypeU::ObjType { .. } => self
+                .type_hierarchy(t)
+                .iter()
+                .rev()
+                .filter_map(|t| match &self.types.get(t.index())?.union {
+                    ValueTypeU::ObjType { fields, .. } => Some(fields),
+                    _ => None,
+                })
+                .flatten()
+                .nth(fid),
+            ValueTypeU::VirtualType { fields, .. } => fields.get(fid),
+            _ => None,
         }
+    }
 
-        Ok(decoder
-            .code
-            .types
-            .clone()
-            .into_iter()
-            .nth(index.try_into().unwrap())
-            .unwrap())
+    /// The method in virtual table slot `pindex` of an object type, which
+    /// may be inherited.
+    pub fn type_method(&self, t: TypeId, pindex: usize) -> Option<&ObjProto> {
+        self.type_hierarchy(t)
+            .iter()
+            .filter_map(|t| match &self.types.get(t.index())?.union {
+                ValueTypeU::ObjType { proto, .. } => Some(proto),
+                _ => None,
+            })
+            .flatten()
+            .find(|p| p.pindex == pindex as i32)
     }
 
-    pub fn read_type(
-        decoder: &mut crate::decoder::Decoder,
-        t: &mut ValueType,
-    ) -> Result<(), DecodeError> {
-        let v = u8::decode(decoder).unwrap();
-        
-        if v >= u8::try_from(TypeKind::HLAST).unwrap() {
-            Code::map_err(DecodeError::with_info(
-                DecodeErrorKind::InvalidType,
-                decoder.file_position,
-            ));
-            return Ok(());
+    /// Reads a reference into the type table, which may point past the types
+    /// decoded so far.
+    pub(crate) fn read_type_ref(&self, decoder: &mut Decoder) -> Result<TypeId, DecodeError> {
+        let position = decoder.file_position;
+        let index = decoder.read_index()?;
+        if index < 0 || index as usize >= self.ntypes {
+            return Err(DecodeError::with_info(
+                DecodeErrorKind::InvalidTypeIndex,
+                position,
+            )
+            .with_value(index));
         }
+        Ok(TypeId(index as u32))
+    }
 
-        let k = TypeKind::try_from(v).unwrap();
+    pub(crate) fn read_type(
+        &self,
+        decoder: &mut Decoder,
+        t: &mut ValueType,
+    ) -> Result<(), DecodeError> {
+        let position = decoder.file_position;
+        let v = u8::decode(decoder)?;
 
-        t.kind = k;
+        t.kind = TypeKind::try_from(v)
+            .ok()
+            .filter(|k| *k != TypeKind::HLAST)
+            .ok_or_else(|| {
+                DecodeError::with_info(DecodeErrorKind::InvalidType, position).with_value(v)
+            })?;
 
         match t.kind {
             TypeKind::HFUN | TypeKind::HMETHOD => {
-                let nargs: usize = u8::decode(decoder).unwrap().try_into().unwrap();
-                let mut fun = ValueTypeU::FuncType {
-                    nargs,
-                    args: Vec::new(),
-                    ret: Box::new(ValueType::default()),
-                };
-
-                if let ValueTypeU::FuncType {
-                    ref mut args,
-                    ref mut ret,
-                    nargs,
-                } = fun
-                {
-                    for i in 0..nargs {
-                        (*args).insert(i, Code::get_type(decoder).unwrap());
-                    }
-
-                    *ret = Box::new(Code::get_type(decoder).unwrap());
+                let nargs: usize = u8::decode(decoder)?.into();
+                decoder.check_count(nargs, 1)?;
+                let mut args = Vec::with_capacity(nargs);
+                for _ in 0..nargs {
+                    args.push(self.read_type_ref(decoder)?);
                 }
+                let ret = self.read_type_ref(decoder)?;
 
-                t.union = fun;
+                t.union = ValueTypeU::FuncType { nargs, args, ret };
             }
             TypeKind::HOBJ | TypeKind::HSTRUCT => {
-                let name = Code::read_ustring(decoder).unwrap();
-                let super_index = INDEX(decoder).unwrap();
-                let mut obj = ValueTypeU::ObjType {
-                    name,
-                    super_type: if super_index < 0 {
-                        Box::new(ValueType::default())
-                    } else {
-                        Box::new(
-                            decoder
-                                .code
-                                .types
-                                .clone()
-                                .into_iter()
-                                .nth(super_index.try_into().unwrap())
-                                .unwrap(),
-                        )
-                    },
-                    global_value: vec![(UINDEX(decoder).unwrap()) as isize],
-                    nfields: UINDEX(decoder).unwrap(),
-                    nproto: UINDEX(decoder).unwrap(),
-                    nbindings: UINDEX(decoder).unwrap(),
-                    fields: Vec::new(),
-                    proto: Vec::new(),
-                    bindings: Vec::new(),
-                    rt: None,
+                let name = self.read_string(decoder)?.to_owned();
+                let super_position = decoder.file_position;
+                let super_index = decoder.read_index()?;
+                let super_type = if super_index < 0 {
+                    None
+                } else if super_index as usize >= self.ntypes {
+                    return Err(DecodeError::with_info(
+                        DecodeErrorKind::InvalidTypeIndex,
+                        super_position,
+                    )
+                    .with_value(super_index));
+                } else {
+                    Some(TypeId(super_index as u32))
                 };
+                let global_value = vec![decoder.read_uindex()? as isize];
+                let nfields = decoder.read_uindex()?;
+                let nproto = decoder.read_uindex()?;
+                let nbindings = decoder.read_uindex()?;
+                decoder.check_count(nfields, 2)?;
+                decoder.check_count(nproto, 3)?;
+                decoder.check_count(nbindings, 2)?;
+
+                let mut fields = Vec::new();
+                for _ in 0..nfields {
+                    let name = self.read_string(decoder)?.to_owned();
+                    let hashed_name = hash(name.as_bytes());
+                    fields.push(ObjField {
+                        name,
+                        hashed_name,
+                        t: self.read_type_ref(decoder)?,
+                    });
+                }
+                let mut proto = Vec::new();
+                for _ in 0..nproto {
+                    let name = self.read_string(decoder)?.to_owned();
+                    let hashed_name = hash(name.as_bytes());
+                    proto.push(ObjProto {
+                        name,
+                        hashed_name,
+                        findex: decoder.read_uindex()?,
+                        pindex: decoder.read_index()?,
+                    });
+                }
+                let mut bindings = Vec::new();
+                for _ in 0..nbindings {
+                    bindings.push(decoder.read_uindex()? as u32);
+                    bindings.push(decoder.read_uindex()? as u32);
+                }
 
-                if let ValueTypeU::ObjType {
-                    name: _,
-                    super_type: _,
-                    ref mut fields,
+                t.union = ValueTypeU::ObjType {
+                    name,
+                    super_type,
+                    fields,
                     nfields,
                     nproto,
                     nbindings,
-                    ref mut proto,
-                    ref mut bindings,
-                    global_value: _,
-                    rt: _,
-                } = obj
-                {
-                    for i in 0..nfields {
-                        let name = Code::read_ustring(decoder).unwrap();
-                        let hashed_name = hash(name.as_bytes());
-                        let field = ObjField {
-                            name,
-                            hashed_name,
-                            t: Code::get_type(decoder).unwrap(),
-                        };
-
-                        (*fields).insert(i, field);
-                    }
-                    for i in 0..nproto {
-                        let name = Code::read_ustring(decoder).unwrap();
-                        let hashed_name = hash(name.as_bytes());
-                        let obj_proto = ObjProto {
-                            name,
-                            hashed_name,
-                            findex: UINDEX(decoder).unwrap(),
-                            pindex: INDEX(decoder).unwrap(),
-                        };
-
-                        (*proto).insert(i, obj_proto);
-                    }
-                    for i in 0..nbindings {
-                        (*bindings).insert(i << 1, UINDEX(decoder).unwrap().try_into().unwrap());
-                        (*bindings).insert((i << 1) | 1, UINDEX(decoder).unwrap().try_into().unwrap());
-                    }
-                }
-
-                t.union = obj;
+                    proto,
+                    bindings,
+                    global_value,
+                    rt: None,
+                };
             }
             TypeKind::HREF => {
-                t.tparam = Some(Box::new(Code::get_type(decoder).unwrap()));
+                t.tparam = Some(self.read_type_ref(decoder)?);
             }
             TypeKind::HVIRTUAL => {
-                let nfields = UINDEX(decoder).unwrap();
-                let mut virt = ValueTypeU::VirtualType {
-                    nfields,
-                    fields: Vec::new(),
-                };
-
-                if let ValueTypeU::VirtualType {
-                    nfields,
-                    ref mut fields,
-                } = virt
-                {
-                    for i in 0..nfields {
-                        let name = Code::read_ustring(decoder).unwrap();
-                        let hashed_name = hash(name.as_bytes());
-                        let field = ObjField {
-                            name,
-                            hashed_name,
-                            t: Code::get_type(decoder).unwrap(),
-                        };
-
-                        (*fields).insert(i, field);
-                    }
+                let nfields = decoder.read_uindex()?;
+                decoder.check_count(nfields, 2)?;
+                let mut fields = Vec::new();
+                for _ in 0..nfields {
+                    let name = self.read_string(decoder)?.to_owned();
+                    let hashed_name = hash(name.as_bytes());
+                    fields.push(ObjField {
+                        name,
+                        hashed_name,
+                        t: self.read_type_ref(decoder)?,
+                    });
                 }
 
-                t.union = virt;
+                t.union = ValueTypeU::VirtualType { nfields, fields };
             }
             TypeKind::HABSTRACT => {
-                t.abs_name = Some(Code::read_ustring(decoder).unwrap());
+                t.abs_name = Some(self.read_string(decoder)?.to_owned());
             }
             TypeKind::HENUM => {
-                let mut tenum = ValueTypeU::EnumType {
-                    name: Code::read_ustring(decoder).unwrap(),
-                    global_value: vec![(UINDEX(decoder).unwrap()) as isize], // Todo
-                    nconstructs: UINDEX(decoder).unwrap(),
-                    constructs: Vec::new(),
-                };
-
-                if let ValueTypeU::EnumType {
-                    name: _,
-                    nconstructs,
-                    ref mut constructs,
-                    global_value: _,
-                } = tenum
-                {
-                    for i in 0..nconstructs {
-                        let name = Code::read_ustring(decoder).unwrap();
-                        let nparams = UINDEX(decoder).unwrap();
-                        let mut con = EnumConstruct {
-                            name,
-                            nparams,
-                            params: Vec::new(),
-                            offsets: Vec::new(),
-                            hasptr: false,
-                            size: 0,
-                        };
-                        for j in 0..nparams {
-                            con.params.insert(j, Code::get_type(decoder).unwrap());
-                        }
-
-                        (*constructs).insert(i, con);
+                let name = self.read_string(decoder)?.to_owned();
+                let global_value = vec![decoder.read_uindex()? as isize]; // Todo
+                let nconstructs = decoder.read_uindex()?;
+                decoder.check_count(nconstructs, 2)?;
+                let mut constructs = Vec::new();
+                for _ in 0..nconstructs {
+                    let name = self.read_string(decoder)?.to_owned();
+                    let nparams = decoder.read_uindex()?;
+                    decoder.check_count(nparams, 1)?;
+                    let mut con = EnumConstruct {
+                        name,
+                        nparams,
+                        params: Vec::new(),
+                        offsets: Vec::new(),
+                        hasptr: false,
+                        size: 0,
+                    };
+                    for _ in 0..nparams {
+                        con.params.push(self.read_type_ref(decoder)?);
                     }
+
+                    constructs.push(con);
                 }
 
-                t.union = tenum;
+                t.union = ValueTypeU::EnumType {
+                    name,
+                    nconstructs,
+                    constructs,
+                    global_value,
+                };
             }
             TypeKind::HNULL | TypeKind::HPACKED => {
-                t.tparam = Some(Box::new(Code::get_type(decoder).unwrap()));
+                t.tparam = Some(self.read_type_ref(decoder)?);
             }
-            _ => {
-               
-                if u8::try_from(t.kind) >= u8::try_from(TypeKind::HLAST) {
-                    Code::map_err(DecodeError::with_info(
-                        DecodeErrorKind::InvalidType,
-                        decoder.file_position,
-                    ));
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Checks that every supertype is an object and that no class inherits
+    /// from itself. This runs once the whole table is known, since a
+    /// supertype may come after its subclasses.
+    fn check_super_types(&self, positions: &[usize]) -> Result<(), DecodeError> {
+        for (i, position) in positions.iter().enumerate() {
+            let mut current = TypeId(i as u32);
+            for _ in 0..self.types.len() {
+                let super_type = match &self.get_type(current).union {
+                    ValueTypeU::ObjType {
+                        super_type: Some(s),
+                        ..
+                    } => *s,
+                    _ => break,
+                };
+                if !matches!(self.get_type(super_type).kind, TypeKind::HOBJ | TypeKind::HSTRUCT)
+                    || super_type.index() == i
+                {
+                    return Err(DecodeError::with_info(
+                        DecodeErrorKind::InvalidSuperType,
+                        *position,
+                    )
+                    .with_value(super_type.0));
                 }
+                current = super_type;
             }
         }
-
         Ok(())
     }
 
-    pub fn read_strings(
+    /// Entry `index` of the bytes pool, as loaded by `OBytes`. It runs up to
+    /// the next entry's offset, or the end of the pool for the last one.
+    ///
+    /// Panics if `index` is not below `nbytes`.
+    pub fn bytes_constant(&self, index: usize) -> &[u8] {
+        let start = self.bytes_pos[index];
+        let end = self
+            .bytes_pos
+            .get(index + 1)
+            .copied()
+            .unwrap_or(self.bytes.len());
+        &self.bytes[start..end]
+    }
+
+    /// Like `bytes_constant`, but `None` for an out of range `index` or
+    /// offsets that do not fit the pool.
+    pub fn get_bytes_constant(&self, index: usize) -> Option<&[u8]> {
+        let start = *self.bytes_pos.get(index)?;
+        let end = self
+            .bytes_pos
+            .get(index + 1)
+            .copied()
+            .unwrap_or(self.bytes.len());
+        self.bytes.get(start..end)
+    }
+
+    /// Position in `functions` of the function with `findex`.
+    pub fn function_position(&self, findex: usize) -> Option<usize> {
+        self.functions.iter().position(|f| f.findex == findex)
+    }
+
+    /// Source file and line of an op, or `None` without debug infos.
+    pub fn source_location(&self, findex: usize, op_index: usize) -> Option<SourceLocation<'_>> {
+        let f = self.function(self.function_position(findex)?).ok()?;
+        self.op_location(f, op_index)
+    }
+
+    /// Source location of op `op_index` of `f`, a function of this module.
+    pub(crate) fn op_location(
+        &self,
+        f: &HLFunction,
+        op_index: usize,
+    ) -> Option<SourceLocation<'_>> {
+        let file = *f.debug.get(op_index << 1)?;
+        let line = *f.debug.get((op_index << 1) | 1)?;
+        Some(SourceLocation {
+            file: self.debugfiles.get(usize::try_from(file).ok()?)?,
+            line: line as u32,
+        })
+    }
+
+    /// Every (findex, op index) pair attributed to `line` of `file`, in file
+    /// order. Lazily read functions that fail to decode are skipped.
+    pub fn ops_at_line(&self, file: &str, line: u32) -> Vec<(usize, usize)> {
+        let mut ops = Vec::new();
+        for i in 0..self.functions.len() {
+            let f = match self.function(i) {
+                Ok(f) => f,
+                Err(_) => continue,
+            };
+            for (op, pair) in f.debug.chunks_exact(2).enumerate() {
+                if pair[1] as u32 == line
+                    && usize::try_from(pair[0])
+                        .ok()
+                        .and_then(|i| self.debugfiles.get(i))
+                        .is_some_and(|name| name == file)
+                {
+                    ops.push((f.findex, op));
+                }
+            }
+        }
+        ops
+    }
+
+    pub(crate) fn read_strings(
         decoder: &mut crate::decoder::Decoder,
         nstrings: usize,
         out_lens: &mut Vec<usize>,
     ) -> Result<Vec<String>, DecodeError> {
-        let size = i32::decode(decoder).unwrap();
+        let size = decoder.read_size()?;
+        decoder.check_count(size, 1)?;
+        let position = decoder.file_position;
+        let data = &decoder.buf[..size];
 
-        let st = String::from_utf8_lossy(&decoder.buf[..size as usize]).to_string();
-     
-        decoder.advance(size as usize)?;
+        decoder.advance(size)?;
+        decoder.check_count(nstrings, 1)?;
 
         let mut strings:Vec<String> = Vec::new();
-        let mut cursor = 0;
-        for _i in 0..nstrings {
-            let sz: usize = UINDEX(decoder).unwrap();
-            let s = st[cursor..][0..sz].to_string();
-            strings.push(s.clone());
-            out_lens.push(s.len());
-            cursor += sz;
-
-            if cursor >= st.len() {
-                break;
-            }
-
-            cursor += 1;
+        let mut cursor: usize = 0;
+        for i in 0..nstrings {
+            let sz: usize = decoder.read_uindex()?;
+            let invalid = || {
+                DecodeError::with_info(DecodeErrorKind::InvalidString, position + cursor)
+                    .with_value(i as i64)
+            };
+            // every string is followed by a NUL terminator inside the pool
+            let end = cursor
+                .checked_add(sz)
+                .filter(|end| data.get(*end) == Some(&0))
+                .ok_or_else(invalid)?;
+            let s = std::str::from_utf8(&data[cursor..end]).map_err(|_| invalid())?;
+            strings.push(s.to_string());
+            out_lens.push(sz);
+            cursor = end + 1;
         }
 
         Ok(strings)
     }
 
-    pub fn read_function(decoder: &mut Decoder) -> Result<HLFunction, DecodeError> {
-        let mut f = HLFunction {
-            t: Code::get_type(decoder).unwrap(),
-            findex: UINDEX(decoder).unwrap(),
-            nregs: UINDEX(decoder).unwrap(),
-            nops: UINDEX(decoder).unwrap(),
+    /// Reads the function type, findex and register and op counts.
+    fn read_function_header(&self, decoder: &mut Decoder) -> Result<HLFunction, DecodeError> {
+        let f = HLFunction {
+            t: self.read_type_ref(decoder)?,
+            findex: decoder.read_uindex()?,
+            nregs: decoder.read_uindex()?,
+            nops: decoder.read_uindex()?,
             regs: Vec::new(),
             ops: Vec::new(),
             debug: Vec::new(),
+            assigns: Vec::new(),
             rf: 0,
             obj: None,
             field: None,
         };
+        decoder.check_count(f.nregs, 1)?;
+        decoder.check_count(f.nops, 1)?;
+        Ok(f)
+    }
 
-        for i in 0..f.nregs {
-            f.regs.insert(i, Code::get_type(decoder).map_err(Code::map_err).unwrap());
-        }
-        if let Err(e) = Code::check_error() {
-            return Ok(f);
+    pub(crate) fn read_function(&self, decoder: &mut Decoder) -> Result<HLFunction, DecodeError> {
+        let mut f = self.read_function_header(decoder)?;
+        for _ in 0..f.nregs {
+            f.regs.push(self.read_type_ref(decoder)?);
         }
         for i in 0..f.nops {
-            let op = Code::read_opcode(decoder).unwrap();
-            f.ops.insert(i, op);
+            f.ops.push(Code::read_opcode(decoder).map_err(|e| e.at_op(i))?);
         }
 
         Ok(f)
     }
 
-    pub fn read_opcode(decoder: &mut Decoder) -> Result<Opcode, DecodeError> {
-        let n = u8::decode(decoder).unwrap();
+    pub(crate) fn read_opcode(decoder: &mut Decoder) -> Result<Opcode, DecodeError> {
+        let position = decoder.file_position;
+        let n = u8::decode(decoder)?;
         let mut res = Opcode::default();
-        if n >= 99 {
-            unsafe {
-                ERRORS = Some(DecodeError::with_info(
-                    DecodeErrorKind::InvalidOpcode,
-                    decoder.file_position,
-                ));
-            }
-            return Ok(res);
+        if n >= Op::OLast as u8 {
+            return Err(DecodeError::with_info(
+                DecodeErrorKind::InvalidOpcode,
+                position,
+            )
+            .with_value(n));
         }
 
-        let op: Op = n.try_into().unwrap();
-        res.op = op;
-        let i = OP_NARGS.into_iter().nth(n.try_into().unwrap()).unwrap();
-        match i {
+        res.op = Op::try_from(n).map_err(|_| {
+            DecodeError::with_info(DecodeErrorKind::InvalidOpcode, position).with_value(n)
+        })?;
+        match OP_NARGS[n as usize] {
             0 => {}
             1 => {
-                res.p1 = Some(INDEX(decoder).unwrap());
+                res.p1 = Some(decoder.read_index()?);
             }
             2 => {
-                res.p1 = Some(INDEX(decoder).unwrap());
-                res.p2 = Some(INDEX(decoder).unwrap());
+                res.p1 = Some(decoder.read_index()?);
+                res.p2 = Some(decoder.read_index()?);
             }
             3 => {
-                res.p1 = Some(INDEX(decoder).unwrap());
-                res.p2 = Some(INDEX(decoder).unwrap());
-                res.p3 = Some(INDEX(decoder).unwrap());
+                res.p1 = Some(decoder.read_index()?);
+                res.p2 = Some(decoder.read_index()?);
+                res.p3 = Some(decoder.read_index()?);
             }
             4 => {
-                res.p1 = Some(INDEX(decoder).unwrap());
-                res.p2 = Some(INDEX(decoder).unwrap());
-                res.p3 = Some(INDEX(decoder).unwrap());
-                let i: isize = INDEX(decoder).unwrap().try_into().unwrap(); // not sure if this is necessary
-                res.extra.insert(0, i);
+                res.p1 = Some(decoder.read_index()?);
+                res.p2 = Some(decoder.read_index()?);
+                res.p3 = Some(decoder.read_index()?);
+                res.extra.push(decoder.read_index()? as isize);
             }
             -1 => match res.op {
                 Op::OCallN | Op::OCallClosure | Op::OCallMethod | Op::OCallThis | Op::OMakeEnum => {
-                    res.p1 = Some(INDEX(decoder).unwrap());
-                    res.p2 = Some(INDEX(decoder).unwrap());
-                    let p3 = u8::decode(decoder).unwrap().into();
-                    res.p3 = Some(p3);
-                    let extra = vec![0; p3.try_into().unwrap()];
-                    res.extra = extra;
-
-                    for i in 0..p3 {
-                        res.extra
-                            .insert(i.try_into().unwrap(), INDEX(decoder).unwrap().try_into().unwrap());
+                    res.p1 = Some(decoder.read_index()?);
+                    res.p2 = Some(decoder.read_index()?);
+                    let p3 = u8::decode(decoder)?;
+                    res.p3 = Some(p3.into());
+
+                    for _ in 0..p3 {
+                        res.extra.push(decoder.read_index()? as isize);
                     }
                 }
                 Op::OSwitch => {
-                    res.p1 = Some(UINDEX(decoder).unwrap().try_into().unwrap());
-                    let p2 = UINDEX(decoder).unwrap().try_into().unwrap();
-                    res.p2 = Some(p2);
-
-                    res.extra = vec![0; p2.try_into().unwrap()];
+                    res.p1 = Some(decoder.read_uindex()? as i32);
+                    let p2 = decoder.read_uindex()?;
+                    res.p2 = Some(p2 as i32);
 
-                    for i in 0..p2 {
-                        res.extra
-                            .insert(i.try_into().unwrap(), UINDEX(decoder).unwrap().try_into().unwrap());
+                    for _ in 0..p2 {
+                        res.extra.push(decoder.read_uindex()? as isize);
                     }
-                    res.p3 = Some(UINDEX(decoder).unwrap().try_into().unwrap());
+                    res.p3 = Some(decoder.read_uindex()? as i32);
                 }
                 _ => {
                     return Err(DecodeError::with_info(
                         DecodeErrorKind::CouldNotProcessOpcode,
-                        decoder.file_position,
-                    ));
+                        position,
+                    )
+                    .with_value(n));
                 }
             },
-            _ => {
-                let size = OP_NARGS.into_iter().nth(n.try_into().unwrap()).unwrap() - 3;
-                res.p1 = Some(INDEX(decoder).unwrap());
-                res.p2 = Some(INDEX(decoder).unwrap());
-                res.p3 = Some(INDEX(decoder).unwrap());
-
-                res.extra = vec![0; size.try_into().unwrap()];
-
-                for i in 0..size {
-                    res.extra
-                        .insert(i.try_into().unwrap(), INDEX(decoder).unwrap().try_into().unwrap());
+            nargs => {
+                let size = nargs - 3;
+                res.p1 = Some(decoder.read_index()?);
+                res.p2 = Some(decoder.read_index()?);
+                res.p3 = Some(decoder.read_index()?);
+
+                for _ in 0..size {
+                    res.extra.push(decoder.read_index()? as isize);
                 }
             }
         }
@@ -534,297 +675,719 @@ impl Code {
         Ok(res)
     }
 
-    pub fn debug_infos(decoder: &mut Decoder, nops: usize) -> Result<Vec<i32>, DecodeError> {
+    pub(crate) fn debug_infos(&self, decoder: &mut Decoder, nops: usize) -> Result<Vec<i32>, DecodeError> {
+        // a single byte covers at most 15 ops
+        decoder.check_count(nops.div_ceil(15), 1)?;
+        let mut debug: Vec<i32> = vec![0; nops * 2];
+        self.walk_debug_infos(decoder, nops, |i, file, line| {
+            debug[i << 1] = file;
+            debug[(i << 1) | 1] = line;
+        })?;
+        Ok(debug)
+    }
+
+    /// Decodes the line table of `nops` ops, handing each op's (file, line) to `store`.
+    fn walk_debug_infos(
+        &self,
+        decoder: &mut Decoder,
+        nops: usize,
+        mut store: impl FnMut(usize, i32, i32),
+    ) -> Result<(), DecodeError> {
         let mut curfile: i32 = -1;
         let mut curline: i32 = 0;
-
-        let mut debug: Vec<i32> = vec![0; nops * 2];
-        // println!("debug_len {}", debug.len());
         let mut i: usize = 0;
 
         while i < nops {
-            let mut c:i32 = u8::decode(decoder).unwrap().into();
+            let position = decoder.file_position;
+            let mut c: i32 = u8::decode(decoder)?.into();
             if (c & 1) != 0 {
                 c >>= 1;
-                curfile = (c << 8)  | u8::decode(decoder).unwrap() as i32;
-                if curfile >= decoder.code.ndebugfiles.try_into().unwrap() {
-                    unsafe {
-                        ERRORS = Some(DecodeError::with_info(DecodeErrorKind::InvalidDebugFile,  decoder.file_position));
-                    }
+                curfile = (c << 8) | i32::from(u8::decode(decoder)?);
+                if curfile as usize >= self.ndebugfiles {
+                    return Err(DecodeError::with_info(
+                        DecodeErrorKind::InvalidDebugFile,
+                        position,
+                    )
+                    .with_value(curfile));
                 }
             } else if (c & 2) != 0 {
-                let delta: i32 = (c >> 6) as i32;
-                let count = ((c >> 2) & 15) as i32;
-                if i + count as usize > nops {
-                    unsafe {
-                        ERRORS = Some(DecodeError::with_info(DecodeErrorKind::OutsideRange,  decoder.file_position));
-                    }
+                let delta = c >> 6;
+                let count = ((c >> 2) & 15) as usize;
+                if i + count > nops {
+                    return Err(DecodeError::with_info(
+                        DecodeErrorKind::OutsideRange,
+                        position,
+                    )
+                    .with_value((i + count) as i64));
                 }
 
-                for _j in count..0 {
-                    debug[i << 1] = curfile as i32;
-                    debug[(i << 1) | 1] = curline as i32;
+                for _ in 0..count {
+                    store(i, curfile, curline);
                     i += 1;
                 }
-                curline += delta;
+                curline = curline.wrapping_add(delta);
             } else if (c & 4) != 0 {
-                curline += (c >> 3);
-                debug[i << 1] = curfile as i32;
-                debug[(i << 1) | 1] = curline as i32;
+                curline = curline.wrapping_add(c >> 3);
+                store(i, curfile, curline);
                 i += 1;
             } else {
-                let b2 = u8::decode(decoder).unwrap();
-                let b3 = u8::decode(decoder).unwrap();
-                let a = c >> 3;
-                let b = (b2 << 5) as i32;
-                let c = b3 as i32;
-                curline = a | b | c;
-                debug[i << 1] = curfile as i32;
-                debug[(i << 1) | 1] = curline as i32;
+                let b2 = i32::from(u8::decode(decoder)?);
+                let b3 = i32::from(u8::decode(decoder)?);
+                curline = (c >> 3) | (b2 << 5) | (b3 << 13);
+                store(i, curfile, curline);
                 i += 1;
             }
         }
-        Ok(debug)
+        Ok(())
     }
 
-    fn map_err(e: DecodeError) {
-        unsafe {
-            if ERRORS == None {
-                ERRORS = Some(e);
+    /// Reads one function followed by its debug line table and variable assigns.
+    fn read_function_debug(&self, decoder: &mut Decoder) -> Result<HLFunction, DecodeError> {
+        let mut f = self.read_function(decoder)?;
+        if self.hasdebug != 0 {
+            f.debug = self.debug_infos(decoder, f.nops)?;
+            if self.version >= 3 {
+                let nassigns = decoder.read_uindex()?;
+                decoder.check_count(nassigns, 2)?;
+                for _ in 0..nassigns {
+                    f.assigns.push(VarAssign {
+                        name: self.read_string(decoder)?.to_owned(),
+                        op_index: decoder.read_index()?,
+                    });
+                }
             }
         }
+        Ok(f)
     }
 
-    fn check_error() -> Result<(), DecodeError> {
-        unsafe {
-            if let Some(e) = ERRORS {
-                return Err(e);
+    /// Walks over one function and its debug infos like `read_function_debug`,
+    /// keeping only the header.
+    fn skip_function_debug(&self, decoder: &mut Decoder) -> Result<HLFunction, DecodeError> {
+        let f = self.read_function_header(decoder)?;
+        for _ in 0..f.nregs {
+            decoder.read_uindex()?;
+        }
+        for i in 0..f.nops {
+            Code::read_opcode(decoder).map_err(|e| e.at_op(i))?;
+        }
+        if self.hasdebug != 0 {
+            self.walk_debug_infos(decoder, f.nops, |_, _, _| {})?;
+            if self.version >= 3 {
+                let nassigns = decoder.read_uindex()?;
+                decoder.check_count(nassigns, 2)?;
+                for _ in 0..nassigns {
+                    decoder.read_uindex()?;
+                    decoder.read_index()?;
+                }
             }
-            Ok(())
         }
+        Ok(f)
     }
 
     pub fn read(buf: &[u8]) -> Result<Self, DecodeError> {
         let mut decoder = Decoder::new(buf);
+        Code::read_sections(&mut decoder, None, |_| {}).map_err(|e| e.in_section(decoder.section))
+    }
+
+    /// Like `read`, but copies the whole `Code` twice per decoded item the way
+    /// the decode loop did before it decoded in place. Only the decode bench
+    /// uses this, as the baseline to compare `read` against.
+    #[cfg(feature = "decode-baseline")]
+    pub fn read_cloning(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(buf);
+        Code::read_sections(&mut decoder, None, |c| {
+            let copy = c.clone();
+            *c = copy.clone();
+        })
+        .map_err(|e| e.in_section(decoder.section))
+    }
+
+    /// Like `read`, but leaves function bodies undecoded until they are first
+    /// accessed through `Code::function`.
+    ///
+    /// Only the function headers are kept in `functions`; their `regs`, `ops`,
+    /// `debug` and `assigns` stay empty until `load_functions` is called.
+    pub fn read_lazy(buf: &[u8]) -> Result<Self, DecodeError> {
+        let mut decoder = Decoder::new(buf);
+        Code::read_sections(&mut decoder, Some(Arc::from(buf)), |_| {})
+            .map_err(|e| e.in_section(decoder.section))
+    }
+
+    /// Reads and decodes the bytecode file at `path`.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let buf = fs::read(path).map_err(LoadError::Io)?;
+        Ok(Code::read(&buf)?)
+    }
+
+    pub fn is_lazy(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    /// Returns the function at `index` in file order, decoding its body on
+    /// first access when this code was loaded with `read_lazy`.
+    pub fn function(&self, index: usize) -> Result<&HLFunction, DecodeError> {
+        let invalid =
+            || DecodeError::new(DecodeErrorKind::InvalidFunctionIndex).with_value(index as i64);
+        let lazy = match &self.lazy {
+            None => return self.functions.get(index).ok_or_else(invalid),
+            Some(lazy) => lazy,
+        };
+        let body = lazy.bodies.get(index).ok_or_else(invalid)?;
+        if let Some(f) = body.function.get() {
+            return Ok(f);
+        }
+
+        let mut decoder =
+            Decoder::with_position(&lazy.source[body.range.clone()], body.range.start);
+        decoder.section = DecodeSection::Functions;
+        let f = self
+            .read_function_debug(&mut decoder)
+            .map_err(|e| e.in_function(index).in_section(DecodeSection::Functions))?;
+        Ok(body.function.get_or_init(|| f))
+    }
+
+    /// Decodes every function body still pending, after which `functions`
+    /// holds complete functions and the code is no longer lazy.
+    pub fn load_functions(&mut self) -> Result<(), DecodeError> {
+        if self.lazy.is_some() {
+            self.functions = (0..self.functions.len())
+                .map(|i| self.function(i).cloned())
+                .collect::<Result<_, _>>()?;
+            self.lazy = None;
+        }
+        Ok(())
+    }
+
+    /// Decodes every section into one `Code`, calling `per_item` after each
+    /// type, global, native, function and constant.
+    fn read_sections(
+        decoder: &mut Decoder,
+        source: Option<Arc<[u8]>>,
+        per_item: fn(&mut Code),
+    ) -> Result<Self, DecodeError> {
         let mut c = Code::new();
         let max_version = 5;
 
-        if u8::decode(&mut decoder).unwrap() as char != 'H'
-            || u8::decode(&mut decoder).unwrap() as char != 'L'
-            || u8::decode(&mut decoder).unwrap() as char != 'B'
+        if u8::decode(decoder)? as char != 'H'
+            || u8::decode(decoder)? as char != 'L'
+            || u8::decode(decoder)? as char != 'B'
         {
             return Err(DecodeError::with_info(
                 DecodeErrorKind::InvalidBytecodeHeader,
                 decoder.file_position,
             ));
         }
-        c.version = u8::decode(&mut decoder).map_err(Code::map_err).unwrap();
+        let version_position = decoder.file_position;
+        c.version = u8::decode(decoder)?;
         if c.version <= 1 || c.version > max_version {
-            println!(
-                "Found version {} while HL {}.{} supports up to {}",
-                c.version,
-                HLVERSION >> 16,
-                (HLVERSION >> 8) & 0xFF,
-                max_version
-            );
             return Err(DecodeError::with_info(
                 DecodeErrorKind::UnsupportedBytecodeVersion,
-                decoder.file_position,
-            ));
+                version_position,
+            )
+            .with_value(c.version));
         }
 
-        let mut flags = 0;
-        if let Ok(_flags) = UINDEX(&mut decoder) {
-            flags = _flags;
-        }
-        if let Ok(nints) = UINDEX(&mut decoder) {
-            c.nints = nints;
+        let flags = decoder.read_uindex()?;
+        c.nints = decoder.read_uindex()?;
+        c.nfloats = decoder.read_uindex()?;
+        c.nstrings = decoder.read_uindex()?;
+        if c.version >= 5 {
+            c.nbytes = decoder.read_uindex()?;
         }
-        if let Ok(nfloats) = UINDEX(&mut decoder) {
-            c.nfloats = nfloats;
+        c.ntypes = decoder.read_uindex()?;
+        c.nglobals = decoder.read_uindex()?;
+        c.nnatives = decoder.read_uindex()?;
+        c.nfunctions = decoder.read_uindex()?;
+        c.nconstants = if c.version >= 4 {
+            decoder.read_uindex()?
+        } else {
+            0
+        };
+        c.entrypoint = decoder.read_uindex()? as u32;
+        c.hasdebug = flags as u32 & 1;
+
+        decoder.section = DecodeSection::Ints;
+        decoder.check_count(c.nints, 4)?;
+        for _ in 0..c.nints {
+            c.ints.push(i32::decode(decoder)?);
         }
-        if let Ok(nstrings) = UINDEX(&mut decoder) {
-            c.nstrings = nstrings;
+
+        decoder.section = DecodeSection::Floats;
+        decoder.check_count(c.nfloats, 8)?;
+        for _ in 0..c.nfloats {
+            c.floats.push(f64::decode(decoder)?);
         }
 
+        decoder.section = DecodeSection::Strings;
+        c.strings = Code::read_strings(decoder, c.nstrings, &mut c.strings_lens)?;
+
+        c.ustrings = vec![OnceLock::new(); c.nstrings];
         if c.version >= 5 {
-            if let Ok(nbytes) = UINDEX(&mut decoder) {
-                c.nbytes = nbytes;
+            decoder.section = DecodeSection::Bytes;
+            let size = decoder.read_size()?;
+            decoder.check_count(size, 1)?;
+            c.bytes = vec![0; size];
+            decoder.read_bytes(&mut c.bytes)?;
+            decoder.check_count(c.nbytes, 1)?;
+            for _ in 0..c.nbytes {
+                let position = decoder.file_position;
+                let pos = decoder.read_uindex()?;
+                // entries are sliced between neighbouring offsets
+                if pos > c.bytes.len() || c.bytes_pos.last().is_some_and(|last| pos < *last) {
+                    return Err(DecodeError::with_info(
+                        DecodeErrorKind::InvalidBytesOffset,
+                        position,
+                    )
+                    .with_value(pos as i64));
+                }
+                c.bytes_pos.push(pos);
             }
         }
-        if let Ok(ntypes) = UINDEX(&mut decoder) {
-            c.ntypes = ntypes;
+
+        if c.hasdebug != 0 {
+            decoder.section = DecodeSection::DebugFiles;
+            c.ndebugfiles = decoder.read_uindex()?;
+            c.debugfiles = Code::read_strings(decoder, c.ndebugfiles, &mut c.debugfiles_lens)?;
         }
-        if let Ok(nglobals) = UINDEX(&mut decoder) {
-            c.nglobals = nglobals;
+
+        decoder.section = DecodeSection::Types;
+        decoder.check_count(c.ntypes, 1)?;
+        c.types = Vec::with_capacity(c.ntypes);
+        let mut positions = Vec::with_capacity(c.ntypes);
+        for _ in 0..c.ntypes {
+            positions.push(decoder.file_position);
+            let mut t = ValueType::default();
+            c.read_type(decoder, &mut t)?;
+            c.types.push(t);
+            per_item(&mut c);
         }
-        if let Ok(nnatives) = UINDEX(&mut decoder) {
-            c.nnatives = nnatives;
+        c.check_super_types(&positions)?;
+
+        decoder.section = DecodeSection::Globals;
+        decoder.check_count(c.nglobals, 1)?;
+        c.globals = Vec::new();
+        for _ in 0..c.nglobals {
+            let t = c.read_type_ref(decoder)?;
+            c.globals.push(t);
+            per_item(&mut c);
         }
-        if let Ok(nfunctions) = UINDEX(&mut decoder) {
-            c.nfunctions = nfunctions;
+
+        decoder.section = DecodeSection::Natives;
+        decoder.check_count(c.nnatives, 4)?;
+        c.natives = Vec::new();
+        for _ in 0..c.nnatives {
+            let native = Native {
+                lib: c.read_string(decoder)?.to_owned(),
+                name: c.read_string(decoder)?.to_owned(),
+                t: c.read_type_ref(decoder)?,
+                findex: decoder.read_uindex()?,
+            };
+            c.natives.push(native);
+            per_item(&mut c);
         }
 
-        c.nconstants = 0;
-        if c.version >= 4 {
-            if let Ok(nconstants) = UINDEX(&mut decoder) {
-                c.nconstants = nconstants;
+        decoder.section = DecodeSection::Functions;
+        decoder.check_count(c.nfunctions, 4)?;
+        let mut bodies = Vec::new();
+        for i in 0..c.nfunctions {
+            if source.is_some() {
+                let start = decoder.file_position;
+                let f = c.skip_function_debug(decoder).map_err(|e| e.in_function(i))?;
+                let end = decoder.file_position;
+                c.functions.push(f);
+                bodies.push(LazyBody {
+                    range: start..end,
+                    function: OnceLock::new(),
+                });
+            } else {
+                let f = c.read_function_debug(decoder).map_err(|e| e.in_function(i))?;
+                c.functions.push(f);
             }
+            per_item(&mut c);
         }
+        c.lazy = source.map(|source| LazyFunctions { source, bodies });
+
+        decoder.section = DecodeSection::Constants;
+        decoder.check_count(c.nconstants, 2)?;
+        for _ in 0..c.nconstants {
+            let mut k = Constant {
+                global: decoder.read_uindex()? as u32,
+                nfields: decoder.read_uindex()?,
+                fields: Vec::new(),
+            };
 
-        if let Ok(_entrypoint) = UINDEX(&mut decoder) {
-            c.entrypoint = _entrypoint as u32;
+            decoder.check_count(k.nfields, 1)?;
+            for _ in 0..k.nfields {
+                k.fields.push(decoder.read_uindex()? as u32);
+            }
+            c.constants.push(k);
+            per_item(&mut c);
         }
 
-        c.hasdebug = flags as u32 & 1;
+        Ok(c)
+    }
 
-        let _ = Code::check_error()?;
+    pub(crate) fn write_type_ref(encoder: &mut Encoder, t: TypeId) -> Result<(), EncodeError> {
+        encoder.write_uindex(t.index())
+    }
 
-        for i in 0..c.nints {
-            if let Ok(_int) = i32::decode(&mut decoder).map_err(Code::map_err) {
-                c.ints.insert(i, _int)
+    pub(crate) fn write_type(encoder: &mut Encoder, t: &ValueType) -> Result<(), EncodeError> {
+        encoder.write(u8::from(t.kind));
+
+        match &t.union {
+            ValueTypeU::FuncType { args, ret, .. } => {
+                let nargs = u8::try_from(args.len()).map_err(|_| {
+                    EncodeError::new(EncodeErrorKind::IndexOutOfRange).with_value(args.len() as i64)
+                })?;
+                encoder.write(nargs);
+                for arg in args {
+                    Code::write_type_ref(encoder, *arg)?;
+                }
+                Code::write_type_ref(encoder, *ret)?;
+            }
+            ValueTypeU::ObjType {
+                name,
+                super_type,
+                fields,
+                proto,
+                bindings,
+                global_value,
+                ..
+            } => {
+                encoder.write_string(name)?;
+                match super_type {
+                    Some(t) => Code::write_type_ref(encoder, *t)?,
+                    None => encoder.write_index(-1)?,
+                }
+                encoder.write_uindex(global_value.first().copied().unwrap_or(0) as usize)?;
+                encoder.write_uindex(fields.len())?;
+                encoder.write_uindex(proto.len())?;
+                encoder.write_uindex(bindings.len() / 2)?;
+                for field in fields {
+                    encoder.write_string(&field.name)?;
+                    Code::write_type_ref(encoder, field.t)?;
+                }
+                for p in proto {
+                    encoder.write_string(&p.name)?;
+                    encoder.write_uindex(p.findex)?;
+                    encoder.write_index(p.pindex)?;
+                }
+                for binding in bindings {
+                    encoder.write_uindex(*binding as usize)?;
+                }
             }
+            ValueTypeU::VirtualType { fields, .. } => {
+                encoder.write_uindex(fields.len())?;
+                for field in fields {
+                    encoder.write_string(&field.name)?;
+                    Code::write_type_ref(encoder, field.t)?;
+                }
+            }
+            ValueTypeU::EnumType {
+                name,
+                constructs,
+                global_value,
+                ..
+            } => {
+                encoder.write_string(name)?;
+                encoder.write_uindex(global_value.first().copied().unwrap_or(0) as usize)?;
+                encoder.write_uindex(constructs.len())?;
+                for con in constructs {
+                    encoder.write_string(&con.name)?;
+                    encoder.write_uindex(con.params.len())?;
+                    for param in &con.params {
+                        Code::write_type_ref(encoder, *param)?;
+                    }
+                }
+            }
+            _ => match t.kind {
+                TypeKind::HREF | TypeKind::HNULL | TypeKind::HPACKED => {
+                    let tparam = t
+                        .tparam
+                        .ok_or_else(|| EncodeError::new(EncodeErrorKind::UnknownType))?;
+                    Code::write_type_ref(encoder, tparam)?;
+                }
+                TypeKind::HABSTRACT => {
+                    let name = t
+                        .abs_name
+                        .as_ref()
+                        .ok_or_else(|| EncodeError::new(EncodeErrorKind::UnknownString))?;
+                    encoder.write_string(name)?;
+                }
+                _ => {}
+            },
         }
-        let _ = Code::check_error()?;
 
-        for i in 0..c.nfloats {
-            if let Ok(float) = f64::decode(&mut decoder).map_err(Code::map_err) {
-                c.floats.insert(i, float);
-            }
+        Ok(())
+    }
+
+    pub(crate) fn write_strings(encoder: &mut Encoder, strings: &[String]) -> Result<(), EncodeError> {
+        let size: usize = strings.iter().map(|s| s.len() + 1).sum();
+        let size = i32::try_from(size)
+            .map_err(|_| EncodeError::new(EncodeErrorKind::IndexOutOfRange).with_value(size as i64))?;
+        encoder.write(size);
+        for s in strings {
+            encoder.write_bytes(s.as_bytes());
+            encoder.write(0u8);
         }
-        let _ = Code::check_error()?;
+        for s in strings {
+            encoder.write_uindex(s.len())?;
+        }
+        Ok(())
+    }
 
-        if let Ok(strings) =
-            Code::read_strings(&mut decoder, c.nstrings, &mut c.strings_lens).map_err(Code::map_err)
-        {
-            c.strings = strings;
+    pub(crate) fn write_function(encoder: &mut Encoder, f: &HLFunction) -> Result<(), EncodeError> {
+        Code::write_type_ref(encoder, f.t)?;
+        encoder.write_uindex(f.findex)?;
+        encoder.write_uindex(f.regs.len())?;
+        encoder.write_uindex(f.ops.len())?;
+        for reg in &f.regs {
+            Code::write_type_ref(encoder, *reg)?;
+        }
+        for op in &f.ops {
+            Code::write_opcode(encoder, op)?;
         }
-        let _ = Code::check_error()?;
+        Ok(())
+    }
 
-        c.ustrings = vec![None; c.nstrings];
-        if c.version >= 5 {
-            let size = i32::decode(&mut decoder)?;
-            c.bytes = vec![0; size as usize];
-            decoder.read_bytes(&mut c.bytes).map_err(Code::map_err).unwrap();
-            for i in 0..c.nbytes {
-                if let Ok(byte_pos) = UINDEX(&mut decoder).map_err(Code::map_err){
-                    c.bytes_pos.insert(i, byte_pos);
+    pub(crate) fn write_opcode(encoder: &mut Encoder, o: &Opcode) -> Result<(), EncodeError> {
+        let missing = || EncodeError::new(EncodeErrorKind::MissingOperand).with_value(o.op as u8);
+        let p1 = || o.p1.ok_or_else(missing);
+        let p2 = || o.p2.ok_or_else(missing);
+        let p3 = || o.p3.ok_or_else(missing);
+        let extra = |i: usize| o.extra.get(i).map(|e| *e as i32).ok_or_else(missing);
+
+        encoder.write(o.op as u8);
+        match OP_NARGS[o.op as usize] {
+            0 => {}
+            1 => {
+                encoder.write_index(p1()?)?;
+            }
+            2 => {
+                encoder.write_index(p1()?)?;
+                encoder.write_index(p2()?)?;
+            }
+            3 => {
+                encoder.write_index(p1()?)?;
+                encoder.write_index(p2()?)?;
+                encoder.write_index(p3()?)?;
+            }
+            4 => {
+                encoder.write_index(p1()?)?;
+                encoder.write_index(p2()?)?;
+                encoder.write_index(p3()?)?;
+                encoder.write_index(extra(0)?)?;
+            }
+            -1 => match o.op {
+                Op::OCallN | Op::OCallClosure | Op::OCallMethod | Op::OCallThis | Op::OMakeEnum => {
+                    encoder.write_index(p1()?)?;
+                    encoder.write_index(p2()?)?;
+                    let nargs = u8::try_from(o.extra.len()).map_err(|_| {
+                        EncodeError::new(EncodeErrorKind::IndexOutOfRange)
+                            .with_value(o.extra.len() as i64)
+                    })?;
+                    encoder.write(nargs);
+                    for i in 0..o.extra.len() {
+                        encoder.write_index(extra(i)?)?;
+                    }
+                }
+                Op::OSwitch => {
+                    encoder.write_index(p1()?)?;
+                    encoder.write_uindex(o.extra.len())?;
+                    for i in 0..o.extra.len() {
+                        encoder.write_index(extra(i)?)?;
+                    }
+                    encoder.write_index(p3()?)?;
+                }
+                _ => return Err(missing()),
+            },
+            nargs => {
+                encoder.write_index(p1()?)?;
+                encoder.write_index(p2()?)?;
+                encoder.write_index(p3()?)?;
+                for i in 0..(nargs - 3) as usize {
+                    encoder.write_index(extra(i)?)?;
                 }
             }
-            let _ = Code::check_error()?;
         }
+        Ok(())
+    }
 
-        if c.hasdebug != 0 {
-            if let Ok(ndebugfiles) =  UINDEX(&mut decoder) {
-                c.ndebugfiles = ndebugfiles;
+    /// Writes a line table the way the Haxe compiler does, so that decoded
+    /// tables are written back byte for byte.
+    pub(crate) fn write_debug_infos(encoder: &mut Encoder, debug: &[i32]) {
+        let mut curfile = -1;
+        let mut curline = 0;
+        let mut repeat = 0;
+
+        fn flush_repeat(encoder: &mut Encoder, repeat: &mut i32, curline: &mut i32, line: i32) {
+            while *repeat > 15 {
+                encoder.write(((15 << 2) | 2) as u8);
+                *repeat -= 15;
             }
-            decoder.code = c.clone();
-            
-            if let Ok(debugfiles) = Code::read_strings(&mut decoder, c.ndebugfiles, &mut c.debugfiles_lens).map_err(Code::map_err) {
-                decoder.code.debugfiles = debugfiles;
+            if *repeat > 0 {
+                let delta = line - *curline;
+                let delta = if delta > 0 && delta < 4 { delta } else { 0 };
+                encoder.write(((delta << 6) | (*repeat << 2) | 2) as u8);
+                *repeat = 0;
+                *curline += delta;
             }
-            c = decoder.code.clone();
-            let _ = Code::check_error()?;
         }
 
-        c.types = vec![ValueType::default(); c.ntypes];
-
-        for i in 0..c.ntypes {
-            let mut t = ValueType::default();
-            decoder.code = c.clone();
-            Code::read_type(&mut decoder, &mut t)?;
-            decoder.code.types.insert(i, t);
-            c = decoder.code.clone();
+        for pair in debug.chunks_exact(2) {
+            let (file, line) = (pair[0], pair[1]);
+            if file != curfile {
+                flush_repeat(encoder, &mut repeat, &mut curline, line);
+                curfile = file;
+                encoder.write((((file >> 8) << 1) | 1) as u8);
+                encoder.write(file as u8);
+            }
+            if line != curline {
+                flush_repeat(encoder, &mut repeat, &mut curline, line);
+            }
+            if line == curline {
+                repeat += 1;
+            } else {
+                let delta = line - curline;
+                if delta > 0 && delta < 32 {
+                    encoder.write(((delta << 3) | 4) as u8);
+                } else {
+                    encoder.write((line << 3) as u8);
+                    encoder.write((line >> 5) as u8);
+                    encoder.write((line >> 13) as u8);
+                }
+                curline = line;
+            }
         }
+        let line = curline;
+        flush_repeat(encoder, &mut repeat, &mut curline, line);
+    }
 
-        c.globals = vec![ValueType::default(); c.nglobals];
-        for i in 0..c.nglobals {
-            decoder.code = c.clone();
-            if let Ok(t) = Code::get_type(&mut decoder).map_err(Code::map_err) {
-                decoder.code.globals.insert(i, t);
-            }
-            c = decoder.code.clone();
+    /// Serializes this code back into the HLB format of `self.version`.
+    pub fn write(&self) -> Result<Vec<u8>, EncodeError> {
+        let mut encoder = Encoder::new();
+        encoder.strings = self
+            .strings
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, s)| (s.clone(), i))
+            .collect();
+
+        encoder.write_bytes(b"HLB");
+        encoder.write(self.version);
+        encoder.write_uindex(self.hasdebug as usize & 1)?;
+        encoder.write_uindex(self.ints.len())?;
+        encoder.write_uindex(self.floats.len())?;
+        encoder.write_uindex(self.strings.len())?;
+        if self.version >= 5 {
+            encoder.write_uindex(self.bytes_pos.len())?;
+        }
+        encoder.write_uindex(self.types.len())?;
+        encoder.write_uindex(self.globals.len())?;
+        encoder.write_uindex(self.natives.len())?;
+        encoder.write_uindex(self.functions.len())?;
+        if self.version >= 4 {
+            encoder.write_uindex(self.constants.len())?;
         }
+        encoder.write_uindex(self.entrypoint as usize)?;
 
-        let _ = Code::check_error()?;
+        for i in &self.ints {
+            encoder.write(*i);
+        }
+        for f in &self.floats {
+            encoder.write(*f);
+        }
+        Code::write_strings(&mut encoder, &self.strings)?;
 
-        c.natives = Vec::new();
-        for _i in 0..c.nnatives {
-            c.natives.push(Native {
-                lib: Code::read_string(&mut decoder).map_err(Code::map_err).unwrap(),
-                name: Code::read_string(&mut decoder).map_err(Code::map_err).unwrap(),
-                t: Code::get_type(&mut decoder).map_err(Code::map_err).unwrap(),
-                findex: UINDEX(&mut decoder).map_err(Code::map_err).unwrap(),
-            });
-            decoder.code = c.clone();
+        if self.version >= 5 {
+            encoder.write(self.bytes.len() as i32);
+            encoder.write_bytes(&self.bytes);
+            for pos in &self.bytes_pos {
+                encoder.write_uindex(*pos)?;
+            }
         }
-        let _ = Code::check_error()?;
 
-       
+        if self.hasdebug != 0 {
+            encoder.write_uindex(self.debugfiles.len())?;
+            Code::write_strings(&mut encoder, &self.debugfiles)?;
+        }
 
-        for i in 0..c.nfunctions {
-            decoder.code = c.clone();
-            let f = Code::read_function(&mut decoder).map_err(Code::map_err).unwrap();
-            println!("{:?}", f);
-            decoder.code.functions.push(f);
-            if decoder.code.hasdebug != 0 {
-                let nops = decoder.code.functions[i].nops;
-                if let Ok(debug) = Code::debug_infos(&mut decoder, nops).map_err(Code::map_err) {
-                    decoder.code.functions[i].debug = debug;
-                }
-                if decoder.code.version >= 3 {
-                    // skip assigns (no need here)
-                    let nassigns = UINDEX(&mut decoder).map_err(Code::map_err).unwrap();
-                    for _j in 0..nassigns {
-                        let _ = UINDEX(&mut decoder);
-                        let _ = INDEX(&mut decoder);
+        for t in &self.types {
+            Code::write_type(&mut encoder, t)?;
+        }
+        for g in &self.globals {
+            Code::write_type_ref(&mut encoder, *g)?;
+        }
+        for n in &self.natives {
+            encoder.write_string(&n.lib)?;
+            encoder.write_string(&n.name)?;
+            Code::write_type_ref(&mut encoder, n.t)?;
+            encoder.write_uindex(n.findex)?;
+        }
+        for (i, f) in self.functions.iter().enumerate() {
+            // bodies never decoded are still the bytes they were read from
+            let f = match &self.lazy {
+                Some(lazy) => match lazy.bodies[i].function.get() {
+                    Some(f) => f,
+                    None => {
+                        encoder.write_bytes(&lazy.source[lazy.bodies[i].range.clone()]);
+                        continue;
+                    }
+                },
+                None => f,
+            };
+            Code::write_function(&mut encoder, f)?;
+            if self.hasdebug != 0 {
+                Code::write_debug_infos(&mut encoder, &f.debug);
+                if self.version >= 3 {
+                    encoder.write_uindex(f.assigns.len())?;
+                    for a in &f.assigns {
+                        encoder.write_string(&a.name)?;
+                        encoder.write_index(a.op_index)?;
                     }
                 }
             }
-            c = decoder.code.clone();
         }
-
-        let _ = Code::check_error()?;
-
-        for i in 0..c.nconstants {
-            let mut k = Constant {
-                global: UINDEX(&mut decoder).map_err(Code::map_err).unwrap().try_into().unwrap(),
-                nfields: UINDEX(&mut decoder).map_err(Code::map_err).unwrap(),
-                fields: Vec::new(),
-            };
-
-            for j in 0..k.nfields {
-                k.fields
-                    .insert(j, UINDEX(&mut decoder).map_err(Code::map_err).unwrap().try_into().unwrap());
+        if self.version >= 4 {
+            for k in &self.constants {
+                encoder.write_uindex(k.global as usize)?;
+                encoder.write_uindex(k.fields.len())?;
+                for field in &k.fields {
+                    encoder.write_uindex(*field as usize)?;
+                }
             }
-            let _ = Code::check_error()?;
-            c.constants.insert(i, k);
-            decoder.code = c.clone();
         }
 
-        Ok(decoder.code)
+        Ok(encoder.buf)
     }
 }
 
-
-
 #[cfg(test)]
 mod tests {
     use std::{
         fs::File,
         io::{BufReader, Read},
-        path::Path,
+        panic,
+        path::{Path, PathBuf},
     };
 
-    use super::Code;
-    #[test]
-    fn read() {
+    use super::{hash, Code};
+    use crate::errors::{DecodeErrorKind, DecodeSection};
+    use crate::op::{Op, Opcode};
+    use crate::types::{HLFunction, ObjField, TypeId, TypeKind, ValueType, ValueTypeU};
+
+    fn example_path() -> PathBuf {
         let pwd = std::env::current_dir().expect("expect current dir");
-        let binary = Path::new(&pwd.display().to_string())
+        Path::new(&pwd.display().to_string())
             .join("..")
             .join("..")
-            .join("example/bin/test.hl");
-        let f = File::open(binary).expect("Could not read hashlink file");
+            .join("example/bin/test.hl")
+    }
+
+    #[test]
+    fn read() {
+        let f = File::open(example_path()).expect("Could not read hashlink file");
         let mut reader = BufReader::new(f);
         let mut buf = Vec::new();
 
@@ -837,4 +1400,353 @@ mod tests {
 
         assert!(code.is_ok(), "Code is not okay! = {:?}", code.err().unwrap())
     }
+
+    #[test]
+    fn failed_read_does_not_leak_into_next_read() {
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+
+        assert!(Code::read(&buf[..buf.len() / 2]).is_err());
+        assert!(Code::read(&buf).is_ok());
+    }
+
+    #[test]
+    fn write_round_trips_example() {
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+        let code = Code::read(&buf).expect("Could not decode hashlink binary");
+        let written = code.write().expect("Could not encode hashlink binary");
+        assert!(written == buf, "re-encoded bytecode differs from the original");
+    }
+
+    #[test]
+    fn read_lazy_matches_read() {
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+        let eager = Code::read(&buf).expect("Could not decode hashlink binary");
+        let mut lazy = Code::read_lazy(&buf).expect("Could not decode hashlink binary");
+        assert!(lazy.is_lazy());
+        assert!(lazy.functions.iter().all(|f| f.ops.is_empty()));
+
+        // untouched bodies are written back as they were read
+        assert!(lazy.write().unwrap() == buf);
+
+        for i in (0..eager.functions.len()).rev().step_by(7) {
+            assert!(lazy.function(i).unwrap() == &eager.functions[i]);
+        }
+        let e = lazy.function(eager.functions.len()).unwrap_err();
+        assert_eq!(e.kind(), DecodeErrorKind::InvalidFunctionIndex);
+        assert_eq!(e.position(), None);
+        assert!(lazy.write().unwrap() == buf);
+
+        lazy.load_functions().unwrap();
+        assert!(!lazy.is_lazy());
+        assert!(lazy.functions == eager.functions);
+    }
+
+    /// A module with a single `fn() -> void` that returns immediately.
+    fn minimal_code(version: u8) -> Code {
+        let void = ValueType {
+            kind: TypeKind::HVOID,
+            ..ValueType::default()
+        };
+        let fun = ValueType {
+            kind: TypeKind::HFUN,
+            union: ValueTypeU::FuncType {
+                args: Vec::new(),
+                nargs: 0,
+                ret: TypeId(0),
+            },
+            ..ValueType::default()
+        };
+        let ret = Opcode {
+            op: Op::ORet,
+            p1: Some(0),
+            ..Opcode::default()
+        };
+
+        let mut c = Code::new();
+        c.version = version;
+        c.hasdebug = 1;
+        c.strings = vec!["main.hx".to_string()];
+        c.debugfiles = vec!["Main.hx".to_string()];
+        c.types = vec![void, fun];
+        c.functions = vec![HLFunction {
+            t: TypeId(1),
+            findex: 0,
+            nregs: 1,
+            nops: 1,
+            rf: 0,
+            regs: vec![TypeId(0)],
+            ops: vec![ret],
+            debug: vec![0, 3],
+            assigns: Vec::new(),
+            obj: None,
+            field: None,
+        }];
+        if version >= 5 {
+            c.bytes = vec![1, 2, 3, 0, 4];
+            c.bytes_pos = vec![0, 3];
+        }
+        c
+    }
+
+    #[test]
+    fn write_round_trips_every_version() {
+        for version in 2..=5 {
+            let written = minimal_code(version).write().unwrap();
+            let code = Code::read(&written).unwrap();
+            assert_eq!(code.version, version);
+            assert_eq!(code.functions[0].debug, vec![0, 3]);
+            assert_eq!(code.write().unwrap(), written, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn assigned_vars() {
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+        let code = Code::read(&buf).expect("Could not decode hashlink binary");
+        let f = code.functions.iter().find(|f| f.findex == 222).unwrap();
+        let names = |op| {
+            f.assigned_vars(op)
+                .iter()
+                .map(|a| a.name.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(0), ["t", "v"]);
+        assert_eq!(names(13), ["t", "v"]);
+        assert_eq!(names(14), ["v", "t"]);
+        assert_eq!(f.assigned_vars(14)[0].op_index, 14);
+    }
+
+    #[test]
+    fn utf16_strings() {
+        let mut code = minimal_code(4);
+        code.strings.push("h\u{e9}llo \u{20ac}\u{1d11e}".to_string());
+        let written = code.write().unwrap();
+        let decoded = Code::read(&written).unwrap();
+        for (i, s) in decoded.strings.iter().enumerate() {
+            let utf16: Vec<u16> = s.encode_utf16().collect();
+            assert_eq!(decoded.get_ustring(i), Some(&utf16[..]));
+        }
+        assert_eq!(decoded.get_ustring(1).unwrap().len(), 9);
+        assert_eq!(decoded.get_ustring(2), None);
+
+        // the pool holds "main.hx\0h..." right after its size
+        let pool = written.windows(7).position(|w| w == b"main.hx").unwrap();
+        let mut invalid_utf8 = written.clone();
+        invalid_utf8[pool + 9] = 0xFF;
+        let e = Code::read(&invalid_utf8).unwrap_err();
+        assert_eq!((e.kind(), e.value()), (DecodeErrorKind::InvalidString, Some(1)));
+        assert_eq!(e.position(), Some(pool + 8));
+
+        let mut unterminated = written;
+        unterminated[pool + 7] = b'!';
+        let e = Code::read(&unterminated).unwrap_err();
+        assert_eq!((e.kind(), e.value()), (DecodeErrorKind::InvalidString, Some(0)));
+    }
+
+    #[test]
+    fn bytes_constants() {
+        let code = Code::read(&minimal_code(5).write().unwrap()).unwrap();
+        assert_eq!(code.bytes_constant(0), [1, 2, 3]);
+        assert_eq!(code.bytes_constant(1), [0, 4]);
+
+        let mut code = minimal_code(5);
+        code.bytes_pos = vec![3, 0];
+        let e = Code::read(&code.write().unwrap()).unwrap_err();
+        assert_eq!(e.kind(), DecodeErrorKind::InvalidBytesOffset);
+        assert_eq!((e.section(), e.value()), (Some(DecodeSection::Bytes), Some(0)));
+
+        code.bytes_pos = vec![0, 6];
+        let e = Code::read(&code.write().unwrap()).unwrap_err();
+        assert_eq!((e.kind(), e.value()), (DecodeErrorKind::InvalidBytesOffset, Some(6)));
+    }
+
+    #[test]
+    fn source_locations_both_ways() {
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+        let code = Code::read(&buf).expect("Could not decode hashlink binary");
+        let lazy = Code::read_lazy(&buf).expect("Could not decode hashlink binary");
+
+        let entry = code.entrypoint as usize;
+        let location = code.source_location(entry, 0).unwrap();
+        assert!(code.debugfiles.iter().any(|f| f == location.file));
+        assert_eq!(lazy.source_location(entry, 0), Some(location));
+        assert!(code.source_location(entry, usize::MAX >> 1).is_none());
+        assert!(code.source_location(usize::MAX, 0).is_none());
+
+        for f in code.functions.iter().step_by(11) {
+            for op in (0..f.nops).step_by(5) {
+                let location = code.source_location(f.findex, op).unwrap();
+                let ops = code.ops_at_line(location.file, location.line);
+                assert!(ops.contains(&(f.findex, op)));
+                assert!(ops
+                    .iter()
+                    .all(|&(findex, op)| code.source_location(findex, op) == Some(location)));
+            }
+        }
+        assert!(code.ops_at_line("Missing.hx", 1).is_empty());
+    }
+
+    fn obj_type(name: &str, super_type: Option<u32>, fields: &[(&str, u32)]) -> ValueType {
+        ValueType {
+            kind: TypeKind::HOBJ,
+            union: ValueTypeU::ObjType {
+                name: name.to_string(),
+                super_type: super_type.map(TypeId),
+                fields: fields
+                    .iter()
+                    .map(|(name, t)| ObjField {
+                        name: name.to_string(),
+                        hashed_name: hash(name.as_bytes()),
+                        t: TypeId(*t),
+                    })
+                    .collect(),
+                nfields: fields.len(),
+                nproto: 0,
+                nbindings: 0,
+                proto: Vec::new(),
+                bindings: Vec::new(),
+                global_value: vec![0],
+                rt: None,
+            },
+            ..ValueType::default()
+        }
+    }
+
+    /// `minimal_code` followed by `types`, whose names must be in `strings`.
+    fn code_with_types(strings: &[&str], types: Vec<ValueType>) -> Vec<u8> {
+        let mut code = minimal_code(4);
+        code.strings.extend(strings.iter().map(|s| s.to_string()));
+        code.types.extend(types);
+        code.write().unwrap()
+    }
+
+    fn super_name(code: &Code, t: TypeId) -> Option<&str> {
+        match &code.get_type(t).union {
+            ValueTypeU::ObjType { super_type, .. } => match &code.get_type((*super_type)?).union {
+                ValueTypeU::ObjType { name, .. } => Some(name),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn recursive_types_round_trip() {
+        let types = vec![
+            obj_type("Node", None, &[("next", 2)]),
+            obj_type("A", None, &[("b", 4)]),
+            obj_type("B", None, &[("a", 3)]),
+        ];
+        let written = code_with_types(&["Node", "next", "A", "b", "B", "a"], types.clone());
+        let decoded = Code::read(&written).unwrap();
+        assert!(decoded.types[2..] == types[..]);
+        for t in &decoded.types {
+            t.hash(&decoded.types, false);
+        }
+    }
+
+    #[test]
+    fn subclasses_before_superclasses() {
+        let types = vec![
+            obj_type("Child", Some(3), &[("parent", 3)]),
+            obj_type("Base", Some(4), &[]),
+            obj_type("Root", None, &[("child", 2)]),
+        ];
+        let written = code_with_types(&["Child", "parent", "Base", "Root", "child"], types);
+        let decoded = Code::read(&written).unwrap();
+        assert_eq!(super_name(&decoded, TypeId(2)), Some("Base"));
+        assert_eq!(super_name(&decoded, TypeId(3)), Some("Root"));
+        assert_eq!(super_name(&decoded, TypeId(4)), None);
+        assert_eq!(decoded.write().unwrap(), written);
+    }
+
+    #[test]
+    fn invalid_super_types_are_rejected() {
+        let cycle = vec![obj_type("A", Some(3), &[]), obj_type("B", Some(2), &[])];
+        let e = Code::read(&code_with_types(&["A", "B"], cycle)).unwrap_err();
+        assert_eq!(e.kind(), DecodeErrorKind::InvalidSuperType);
+        assert_eq!(e.section(), Some(DecodeSection::Types));
+
+        let not_an_object = vec![obj_type("A", Some(0), &[])];
+        let e = Code::read(&code_with_types(&["A"], not_an_object)).unwrap_err();
+        assert_eq!(e.kind(), DecodeErrorKind::InvalidSuperType);
+        assert_eq!(e.value(), Some(0));
+    }
+
+    #[test]
+    fn errors_carry_section_and_value() {
+        let e = Code::read(b"HLB\x07").unwrap_err();
+        assert_eq!(e.kind(), DecodeErrorKind::UnsupportedBytecodeVersion);
+        assert_eq!(e.to_string(), "header: unsupported bytecode version 7 at byte 0x3");
+
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+        let e = Code::read(&buf[..64]).unwrap_err();
+        assert_eq!(e.kind(), DecodeErrorKind::NoMoreData);
+        assert_eq!(e.section(), Some(DecodeSection::Ints));
+    }
+
+    /// Small xorshift generator so the mutation corpus is reproducible.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    fn mutate(rng: &mut Rng, buf: &[u8]) -> Vec<u8> {
+        let mut out = buf.to_vec();
+        // keep the header most of the time so mutations reach deeper sections
+        let at = 4 + rng.below(out.len() - 4);
+        match rng.below(6) {
+            0 => out[at] ^= 1 << rng.below(8),
+            1 => out[at] = rng.next() as u8,
+            2 => out[at] = 0xFF,
+            3 => out.truncate(at),
+            4 => {
+                let len = 1 + rng.below(16).min(out.len() - at - 1);
+                out.drain(at..at + len);
+            }
+            _ => {
+                let len = 1 + rng.below(16).min(out.len() - at - 1);
+                let chunk = out[at..at + len].to_vec();
+                out.splice(at..at, chunk);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn read_never_panics_on_mutated_bytecode() {
+        let buf = std::fs::read(example_path()).expect("Could not read hashlink binary");
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+
+        let mut corpus: Vec<Vec<u8>> = (0..buf.len())
+            .step_by(buf.len() / 64)
+            .map(|len| buf[..len].to_vec())
+            .collect();
+        corpus.extend((0..256).map(|_| mutate(&mut rng, &buf)));
+        // counts far larger than the file must fail before allocating
+        let mut huge = buf.clone();
+        huge.splice(5..6, [0xDF, 0xFF, 0xFF, 0xFF]);
+        corpus.push(huge);
+
+        for (i, input) in corpus.iter().enumerate() {
+            let result = panic::catch_unwind(|| Code::read(input));
+            assert!(result.is_ok(), "mutant #{} panicked", i);
+            let result = panic::catch_unwind(|| {
+                if let Ok(mut code) = Code::read_lazy(input) {
+                    let _ = code.load_functions();
+                }
+            });
+            assert!(result.is_ok(), "mutant #{} panicked when read lazily", i);
+        }
+    }
 }
@@ -2,10 +2,9 @@ use crate::{
     code::Code,
     native::Native,
     op::{Op, OP_NARGS},
-    types::{HLFunction, TypeKind, ValueType, ValueTypeU},
+    types::{FuncField, HLFunction, TypeId, TypeKind, ValueTypeU},
 };
 use crc32fast::Hasher as Crc32Hasher;
-use num_enum::{IntoPrimitive, TryFromPrimitive};
 use std::hash::Hasher;
 
 #[derive(Clone, Debug)]
@@ -43,30 +42,29 @@ impl CodeHash {
             code_hash.functions_indexes[n.findex] = (i + c.nfunctions) as i32;
         }
 
+        code_hash.name_functions();
+
         for i in 0..c.ntypes {
-            let types = c.types.clone();
-            code_hash.type_hashes[i] = types[i].clone().hash(false);
+            code_hash.type_hashes[i] = c.types[i].hash(&c.types, false);
         }
 
         let mut type_hashes = vec![0; c.ntypes]; // use a second buffer for order-indepedent
-        for i in 0..c.ntypes {
-            type_hashes.insert(i, code_hash.type_hashes[i] ^ code_hash.hash_type_rec(i));
+        for (i, h) in type_hashes.iter_mut().enumerate() {
+            *h = code_hash.type_hashes[i] ^ code_hash.hash_type_rec(i);
         }
         code_hash.type_hashes.clear();
         code_hash.type_hashes.clone_from(&type_hashes);
 
         for i in 0..c.nglobals {
-            let types = c.globals.clone();
             code_hash.globals_signs[i] = (i | 0x80000000) as i32;
-            if let TypeKind::HABSTRACT = types[i].kind {
-                code_hash.globals_signs[i] = code_hash.code_hash_type(types[i].clone()) as i32;
+            if let TypeKind::HABSTRACT = c.get_type(c.globals[i]).kind {
+                code_hash.globals_signs[i] = code_hash.code_hash_type(c.globals[i]) as i32;
                 // some global abstracts allocated by compiler
             }
         }
 
         for i in 0..c.ntypes {
-            let types = c.types.clone();
-            let t = types[i].clone();
+            let t = &c.types[i];
             match t.kind {
                 TypeKind::HOBJ | TypeKind::HSTRUCT => {
                     if let ValueTypeU::ObjType {
@@ -82,8 +80,11 @@ impl CodeHash {
                         rt: _,
                     } = t.union
                     {
-                        code_hash.globals_signs[((*global_value)[0] - 1) as usize] =
-                            code_hash.code_hash_type(t.clone()) as i32;
+                        // a zero global_value means the type has no global
+                        if global_value[0] > 0 {
+                            code_hash.globals_signs[(global_value[0] - 1) as usize] =
+                                code_hash.code_hash_type(TypeId(i as u32)) as i32;
+                        }
                     }
                 }
                 TypeKind::HENUM => {
@@ -94,8 +95,11 @@ impl CodeHash {
                         ref global_value,
                     } = t.union
                     {
-                        code_hash.globals_signs[((*global_value)[0] - 1) as usize] =
-                            code_hash.code_hash_type(t.clone()) as i32;
+                        // a zero global_value means the type has no global
+                        if global_value[0] > 0 {
+                            code_hash.globals_signs[(global_value[0] - 1) as usize] =
+                                code_hash.code_hash_type(TypeId(i as u32)) as i32;
+                        }
                     }
                 }
                 _ => {}
@@ -103,7 +107,7 @@ impl CodeHash {
         }
         for i in 0..c.nconstants {
             let conn = c.constants[i].clone();
-            let tt = &c.globals[conn.global as usize];
+            let tt = c.get_type(c.globals[conn.global as usize]);
             let mut hasher = Crc32Hasher::new();
             for k in 0..conn.nfields {
                 let index = conn.fields[k];
@@ -120,7 +124,7 @@ impl CodeHash {
                     rt: _,
                 } = &tt.union
                 {
-                    match fields[k].t.kind {
+                    match c.get_type(fields[k].t).kind {
                         TypeKind::HI32 => {
                             hasher.write_i32(c.ints[index as usize]);
                         }
@@ -140,19 +144,19 @@ impl CodeHash {
         for i in 4..f.nops {
             let op = &f.ops[i];
             if let Op::OSetGlobal = op.op {
-                let t = &c.globals[i];
+                let t = c.get_type(c.globals[op.p1.unwrap() as usize]);
                 if t.kind == TypeKind::HENUM
                     && f.ops[i - 2].op == Op::OGetArray
                     && f.ops[i - 3].op == Op::OInt
                 {
                     code_hash.globals_signs[op.p1.unwrap() as usize] =
-                        c.ints[(f.ops[i - 3].p2.unwrap() as usize)] as i32;
+                        c.ints[f.ops[i - 3].p2.unwrap() as usize];
                 }
             }
         }
 
         for i in 0..c.nglobals {
-            code_hash.globals_signs[i] ^= code_hash.code_hash_type(c.globals[i].clone()) as i32;
+            code_hash.globals_signs[i] ^= code_hash.code_hash_type(c.globals[i]) as i32;
         }
 
         code_hash
@@ -163,16 +167,11 @@ impl CodeHash {
         match t.kind {
             TypeKind::HFUN | TypeKind::HMETHOD => {
                 if let ValueTypeU::FuncType { args, nargs, ret } = &t.union {
-                    for i in 0..*nargs {
-                        let a = args.get(i).unwrap().clone();
-                        let i = self.code.types.iter().position(|v| *v == a).unwrap();
-                        let h = self.type_hashes[i];
-                        hasher.write_u32(h);
+                    for a in &args[..*nargs] {
+                        hasher.write_u32(self.type_hashes[a.index()]);
                     }
 
-                    let r = ret.as_ref().clone();
-                    let i = self.code.types.iter().position(|v| *v == r).unwrap();
-                    hasher.write_u32(self.type_hashes[i]);
+                    hasher.write_u32(self.type_hashes[ret.index()]);
                 }
             }
             TypeKind::HOBJ | TypeKind::HSTRUCT => {
@@ -189,22 +188,14 @@ impl CodeHash {
                     rt: _,
                 } = &t.union
                 {
-                    for i in 0..*nfields {
-                        let f = fields.get(i).unwrap().clone().t;
-                        let i = self.code.types.iter().position(|v| *v == f).unwrap();
-                        hasher.write_u32(self.type_hashes[i]);
+                    for f in &fields[..*nfields] {
+                        hasher.write_u32(self.type_hashes[f.t.index()]);
                     }
                 }
             }
             TypeKind::HREF | TypeKind::HNULL => {
-                let p = t.tparam.as_ref().unwrap();
-                let i = self
-                    .code
-                    .types
-                    .iter()
-                    .position(|v| v == p.as_ref())
-                    .unwrap();
-                hasher.write_u32(self.type_hashes[i]);
+                let p = t.tparam.unwrap();
+                hasher.write_u32(self.type_hashes[p.index()]);
             }
             TypeKind::HENUM => {
                 if let ValueTypeU::EnumType {
@@ -214,13 +205,9 @@ impl CodeHash {
                     global_value: _,
                 } = &t.union
                 {
-                    for i in 0..*nconstructs {
-                        let con = constructs.get(i).unwrap().clone();
-
-                        for k in 0..con.nparams {
-                            let p = con.params.get(k).unwrap();
-                            let i = self.code.types.iter().position(|v| v == p).unwrap();
-                            hasher.write_u32(self.type_hashes[i]);
+                    for con in &constructs[..*nconstructs] {
+                        for p in &con.params[..con.nparams] {
+                            hasher.write_u32(self.type_hashes[p.index()]);
                         }
                     }
                 }
@@ -235,71 +222,104 @@ impl CodeHash {
         let mut hasher = Crc32Hasher::new();
         hasher.write(n.lib.as_bytes());
         hasher.write(n.name.as_bytes());
-        let i = self.code.types.iter().position(|v| *v == n.t).unwrap();
-        hasher.write_u32(self.type_hashes[i]);
+        hasher.write_u32(self.type_hashes[n.t.index()]);
         hasher.finalize()
     }
 
     pub fn hash_fun_sign(&self, f: HLFunction) -> u32 {
         let mut hasher = Crc32Hasher::new();
-        let i = self.code.types.iter().position(|v| *v == f.t).unwrap();
-        hasher.write_u32(self.type_hashes[i]);
+        hasher.write_u32(self.type_hashes[f.t.index()]);
         let field = f.field.as_ref();
 
-        if let Some(ValueTypeU::ObjType {
-            name,
-            super_type: _,
-            fields: _,
-            nfields: _,
-            nproto: _,
-            nbindings: _,
-            proto: _,
-            bindings: _,
-            global_value: _,
-            rt: _,
-        }) = f.obj
-        {
+        if let (Some(ValueTypeU::ObjType { name, .. }), Some(field)) = (&f.obj, field) {
             hasher.write(name.as_bytes());
-            hasher.write(field.unwrap().name.as_bytes());
-        }
-
-        if let Some(rf) = &field.unwrap().rf {
-            if let Some(ValueTypeU::ObjType {
-                name,
-                super_type: _,
-                fields: _,
-                nfields: _,
-                nproto: _,
-                nbindings: _,
-                proto: _,
-                bindings: _,
-                global_value: _,
-                rt: _,
-            }) = &rf.obj
-            {
+            hasher.write(field.name.as_bytes());
+        } else if let Some(rf) = field.and_then(|field| field.rf.as_ref()) {
+            if let Some(ValueTypeU::ObjType { name, .. }) = &rf.obj {
                 hasher.write(name.as_bytes());
             }
-            hasher.write(rf.field.as_ref().unwrap().name.as_bytes());
+            if let Some(field) = &rf.field {
+                hasher.write(field.name.as_bytes());
+            }
             hasher.write_u32(f.rf);
         }
         hasher.finalize()
     }
 
+    /// Names methods and bound static functions after their class field,
+    /// and anonymous functions after the function creating their closure,
+    /// since signatures are built from these names.
+    fn name_functions(&mut self) {
+        let nfunctions = self.code.functions.len();
+        let position = |indexes: &[i32], findex: usize| {
+            indexes
+                .get(findex)
+                .map(|i| *i as usize)
+                .filter(|i| *i < nfunctions)
+        };
+
+        let mut names = Vec::new();
+        for (t, ty) in self.code.types.iter().enumerate() {
+            if let ValueTypeU::ObjType {
+                proto, bindings, ..
+            } = &ty.union
+            {
+                for p in proto {
+                    names.push((p.findex, ty.union.clone(), p.name.clone()));
+                }
+                for pair in bindings.chunks_exact(2) {
+                    let field = self.code.type_field(TypeId(t as u32), pair[0] as usize);
+                    if let Some(field) = field.filter(|field| {
+                        matches!(
+                            self.code.get_type(field.t).kind,
+                            TypeKind::HFUN | TypeKind::HDYN
+                        )
+                    }) {
+                        names.push((pair[1] as usize, ty.union.clone(), field.name.clone()));
+                    }
+                }
+            }
+        }
+        for (findex, obj, name) in names {
+            if let Some(i) = position(&self.functions_indexes, findex) {
+                let f = &mut self.code.functions[i];
+                f.obj = Some(obj);
+                f.field = Some(FuncField { name, rf: None });
+            }
+        }
+
+        for i in 0..nfunctions {
+            let mut count = 0;
+            for k in 0..self.code.functions[i].ops.len() {
+                let o = &self.code.functions[i].ops[k];
+                if !matches!(o.op, Op::OStaticClosure | Op::OInstanceClosure) {
+                    continue;
+                }
+                let target = match position(&self.functions_indexes, o.p2.unwrap_or(-1) as usize) {
+                    Some(target) if self.code.functions[target].field.is_none() => target,
+                    _ => continue,
+                };
+                let parent = Box::new(self.code.functions[i].clone());
+                let f = &mut self.code.functions[target];
+                f.field = Some(FuncField {
+                    name: String::new(),
+                    rf: Some(parent),
+                });
+                f.rf = count;
+                count += 1;
+            }
+        }
+    }
+
     fn hfun(&self, hasher: &mut Crc32Hasher, idx: usize) {
-        hasher.write_u32(self.functions_hashes[self.functions_indexes[idx] as usize]);
+        hasher.write_u32(self.functions_signs[self.functions_indexes[idx] as usize]);
     }
 
     pub fn hash_fun(&self, f: HLFunction) -> u32 {
         let mut hasher = Crc32Hasher::new();
         let c = &self.code;
-        for i in 0..f.nregs {
-            let fi = self
-                .code
-                .types
-                .iter()
-                .position(|v| *v == f.regs[i])
-                .unwrap();
-            hasher.write_u32(self.type_hashes[fi]);
+        for r in &f.regs[..f.nregs] {
+            hasher.write_u32(self.type_hashes[r.index()]);
         }
         for k in 0..f.nops {
             let o = &f.ops[k];
@@ -309,29 +329,24 @@ impl CodeHash {
                 Op::OInt => {
                     hasher.write_i32(o.p1.unwrap());
                     let p2: usize = o.p2.unwrap().try_into().unwrap();
-                    hasher.write_i32(*(*c).ints.get(p2).unwrap());
+                    hasher.write_i32(c.ints[p2]);
                 }
                 Op::OFloat => {
                     hasher.write_i32(o.p1.unwrap());
                     let p2: usize = o.p2.unwrap().try_into().unwrap();
-                    hasher.write_i32((*(*c).floats.get(p2 << 1).unwrap()) as i32);
-                    hasher.write_i32((*(*c).floats.get(p2 << 1 | 1).unwrap()) as i32);
+                    let bits = c.floats[p2].to_bits();
+                    hasher.write_u32(bits as u32);
+                    hasher.write_u32((bits >> 32) as u32);
                 }
                 Op::OString => {
                     hasher.write_i32(o.p1.unwrap());
                     let p2: usize = o.p2.unwrap().try_into().unwrap();
-                    hasher.write((*(*c).strings.get(p2).unwrap()).as_bytes());
+                    hasher.write(c.strings[p2].as_bytes());
                 }
                 Op::OType => {
                     hasher.write_i32(o.p1.unwrap());
                     let p2: usize = o.p2.unwrap().try_into().unwrap();
-                    let ti = self
-                        .code
-                        .types
-                        .iter()
-                        .position(|v| *v == c.types[p2])
-                        .unwrap();
-                    hasher.write_u32(self.type_hashes[ti]);
+                    hasher.write_u32(self.type_hashes[p2]);
                 }
                 Op::OCall0 => {
                     hasher.write_i32(o.p1.unwrap());
@@ -450,10 +465,9 @@ impl CodeHash {
         }
         hasher.finalize()
     }
-    pub fn code_hash_type(&self, t: ValueType) -> u32 {
+    pub fn code_hash_type(&self, t: TypeId) -> u32 {
         let mut hasher = Crc32Hasher::new();
-        let ti = self.code.types.iter().position(|v| *v == t).unwrap();
-        hasher.write_u32(self.type_hashes[ti]);
+        hasher.write_u32(self.type_hashes[t.index()]);
         hasher.finalize()
     }
 
@@ -466,7 +480,7 @@ impl CodeHash {
         }
         for i in 0..c.nnatives {
             let f = &c.natives[i];
-            self.functions_signs[i] = self.hash_native(f.clone());
+            self.functions_signs[i + c.nfunctions] = self.hash_native(f.clone());
         }
 
         self.functions_hashes = vec![0; c.nfunctions];
@@ -476,3 +490,48 @@ impl CodeHash {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CodeHash;
+    use crate::code::Code;
+
+    #[test]
+    fn alloc_example() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let buf = std::fs::read(path).expect("Could not read hashlink binary");
+        let code = Code::read(&buf).expect("Could not decode hashlink binary");
+        let code_hash = CodeHash::alloc(&code);
+        assert_eq!(code_hash.type_hashes.len(), code.types.len());
+    }
+
+    #[test]
+    fn finalize_example() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let buf = std::fs::read(path).expect("Could not read hashlink binary");
+        let code = Code::read(&buf).expect("Could not decode hashlink binary");
+        let mut code_hash = CodeHash::alloc(&code);
+        code_hash.finalize();
+        assert_eq!(code_hash.functions_signs.len(), code.functions.len() + code.natives.len());
+        assert_eq!(code_hash.functions_hashes.len(), code.functions.len());
+
+        // methods are named after their class, anonymous functions after
+        // the function creating them
+        let main = code_hash.code.functions.iter().find(|f| match &f.field {
+            Some(field) => field.name == "main",
+            None => false,
+        });
+        assert!(main.is_some());
+        assert!(code_hash
+            .code
+            .functions
+            .iter()
+            .any(|f| f.field.as_ref().is_some_and(|field| field.rf.is_some())));
+
+        // the same code hashes the same way
+        let mut again = CodeHash::alloc(&code);
+        again.finalize();
+        assert_eq!(again.functions_signs, code_hash.functions_signs);
+        assert_eq!(again.functions_hashes, code_hash.functions_hashes);
+    }
+}
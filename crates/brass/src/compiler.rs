@@ -1,33 +1,116 @@
-use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::{mem, mem::offset_of, ptr};
 
 use cranelift::{
-    codegen::{ir::{immediates::Offset32, GlobalValue}, Context},
-    frontend::FunctionBuilder,
-    prelude::{types, EntityRef, GlobalValueData, Type},
+    codegen::{
+        ir::{ExternalName, FuncRef, Opcode},
+        Context,
+    },
+    frontend::{FunctionBuilder, FunctionBuilderContext, Variable},
+    prelude::{
+        settings, types, AbiParam, Block, Configurable, EntityRef, FloatCC, InstBuilder, IntCC,
+        JumpTableData, MemFlags, Signature, StackSlotData, StackSlotKind, TrapCode, Type,
+        Value as ClValue,
+    },
 };
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{FuncId, Linkage, Module};
+use cranelift_module::{DataContext, DataId, FuncId, Linkage, Module, ModuleError};
 
-use crate::{code::Code, code_hash::CodeHash, types::TypeKind};
+use crate::{
+    cfg::Cfg,
+    code::Code,
+    code_hash::CodeHash,
+    errors::VmError,
+    instr::{FunIdx, Instr, Reg},
+    native::Native,
+    op::Op,
+    runtime::{self, Closure, Runtime, Wrapper},
+    types::{HLFunction, TypeId, TypeKind, ValueType, ValueTypeU},
+    verify::Diagnostic,
+    vm::Value,
+};
+
+const HOT_RELOAD_EXTRA_GLOBALS: usize = 4096;
 
-const HOT_RELOAD_EXTRA_GLOBALS: i32 = 4096;
+/// Whether values of `kind` are pointers the GC has to scan, which is every
+/// kind from `HBYTES` on.
+pub fn is_ptr(kind: TypeKind) -> bool {
+    kind as u8 >= TypeKind::HBYTES as u8
+}
 
-pub struct HLModule<'a> {
+/// Size in bytes of the values of `kind` once stored in memory.
+pub fn type_size(kind: TypeKind) -> usize {
+    match kind {
+        TypeKind::HVOID => 0,
+        TypeKind::HUI8 | TypeKind::HBOOL => 1,
+        TypeKind::HUI16 => 2,
+        TypeKind::HI32 | TypeKind::HF32 => 4,
+        TypeKind::HI64 | TypeKind::HF64 => 8,
+        _ => mem::size_of::<*mut u8>(),
+    }
+}
+
+pub struct HLModule {
     pub module: JITModule,
     pub module_ctx: Context,
-    pub code: &'a Code,
+    pub code: Rc<Code>,
     pub codesize: usize,
-    // pub globals_size: usize,
-    // pub globals_indexes: Vec<i32>,
-    // pub globals_data: Vec<u32>,
-    pub functions_ptrs: Vec<FuncId>,
+    pub globals_size: usize,
+    /// Offset of every global in `globals_data`.
+    pub globals_indexes: Vec<usize>,
+    pub globals_data: *mut u8,
+    globals_id: Option<DataId>,
+    /// Offsets of the globals holding GC references.
+    roots: Vec<usize>,
+    /// Declaration of every function by findex, bytecode or native. Natives
+    /// without an implementation are left out.
+    pub functions_ptrs: Vec<Option<FuncId>>,
+    /// Index of every findex in `Code::functions`, or `nfunctions` plus its
+    /// index in `Code::natives`.
     pub functions_indexes: Vec<i32>,
+    /// Compiled bytecode functions, by findex.
+    compiled: Vec<(usize, FuncId)>,
+    /// Dynamic call wrappers, by function or method type index.
+    wrappers: Vec<(usize, FuncId)>,
+    runtime: Box<Runtime>,
+    /// Symbols given to `with_natives`, and the natives of the runtime.
+    native_symbols: HashSet<String>,
     pub code_hash: Option<CodeHash>,
 }
 
-impl<'a> HLModule<'a> {
-    pub fn new(code: &'a Code) -> Self {
-        let builder = JITBuilder::new(cranelift_module::default_libcall_names()).unwrap();
+impl HLModule {
+    #[cfg(test)]
+    pub fn new(code: Rc<Code>) -> Self {
+        Self::with_natives(code, &[])
+    }
+
+    /// Creates a module whose natives can also resolve to `natives`, pairs
+    /// of a symbol as named by `native_symbol` and its address.
+    pub fn with_natives(code: Rc<Code>, natives: &[(&str, *const u8)]) -> Self {
+        // Position dependent code, as the GOT that PIC goes through may be
+        // mapped too far from the code for its 32-bit relocations.
+        let mut flags = settings::builder();
+        flags.set("use_colocated_libcalls", "false").unwrap();
+        flags.set("is_pic", "false").unwrap();
+        let isa = cranelift_native::builder()
+            .unwrap()
+            .finish(settings::Flags::new(flags))
+            .unwrap();
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+        builder.symbol("fmod", fmod as *const u8);
+        builder.symbol("fmodf", fmodf as *const u8);
+        builder.symbol("brass_alloc_closure", runtime::alloc_closure as *const u8);
+        builder.symbol(
+            "brass_virtual_closure",
+            runtime::virtual_closure as *const u8,
+        );
+        builder.symbol("brass_dyn_call", runtime::dyn_call as *const u8);
+        builder.symbol("brass_null_access", runtime::null_access as *const u8);
+        // natives of the standard library that the runtime provides itself
+        let std_natives = [("hl_sys_args", runtime::sys_args as *const u8)];
+        builder.symbols(std_natives.iter().map(|(name, p)| (name.to_string(), *p)));
+        builder.symbols(natives.iter().map(|(name, p)| (name.to_string(), *p)));
         // The Module holds information about all functions and data objects defined in the current JIT
         let module = JITModule::new(builder);
         // This is the main Context object for compiling functions.
@@ -36,90 +119,1884 @@ impl<'a> HLModule<'a> {
         // if hot reloading
         let code_hash = None;
 
-        // let gsize = 0;
-
-        // let globals_data = vec![0; code.nglobals];
-        // let globals_indexes = vec![0; code.nglobals];
-
         let functions_indexes = vec![0; code.nfunctions + code.nnatives];
-        let functions_ptrs = vec![FuncId::new(0); code.nfunctions + code.nnatives];
+        let functions_ptrs = vec![None; code.nfunctions + code.nnatives];
+        let globals_indexes = vec![0; code.nglobals];
+        let runtime = Box::new(Runtime::new(&code));
 
         HLModule {
             module,
             module_ctx,
             code,
             codesize: 0,
-            // globals_size: code.nglobals,
-            // globals_indexes,
-            // globals_data,
+            globals_size: 0,
+            globals_indexes,
+            globals_data: ptr::null_mut(),
+            globals_id: None,
+            roots: Vec::new(),
             functions_ptrs,
             functions_indexes,
+            compiled: Vec::new(),
+            wrappers: Vec::new(),
+            runtime,
+            native_symbols: std_natives
+                .iter()
+                .chain(natives)
+                .map(|(name, _)| name.to_string())
+                .collect(),
             code_hash,
         }
     }
-    pub fn init(&mut self, hot_reload: bool) {
+    pub fn init(&mut self, hot_reload: bool) -> Result<(), VmError> {
         if hot_reload {
-            self.code_hash = Some(CodeHash::alloc(self.code));
+            self.code_hash = Some(CodeHash::alloc(&self.code));
         }
 
-        self.init_globals();
+        self.init_globals()?;
+        self.declare_functions()?;
+        self.compile_wrappers()
     }
 
-    pub fn init_globals(&mut self) {
+    /// Declares every function up front, so that calls can reach functions
+    /// which are not compiled yet.
+    pub fn declare_functions(&mut self) -> Result<(), VmError> {
+        let declare_error = |e: ModuleError| VmError::Unsupported(e.to_string());
+        for i in 0..self.code.nfunctions {
+            let f = self.code.function(i)?;
+            let sig = self.signature(f.t);
+            let name = format!("fun@{}", f.findex);
+            self.functions_ptrs[f.findex] = Some(
+                self.module
+                    .declare_function(&name, Linkage::Local, &sig)
+                    .map_err(declare_error)?,
+            );
+            self.functions_indexes[f.findex] = i as i32;
+        }
+        for (i, n) in self.code.natives.iter().enumerate() {
+            self.functions_indexes[n.findex] = (self.code.nfunctions + i) as i32;
+            // an unresolved import would only fail when finalizing
+            let symbol = native_symbol(n);
+            if !self.native_symbols.contains(&symbol) {
+                continue;
+            }
+            let sig = self.signature(n.t);
+            self.functions_ptrs[n.findex] = Some(
+                self.module
+                    .declare_function(&symbol, Linkage::Import, &sig)
+                    .map_err(declare_error)?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Lays out every global at its natural alignment in one zeroed data
+    /// object, and records the ones holding references as GC roots.
+    pub fn init_globals(&mut self) -> Result<(), VmError> {
+        let mut gsize = 0;
+        let mut align = 1;
+        self.roots.clear();
         for i in 0..self.code.nglobals {
-            let t = &self.code.globals[i];
+            let kind = self.code.get_type(self.code.globals[i]).kind;
+            let size = type_size(kind);
+            let a = size.max(1);
+            gsize = (gsize + a - 1) & !(a - 1);
+            align = align.max(a);
+            self.globals_indexes[i] = gsize;
+            if is_ptr(kind) {
+                self.roots.push(gsize);
+            }
+            gsize += size;
+        }
+        if self.code_hash.is_some() {
+            gsize += HOT_RELOAD_EXTRA_GLOBALS * mem::size_of::<*mut u8>();
+        }
+        self.globals_size = gsize;
 
-            match t.kind {
-                TypeKind::HUI8 => {
-                    self.translate_global(types::I8, format!("$global{}", i).to_string(), true, Offset32::from(i as i32));
-                }
-                TypeKind::HUI16 => todo!(),
-                TypeKind::HI32 => todo!(),
-                TypeKind::HI64 => todo!(),
-                TypeKind::HF32 => todo!(),
-                TypeKind::HF64 => todo!(),
-                TypeKind::HBOOL => todo!(),
-                TypeKind::HBYTES => todo!(),
-                TypeKind::HDYN => todo!(),
-                TypeKind::HFUN => todo!(),
-                TypeKind::HOBJ => todo!(),
-                TypeKind::HARRAY => todo!(),
-                TypeKind::HTYPE => todo!(),
-                TypeKind::HREF => todo!(),
-                TypeKind::HVIRTUAL => todo!(),
-                TypeKind::HDYNOBJ => todo!(),
-                TypeKind::HABSTRACT => todo!(),
-                TypeKind::HENUM => todo!(),
-                TypeKind::HNULL => todo!(),
-                TypeKind::HMETHOD => todo!(),
-                TypeKind::HSTRUCT => todo!(),
-                TypeKind::HPACKED => todo!(),
-                TypeKind::HLAST => todo!(),
-                _ => {}
-            }
-        }
-    }
-
-    fn translate_global(&mut self, t: Type, name: String, writable: bool, offset: Offset32)-> GlobalValue {
-        let sym = self
-            .module
-            .declare_data(name.as_str(), Linkage::Export, writable, false)
-            .expect("problem declaring data object");
-        let local_id = self
+        let declare_error = |e: ModuleError| VmError::Unsupported(e.to_string());
+        let id = self
             .module
-            .declare_data_in_func(sym, &mut self.module_ctx.func);
-        self.module_ctx
-            .func
-            .create_global_value(GlobalValueData::Load {
-                base: local_id,
-                offset,
-                global_type: t,
-                readonly: false,
+            .declare_data("$globals", Linkage::Local, true, false)
+            .map_err(declare_error)?;
+        let mut data = DataContext::new();
+        // an empty data object would have no address
+        data.define_zeroinit(gsize.max(1));
+        data.set_align(align as u64);
+        self.module.define_data(id, &data).map_err(declare_error)?;
+        self.module.finalize_definitions();
+        self.globals_data = self.module.get_finalized_data(id).0 as *mut u8;
+        self.globals_id = Some(id);
+        Ok(())
+    }
+
+    /// Addresses of the globals holding references, for the GC to scan.
+    pub fn gc_roots(&self) -> impl Iterator<Item = *mut *mut u8> + '_ {
+        self.roots
+            .iter()
+            .map(move |offset| unsafe { self.globals_data.add(*offset) as *mut *mut u8 })
+    }
+
+    /// Signature of functions of type `t`, void returns left out.
+    pub fn signature(&self, t: TypeId) -> Signature {
+        signature(&self.code, &self.module, t)
+    }
+
+    /// Compiles the function at `index` in `Code::functions`, once
+    /// `declare_functions` ran.
+    pub fn compile_function(&mut self, index: usize) -> Result<FuncId, VmError> {
+        let f = self.code.function(index)?;
+        let id = self.functions_ptrs[f.findex]
+            .ok_or_else(|| VmError::Unsupported(format!("fun@{} is not declared", f.findex)))?;
+        self.module_ctx.func.signature = self.signature(f.t);
+        self.module_ctx.func.name = ExternalName::user(0, id.as_u32());
+
+        let mut builder_ctx = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut self.module_ctx.func, &mut builder_ctx);
+        let result = FunctionCompiler::new(
+            &self.code,
+            f,
+            &mut self.module,
+            &self.functions_ptrs,
+            &mut *self.runtime,
+            builder,
+        )
+        .and_then(FunctionCompiler::compile)
+        .and_then(|()| {
+            self.module
+                .define_function(id, &mut self.module_ctx)
+                .map(|defined| self.codesize += defined.size as usize)
+                .map_err(|e| VmError::Unsupported(e.to_string()))
+        });
+        self.module.clear_context(&mut self.module_ctx);
+        result?;
+        self.compiled.push((f.findex, id));
+        Ok(id)
+    }
+
+    /// Compiles the dynamic call wrapper of every function and method type,
+    /// through which closures are called with another type than their own.
+    pub fn compile_wrappers(&mut self) -> Result<(), VmError> {
+        let ptr_type = self.module.target_config().pointer_type();
+        let mut wrapper_sig = self.module.make_signature();
+        for ty in [ptr_type, ptr_type, types::I32, ptr_type] {
+            wrapper_sig.params.push(AbiParam::new(ty));
+        }
+        wrapper_sig.returns.push(AbiParam::new(types::I64));
+
+        for (i, t) in self.code.types.iter().enumerate() {
+            let args = match &t.union {
+                ValueTypeU::FuncType { args, .. } => args,
+                _ => continue,
+            };
+            let id = self
+                .module
+                .declare_function(&format!("wrap@{}", i), Linkage::Local, &wrapper_sig)
+                .map_err(|e| VmError::Unsupported(e.to_string()))?;
+            let sig = self.signature(TypeId(i as u32));
+            let mut bound_sig = sig.clone();
+            bound_sig.params.insert(0, AbiParam::new(ptr_type));
+            self.module_ctx.func.signature = wrapper_sig.clone();
+            self.module_ctx.func.name = ExternalName::user(0, id.as_u32());
+
+            let mut builder_ctx = FunctionBuilderContext::new();
+            let mut b = FunctionBuilder::new(&mut self.module_ctx.func, &mut builder_ctx);
+            let entry = b.create_block();
+            let bound = b.create_block();
+            let unbound = b.create_block();
+            let done = b.create_block();
+            b.append_block_param(done, types::I64);
+            b.append_block_params_for_function_params(entry);
+            b.switch_to_block(entry);
+            let params = b.block_params(entry).to_vec();
+            let (fun, value, has_value, slots) = (params[0], params[1], params[2], params[3]);
+            let mut values = vec![value];
+            for (n, a) in args.iter().enumerate() {
+                let ty = cl_type(self.code.get_type(*a).kind, ptr_type);
+                let v = b.ins().load(ty, MemFlags::trusted(), slots, 8 * n as i32);
+                values.push(v);
+            }
+            b.ins().brz(has_value, unbound, &[]);
+            b.ins().jump(bound, &[]);
+            for (block, sig, values) in [
+                (bound, bound_sig, &values[..]),
+                (unbound, sig, &values[1..]),
+            ] {
+                b.switch_to_block(block);
+                let sig = b.import_signature(sig);
+                let call = b.ins().call_indirect(sig, fun, values);
+                let ret = b.inst_results(call).first().copied();
+                let ret = to_slot(&mut b, ret);
+                b.ins().jump(done, &[ret]);
+            }
+            b.switch_to_block(done);
+            let ret = b.block_params(done)[0];
+            b.ins().return_(&[ret]);
+            b.seal_all_blocks();
+            b.finalize();
+
+            let result = self
+                .module
+                .define_function(id, &mut self.module_ctx)
+                .map(|defined| self.codesize += defined.size as usize)
+                .map_err(|e| VmError::Unsupported(e.to_string()));
+            self.module.clear_context(&mut self.module_ctx);
+            result?;
+            self.wrappers.push((i, id));
+        }
+        Ok(())
+    }
+
+    /// Makes every compiled function executable, and lets the runtime find
+    /// them and the dynamic call wrappers.
+    pub fn finalize(&mut self) {
+        self.module.finalize_definitions();
+        for &(findex, id) in &self.compiled {
+            self.runtime.functions[findex] = self.module.get_finalized_function(id);
+        }
+        for &(t, id) in &self.wrappers {
+            let p = self.module.get_finalized_function(id);
+            self.runtime.wrappers[t] = Some(unsafe { mem::transmute::<*const u8, Wrapper>(p) });
+        }
+    }
+
+    /// Address of a compiled function, finalizing the module.
+    pub fn function_ptr(&mut self, id: FuncId) -> *const u8 {
+        self.finalize();
+        self.module.get_finalized_function(id)
+    }
+
+    /// Clears the exception raised by the last call into compiled code, if
+    /// any, describing the value thrown.
+    pub fn take_exception(&mut self) -> Option<String> {
+        self.runtime.take_exception()
+    }
+
+    fn global_ptr(&self, index: usize) -> Result<(*mut u8, TypeKind), VmError> {
+        if self.globals_data.is_null() || index >= self.code.nglobals {
+            return Err(VmError::InvalidGlobal(index));
+        }
+        let kind = self.code.get_type(self.code.globals[index]).kind;
+        Ok((
+            unsafe { self.globals_data.add(self.globals_indexes[index]) },
+            kind,
+        ))
+    }
+
+    /// Reads global `index` once `init_globals` has run.
+    pub fn get_global(&self, index: usize) -> Result<Value, VmError> {
+        let (p, kind) = self.global_ptr(index)?;
+        // offsets are aligned for their kind by `init_globals`
+        unsafe {
+            Ok(match kind {
+                TypeKind::HVOID => Value::Void,
+                TypeKind::HUI8 => Value::UI8(*p),
+                TypeKind::HUI16 => Value::UI16(*(p as *const u16)),
+                TypeKind::HI32 => Value::I32(*(p as *const i32)),
+                TypeKind::HI64 => Value::I64(*(p as *const i64)),
+                TypeKind::HF32 => Value::F32(*(p as *const f32)),
+                TypeKind::HF64 => Value::F64(*(p as *const f64)),
+                TypeKind::HBOOL => Value::Bool(*p != 0),
+                _ => Value::Ptr(*(p as *const *mut u8)),
             })
+        }
+    }
+
+    /// Writes global `index`, failing if `value` does not match its kind.
+    pub fn set_global(&mut self, index: usize, value: Value) -> Result<(), VmError> {
+        let (p, kind) = self.global_ptr(index)?;
+        unsafe {
+            match (kind, value) {
+                (TypeKind::HVOID, Value::Void) => {}
+                (TypeKind::HUI8, Value::UI8(v)) => *p = v,
+                (TypeKind::HUI16, Value::UI16(v)) => *(p as *mut u16) = v,
+                (TypeKind::HI32, Value::I32(v)) => *(p as *mut i32) = v,
+                (TypeKind::HI64, Value::I64(v)) => *(p as *mut i64) = v,
+                (TypeKind::HF32, Value::F32(v)) => *(p as *mut f32) = v,
+                (TypeKind::HF64, Value::F64(v)) => *(p as *mut f64) = v,
+                (TypeKind::HBOOL, Value::Bool(v)) => *p = v as u8,
+                (kind, Value::Ptr(v)) if is_ptr(kind) => *(p as *mut *mut u8) = v,
+                _ => return Err(VmError::InvalidGlobal(index)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Translates the ops of one function, with one Cranelift variable per
+/// register.
+pub struct FunctionCompiler<'a, 'b> {
+    pub code: &'a Code,
+    pub f: &'a HLFunction,
+    pub module: &'b mut JITModule,
+    functions_ptrs: &'b [Option<FuncId>],
+    runtime: *mut Runtime,
+    pub builder: FunctionBuilder<'b>,
+    vars: Vec<Variable>,
+    kinds: Vec<TypeKind>,
+    instrs: Vec<Instr>,
+    cfg: Cfg,
+    /// Cranelift block of each block of `cfg`.
+    blocks: Vec<Block>,
+    libcalls: HashMap<&'static str, FuncRef>,
+    /// Callees imported into the function, by findex.
+    callees: HashMap<usize, FuncRef>,
+    /// Block returning to the caller with a pending exception.
+    unwind: Option<Block>,
+}
+
+impl<'a, 'b> FunctionCompiler<'a, 'b> {
+    pub fn new(
+        code: &'a Code,
+        f: &'a HLFunction,
+        module: &'b mut JITModule,
+        functions_ptrs: &'b [Option<FuncId>],
+        runtime: *mut Runtime,
+        builder: FunctionBuilder<'b>,
+    ) -> Result<Self, VmError> {
+        let kinds = f.regs.iter().map(|t| code.get_type(*t).kind).collect();
+        let instrs = f.instrs()?;
+        let cfg = Cfg::new(&instrs);
+        Ok(FunctionCompiler {
+            code,
+            f,
+            module,
+            functions_ptrs,
+            runtime,
+            builder,
+            vars: Vec::new(),
+            kinds,
+            instrs,
+            cfg,
+            blocks: Vec::new(),
+            libcalls: HashMap::new(),
+            callees: HashMap::new(),
+            unwind: None,
+        })
+    }
+
+    pub fn compile(mut self) -> Result<(), VmError> {
+        let instrs = mem::take(&mut self.instrs);
+        let ptr_type = self.module.target_config().pointer_type();
+        let entry = self.builder.create_block();
+        self.builder.append_block_params_for_function_params(entry);
+        self.builder.switch_to_block(entry);
+        self.builder.seal_block(entry);
+
+        for (i, kind) in self.kinds.clone().into_iter().enumerate() {
+            let var = Variable::new(i);
+            let ty = cl_type(kind, ptr_type);
+            self.builder.declare_var(var, ty);
+            self.vars.push(var);
+        }
+        // arguments come first, every other register starts zeroed
+        let nargs = self.builder.block_params(entry).len();
+        for i in 0..self.vars.len() {
+            let v = if i < nargs {
+                self.builder.block_params(entry)[i]
+            } else {
+                self.zero(self.kinds[i])
+            };
+            self.builder.def_var(self.vars[i], v);
+        }
+
+        self.blocks = self
+            .cfg
+            .blocks
+            .iter()
+            .map(|_| self.builder.create_block())
+            .collect();
+        for (pos, instr) in instrs.iter().enumerate() {
+            let b = self.cfg.block_of(pos);
+            if self.cfg.blocks[b].ops.start == pos {
+                if !self.builder.is_filled() {
+                    self.builder.ins().jump(self.blocks[b], &[]);
+                }
+                self.builder.switch_to_block(self.blocks[b]);
+            }
+            self.translate(pos, instr)?;
+        }
+        if !self.builder.is_filled() {
+            // ops never fall off the end of valid bytecode
+            self.builder.ins().trap(TrapCode::UnreachableCodeReached);
+        }
+        if let Some(unwind) = self.unwind {
+            self.builder.switch_to_block(unwind);
+            let returns: Vec<_> = self.builder.func.signature.returns.clone();
+            let zeros: Vec<_> = returns.iter().map(|r| self.zero_of(r.value_type)).collect();
+            self.builder.ins().return_(&zeros);
+        }
+        // loop headers only know their predecessors once every op is in
+        self.builder.seal_all_blocks();
+        self.builder.finalize();
+        Ok(())
+    }
+
+    fn zero(&mut self, kind: TypeKind) -> ClValue {
+        let ptr_type = self.module.target_config().pointer_type();
+        self.zero_of(cl_type(kind, ptr_type))
+    }
+
+    fn zero_of(&mut self, ty: Type) -> ClValue {
+        match ty {
+            types::F32 => self.builder.ins().f32const(0.0),
+            types::F64 => self.builder.ins().f64const(0.0),
+            ty => self.builder.ins().iconst(ty, 0),
+        }
+    }
+
+    fn unwind_block(&mut self) -> Block {
+        match self.unwind {
+            Some(b) => b,
+            None => {
+                let b = self.builder.create_block();
+                self.unwind = Some(b);
+                b
+            }
+        }
+    }
+
+    /// Raises `exc`: there are no traps yet, so every frame returns to its
+    /// caller until the entrypoint reports it.
+    fn throw(&mut self, exc: Reg) {
+        let ptr_type = self.module.target_config().pointer_type();
+        let (flag, value) = unsafe {
+            (
+                ptr::addr_of!((*self.runtime).has_exception),
+                ptr::addr_of!((*self.runtime).exception),
+            )
+        };
+        let one = self.builder.ins().iconst(types::I8, 1);
+        let flag = self.builder.ins().iconst(ptr_type, flag as i64);
+        self.builder.ins().store(MemFlags::trusted(), one, flag, 0);
+        let exc = self.get(exc);
+        let value = self.builder.ins().iconst(ptr_type, value as i64);
+        self.builder.ins().store(MemFlags::trusted(), exc, value, 0);
+        let unwind = self.unwind_block();
+        self.builder.ins().jump(unwind, &[]);
+    }
+
+    /// Raises HashLink's null access exception if `v` is null, giving the
+    /// value to use in its place for the code that follows.
+    fn null_check(&mut self, v: ClValue) -> Result<ClValue, VmError> {
+        let ptr_type = self.module.target_config().pointer_type();
+        let f = self.libcall("brass_null_access", &[ptr_type], &[])?;
+        let null = self.builder.create_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brz(v, null, &[]);
+        self.builder.ins().jump(next, &[]);
+
+        self.builder.switch_to_block(null);
+        let rt = self.builder.ins().iconst(ptr_type, self.runtime as i64);
+        self.builder.ins().call(f, &[rt]);
+        let unwind = self.unwind_block();
+        self.builder.ins().jump(unwind, &[]);
+
+        self.builder.switch_to_block(next);
+        // Cranelift 0.84 folds a constant address into the addressing mode
+        // of a load without a base register, which breaks register
+        // allocation, so a constant null is hidden behind an instruction
+        let dfg = &self.builder.func.dfg;
+        let is_const = dfg
+            .value_def(v)
+            .inst()
+            .is_some_and(|i| dfg[i].opcode() == Opcode::Iconst);
+        Ok(if is_const {
+            self.builder.ins().bor_imm(v, 0)
+        } else {
+            v
+        })
+    }
+
+    /// Leaves the function if the call just made raised an exception.
+    fn check_exception(&mut self) {
+        let ptr_type = self.module.target_config().pointer_type();
+        let flag = unsafe { ptr::addr_of!((*self.runtime).has_exception) };
+        let flag = self.builder.ins().iconst(ptr_type, flag as i64);
+        let raised = self
+            .builder
+            .ins()
+            .load(types::I8, MemFlags::trusted(), flag, 0);
+        let unwind = self.unwind_block();
+        let next = self.builder.create_block();
+        self.builder.ins().brnz(raised, unwind, &[]);
+        self.builder.ins().jump(next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    fn get(&mut self, r: Reg) -> ClValue {
+        self.builder.use_var(self.vars[r.index()])
+    }
+
+    fn set(&mut self, r: Reg, v: ClValue) {
+        self.builder.def_var(self.vars[r.index()], v);
+    }
+
+    fn kind(&self, r: Reg) -> TypeKind {
+        self.kinds[r.index()]
+    }
+
+    fn reg_type(&self, r: Reg) -> Type {
+        cl_type(self.kind(r), self.module.target_config().pointer_type())
+    }
+
+    fn is_float(&self, r: Reg) -> bool {
+        matches!(self.kind(r), TypeKind::HF32 | TypeKind::HF64)
+    }
+
+    /// Block starting at the target of a jump at `pos`.
+    fn target(&self, pos: usize, offset: i32) -> Result<Block, VmError> {
+        let t = pos as i64 + 1 + offset as i64;
+        let nops = self.cfg.blocks.last().map_or(0, |b| b.ops.end);
+        if t < 0 || t >= nops as i64 {
+            return Err(VmError::Invalid(vec![Diagnostic {
+                findex: self.f.findex,
+                op: Some(pos),
+                message: format!("jump to op {} out of the function", t),
+            }]));
+        }
+        Ok(self.blocks[self.cfg.block_of(t as usize)])
+    }
+
+    fn unsupported(&self, pos: usize, instr: &Instr) -> VmError {
+        let name: &'static str = instr.op().into();
+        VmError::Unsupported(format!(
+            "fun@{}, op {}: {} is not supported by the JIT",
+            self.f.findex, pos, name
+        ))
+    }
+
+    /// Imports a runtime helper, such as `fmod`.
+    fn libcall(
+        &mut self,
+        name: &'static str,
+        params: &[Type],
+        returns: &[Type],
+    ) -> Result<FuncRef, VmError> {
+        if let Some(f) = self.libcalls.get(name) {
+            return Ok(*f);
+        }
+        let mut sig = self.module.make_signature();
+        sig.params.extend(params.iter().map(|t| AbiParam::new(*t)));
+        sig.returns.extend(returns.iter().map(|t| AbiParam::new(*t)));
+        let id = self
+            .module
+            .declare_function(name, Linkage::Import, &sig)
+            .map_err(|e| VmError::Unsupported(e.to_string()))?;
+        let f = self.module.declare_func_in_func(id, self.builder.func);
+        self.libcalls.insert(name, f);
+        Ok(f)
+    }
+
+    fn translate(&mut self, pos: usize, instr: &Instr) -> Result<(), VmError> {
+        match *instr {
+            Instr::Mov { dst, src } => {
+                let v = self.get(src);
+                self.set(dst, v);
+            }
+            Instr::Int { dst, index } => {
+                let n = self.code.ints[index as usize];
+                let ty = self.reg_type(dst);
+                let v = self.builder.ins().iconst(ty, n as i64);
+                self.set(dst, v);
+            }
+            Instr::Float { dst, index } => {
+                let n = self.code.floats[index as usize];
+                let v = match self.kind(dst) {
+                    TypeKind::HF32 => self.builder.ins().f32const(n as f32),
+                    _ => self.builder.ins().f64const(n),
+                };
+                self.set(dst, v);
+            }
+            Instr::Bool { dst, value } => {
+                let v = self.builder.ins().iconst(types::I8, (value != 0) as i64);
+                self.set(dst, v);
+            }
+            Instr::Null { dst } => {
+                let v = self.zero(self.kind(dst));
+                self.set(dst, v);
+            }
+
+            Instr::Add { dst, a, b }
+            | Instr::Sub { dst, a, b }
+            | Instr::Mul { dst, a, b }
+            | Instr::SDiv { dst, a, b }
+            | Instr::UDiv { dst, a, b }
+            | Instr::SMod { dst, a, b }
+            | Instr::UMod { dst, a, b }
+            | Instr::Shl { dst, a, b }
+            | Instr::SShr { dst, a, b }
+            | Instr::UShr { dst, a, b }
+            | Instr::And { dst, a, b }
+            | Instr::Or { dst, a, b }
+            | Instr::Xor { dst, a, b } => {
+                let v = self.binop(instr.op(), dst, a, b)?;
+                self.set(dst, v);
+            }
+            Instr::Neg { dst, src } => {
+                let x = self.get(src);
+                let v = if self.is_float(src) {
+                    self.builder.ins().fneg(x)
+                } else {
+                    self.builder.ins().ineg(x)
+                };
+                self.set(dst, v);
+            }
+            Instr::Not { dst, src } => {
+                let x = self.get(src);
+                let v = self.builder.ins().bxor_imm(x, 1);
+                self.set(dst, v);
+            }
+            Instr::Incr { dst } | Instr::Decr { dst } => {
+                let x = self.get(dst);
+                let n = if matches!(instr, Instr::Incr { .. }) {
+                    1
+                } else {
+                    -1
+                };
+                let v = self.builder.ins().iadd_imm(x, n);
+                self.set(dst, v);
+            }
+
+            Instr::ToSFloat { dst, src } | Instr::ToUFloat { dst, src } => {
+                let mut x = self.get(src);
+                let ty = self.reg_type(dst);
+                let mut from = self.builder.func.dfg.value_type(x);
+                // ui8 and ui16 are unsigned whichever conversion is asked
+                if from.is_int() && from.bits() < 32 {
+                    x = self.builder.ins().uextend(types::I32, x);
+                    from = types::I32;
+                }
+                let v = if from == ty {
+                    x
+                } else if from == types::F32 {
+                    self.builder.ins().fpromote(ty, x)
+                } else if from == types::F64 {
+                    self.builder.ins().fdemote(ty, x)
+                } else if matches!(instr, Instr::ToSFloat { .. }) {
+                    self.builder.ins().fcvt_from_sint(ty, x)
+                } else {
+                    self.builder.ins().fcvt_from_uint(ty, x)
+                };
+                self.set(dst, v);
+            }
+            Instr::ToInt { dst, src } => {
+                let x = self.get(src);
+                let ty = self.reg_type(dst);
+                let from = self.builder.func.dfg.value_type(x);
+                let v = if from.is_float() {
+                    // as on HashLink x86, NaN and out of range values give
+                    // the minimum, which saturating already gives below it
+                    let sat = self.builder.ins().fcvt_to_sint_sat(ty, x);
+                    let max = 2f64.powi(ty.bits() as i32 - 1);
+                    let max = if from == types::F32 {
+                        self.builder.ins().f32const(max as f32)
+                    } else {
+                        self.builder.ins().f64const(max)
+                    };
+                    let in_range = self.builder.ins().fcmp(FloatCC::LessThan, x, max);
+                    let min = self.builder.ins().iconst(ty, i64::MIN >> (64 - ty.bits()));
+                    self.builder.ins().select(in_range, sat, min)
+                } else if from.bits() < ty.bits() {
+                    match self.kind(src) {
+                        TypeKind::HUI8 | TypeKind::HUI16 => self.builder.ins().uextend(ty, x),
+                        _ => self.builder.ins().sextend(ty, x),
+                    }
+                } else if from.bits() > ty.bits() {
+                    self.builder.ins().ireduce(ty, x)
+                } else {
+                    x
+                };
+                self.set(dst, v);
+            }
+
+            Instr::Ret { reg } => {
+                if self.builder.func.signature.returns.is_empty() {
+                    self.builder.ins().return_(&[]);
+                } else {
+                    let v = self.get(reg);
+                    self.builder.ins().return_(&[v]);
+                }
+            }
+            Instr::Call0 { dst, fun } => self.call(pos, dst, fun, &[])?,
+            Instr::Call1 { dst, fun, a } => self.call(pos, dst, fun, &[a])?,
+            Instr::Call2 { dst, fun, a, b } => self.call(pos, dst, fun, &[a, b])?,
+            Instr::Call3 { dst, fun, a, b, c } => self.call(pos, dst, fun, &[a, b, c])?,
+            Instr::Call4 {
+                dst,
+                fun,
+                a,
+                b,
+                c,
+                d,
+            } => self.call(pos, dst, fun, &[a, b, c, d])?,
+            Instr::CallN { dst, fun, ref args } => self.call(pos, dst, fun, args)?,
+            Instr::StaticClosure { dst, fun } | Instr::InstanceClosure { dst, fun, .. } => {
+                let callee = self.callee(pos, fun)?;
+                let ptr_type = self.module.target_config().pointer_type();
+                let f = self.builder.ins().func_addr(ptr_type, callee);
+                let value = match *instr {
+                    Instr::InstanceClosure { obj, .. } => Some(self.get(obj)),
+                    _ => None,
+                };
+                self.alloc_closure(dst, f, value)?;
+            }
+            Instr::VirtualClosure { dst, obj, field } => {
+                let ptr_type = self.module.target_config().pointer_type();
+                let rt = self.builder.ins().iconst(ptr_type, self.runtime as i64);
+                let o = self.get(obj);
+                let field = self.builder.ins().iconst(types::I32, field as i64);
+                let t = self.type_ptr(dst);
+                let f = self.libcall(
+                    "brass_virtual_closure",
+                    &[ptr_type, ptr_type, types::I32, ptr_type],
+                    &[ptr_type],
+                )?;
+                let call = self.builder.ins().call(f, &[rt, o, field, t]);
+                let c = self.builder.inst_results(call)[0];
+                self.set(dst, c);
+                self.check_exception();
+            }
+            Instr::CallClosure { dst, fun, ref args } => self.call_closure(pos, dst, fun, args)?,
+            Instr::JAlways { offset } => {
+                let target = self.target(pos, offset)?;
+                self.builder.ins().jump(target, &[]);
+            }
+            Instr::JTrue { cond: reg, offset }
+            | Instr::JNotNull { reg, offset }
+            | Instr::JFalse { cond: reg, offset }
+            | Instr::JNull { reg, offset } => {
+                let target = self.target(pos, offset)?;
+                let x = self.get(reg);
+                if matches!(instr, Instr::JTrue { .. } | Instr::JNotNull { .. }) {
+                    self.builder.ins().brnz(x, target, &[]);
+                } else {
+                    self.builder.ins().brz(x, target, &[]);
+                }
+                let next = self.target(pos, 0)?;
+                self.builder.ins().jump(next, &[]);
+            }
+            Instr::JSLt { a, b, offset }
+            | Instr::JSGte { a, b, offset }
+            | Instr::JSGt { a, b, offset }
+            | Instr::JSLte { a, b, offset }
+            | Instr::JULt { a, b, offset }
+            | Instr::JUGte { a, b, offset }
+            | Instr::JNotLt { a, b, offset }
+            | Instr::JNotGte { a, b, offset }
+            | Instr::JEq { a, b, offset }
+            | Instr::JNotEq { a, b, offset } => {
+                let target = self.target(pos, offset)?;
+                let cond = self.compare(instr.op(), a, b);
+                self.builder.ins().brnz(cond, target, &[]);
+                let next = self.target(pos, 0)?;
+                self.builder.ins().jump(next, &[]);
+            }
+            Instr::Switch {
+                reg, ref targets, ..
+            } => {
+                let mut table = JumpTableData::with_capacity(targets.len());
+                for t in targets {
+                    table.push_entry(self.target(pos, *t as i32)?);
+                }
+                let table = self.builder.create_jump_table(table);
+                let x = self.get(reg);
+                // out of range values, negative ones included, fall through
+                let next = self.target(pos, 0)?;
+                self.builder.ins().br_table(x, next, table);
+            }
+            Instr::Throw { exc } | Instr::Rethrow { exc } => self.throw(exc),
+            Instr::Label {} | Instr::Nop {} => {}
+            _ => return Err(self.unsupported(pos, instr)),
+        }
+        Ok(())
+    }
+
+    fn callee(&mut self, pos: usize, fun: FunIdx) -> Result<FuncRef, VmError> {
+        let findex = fun.0 as usize;
+        if let Some(f) = self.callees.get(&findex) {
+            return Ok(*f);
+        }
+        let id = match self.functions_ptrs.get(findex) {
+            Some(Some(id)) => *id,
+            Some(None) => {
+                let n = self.code.natives.iter().find(|n| n.findex == findex);
+                return Err(VmError::Unsupported(format!(
+                    "fun@{}, op {}: native {} is not available",
+                    self.f.findex,
+                    pos,
+                    n.map_or_else(|| findex.to_string(), native_symbol)
+                )));
+            }
+            None => {
+                return Err(VmError::Invalid(vec![Diagnostic {
+                    findex: self.f.findex,
+                    op: Some(pos),
+                    message: format!("reference to unknown function {}", findex),
+                }]))
+            }
+        };
+        let f = self.module.declare_func_in_func(id, self.builder.func);
+        self.callees.insert(findex, f);
+        Ok(f)
+    }
+
+    /// Address of the runtime type of register `r`.
+    fn type_ptr(&mut self, r: Reg) -> ClValue {
+        let t = &self.code.types[self.f.regs[r.index()].index()] as *const ValueType;
+        let ptr_type = self.module.target_config().pointer_type();
+        self.builder.ins().iconst(ptr_type, t as i64)
+    }
+
+    /// Allocates a closure of the type of `dst`, bound to `value` if any.
+    fn alloc_closure(
+        &mut self,
+        dst: Reg,
+        fun: ClValue,
+        value: Option<ClValue>,
+    ) -> Result<(), VmError> {
+        let ptr_type = self.module.target_config().pointer_type();
+        let t = self.type_ptr(dst);
+        let has_value = self
+            .builder
+            .ins()
+            .iconst(types::I32, value.is_some() as i64);
+        let value = value.unwrap_or_else(|| self.builder.ins().iconst(ptr_type, 0));
+        let alloc = self.libcall(
+            "brass_alloc_closure",
+            &[ptr_type, ptr_type, types::I32, ptr_type],
+            &[ptr_type],
+        )?;
+        let call = self.builder.ins().call(alloc, &[t, fun, has_value, value]);
+        let c = self.builder.inst_results(call)[0];
+        self.set(dst, c);
+        Ok(())
+    }
+
+    /// Calls the closure in `fun`. A closure of the static type of `fun` is
+    /// called directly, with its bound value if it has one, and any other
+    /// goes through `brass_dyn_call` and the wrapper of its own type.
+    fn call_closure(
+        &mut self,
+        pos: usize,
+        dst: Reg,
+        fun: Reg,
+        args: &[Reg],
+    ) -> Result<(), VmError> {
+        if self.kind(fun) != TypeKind::HFUN {
+            return Err(VmError::Unsupported(format!(
+                "fun@{}, op {}: calling a dynamic value is not supported by the JIT",
+                self.f.findex, pos
+            )));
+        }
+        let ptr_type = self.module.target_config().pointer_type();
+        let flags = MemFlags::trusted();
+        let fun_type = self.f.regs[fun.index()];
+        let sig = signature(self.code, self.module, fun_type);
+        let ret = sig.returns.first().map(|r| r.value_type);
+        let mut bound_sig = sig.clone();
+        bound_sig.params.insert(0, AbiParam::new(ptr_type));
+
+        let c = self.get(fun);
+        let c = self.null_check(c)?;
+        let args: Vec<_> = args.iter().map(|r| self.get(*r)).collect();
+        let t = self.type_ptr(fun);
+        let bound = self.builder.create_block();
+        let unbound = self.builder.create_block();
+        let direct = self.builder.create_block();
+        let dynamic = self.builder.create_block();
+        let done = self.builder.create_block();
+        if let Some(ty) = ret {
+            self.builder.append_block_param(done, ty);
+        }
+
+        let closure_t = self.builder.ins().load(ptr_type, flags, c, 0);
+        self.builder
+            .ins()
+            .br_icmp(IntCC::NotEqual, closure_t, t, dynamic, &[]);
+        self.builder.ins().jump(direct, &[]);
+
+        self.builder.switch_to_block(direct);
+        let f = self
+            .builder
+            .ins()
+            .load(ptr_type, flags, c, offset_of!(Closure, fun) as i32);
+        let has_value =
+            self.builder
+                .ins()
+                .load(types::I32, flags, c, offset_of!(Closure, has_value) as i32);
+        self.builder.ins().brz(has_value, unbound, &[]);
+        self.builder.ins().jump(bound, &[]);
+
+        self.builder.switch_to_block(bound);
+        let value = self
+            .builder
+            .ins()
+            .load(ptr_type, flags, c, offset_of!(Closure, value) as i32);
+        let bound_args: Vec<_> = [value].into_iter().chain(args.iter().copied()).collect();
+        let sig_ref = self.builder.import_signature(bound_sig);
+        let call = self.builder.ins().call_indirect(sig_ref, f, &bound_args);
+        let results = self.builder.inst_results(call).to_vec();
+        self.builder.ins().jump(done, &results);
+
+        self.builder.switch_to_block(unbound);
+        let sig_ref = self.builder.import_signature(sig);
+        let call = self.builder.ins().call_indirect(sig_ref, f, &args);
+        let results = self.builder.inst_results(call).to_vec();
+        self.builder.ins().jump(done, &results);
+
+        self.builder.switch_to_block(dynamic);
+        let slots = self.builder.create_stack_slot(StackSlotData::new(
+            StackSlotKind::ExplicitSlot,
+            8 * args.len().max(1) as u32,
+        ));
+        for (i, a) in args.iter().enumerate() {
+            self.builder.ins().stack_store(*a, slots, 8 * i as i32);
+        }
+        let slots = self.builder.ins().stack_addr(ptr_type, slots, 0);
+        let rt = self.builder.ins().iconst(ptr_type, self.runtime as i64);
+        let dyn_call = self.libcall(
+            "brass_dyn_call",
+            &[ptr_type, ptr_type, ptr_type, ptr_type],
+            &[types::I64],
+        )?;
+        let call = self.builder.ins().call(dyn_call, &[rt, c, t, slots]);
+        let slot = self.builder.inst_results(call)[0];
+        match ret {
+            Some(ty) => {
+                let v = from_slot(&mut self.builder, slot, ty);
+                self.builder.ins().jump(done, &[v]);
+            }
+            None => {
+                self.builder.ins().jump(done, &[]);
+            }
+        }
+
+        self.builder.switch_to_block(done);
+        if let Some(v) = self.builder.block_params(done).first().copied() {
+            self.set(dst, v);
+        }
+        self.check_exception();
+        Ok(())
+    }
+
+    /// Calls the bytecode function or native `fun`, which are all declared
+    /// by the time a function is compiled.
+    fn call(&mut self, pos: usize, dst: Reg, fun: FunIdx, args: &[Reg]) -> Result<(), VmError> {
+        let callee = self.callee(pos, fun)?;
+        let args: Vec<_> = args.iter().map(|r| self.get(*r)).collect();
+        let call = self.builder.ins().call(callee, &args);
+        if let Some(v) = self.builder.inst_results(call).first().copied() {
+            self.set(dst, v);
+        }
+        self.check_exception();
+        Ok(())
+    }
+
+    /// Condition of a comparison jump. Float comparisons are ordered, except
+    /// `OJNotLt`, `OJNotGte` and `OJNotEq` which also hold when either side
+    /// is NaN.
+    fn compare(&mut self, op: Op, a: Reg, b: Reg) -> ClValue {
+        let x = self.get(a);
+        let y = self.get(b);
+        if self.is_float(a) {
+            let cc = match op {
+                Op::OJSLt => FloatCC::LessThan,
+                Op::OJSGte => FloatCC::GreaterThanOrEqual,
+                Op::OJSGt => FloatCC::GreaterThan,
+                Op::OJSLte => FloatCC::LessThanOrEqual,
+                Op::OJNotLt => FloatCC::UnorderedOrGreaterThanOrEqual,
+                Op::OJNotGte => FloatCC::UnorderedOrLessThan,
+                Op::OJEq => FloatCC::Equal,
+                _ => FloatCC::NotEqual,
+            };
+            return self.builder.ins().fcmp(cc, x, y);
+        }
+        // ui8 and ui16 compare as their 32-bit zero extension
+        let (x, y) = (self.widen(x), self.widen(y));
+        let cc = match op {
+            Op::OJSLt | Op::OJNotGte => IntCC::SignedLessThan,
+            Op::OJSGte | Op::OJNotLt => IntCC::SignedGreaterThanOrEqual,
+            Op::OJSGt => IntCC::SignedGreaterThan,
+            Op::OJSLte => IntCC::SignedLessThanOrEqual,
+            Op::OJULt => IntCC::UnsignedLessThan,
+            Op::OJUGte => IntCC::UnsignedGreaterThanOrEqual,
+            Op::OJEq => IntCC::Equal,
+            _ => IntCC::NotEqual,
+        };
+        self.builder.ins().icmp(cc, x, y)
+    }
+
+    /// Integer ops wrap, and division or modulo by zero gives 0 as with the
+    /// HashLink JIT. Float modulo follows C `fmod`.
+    fn binop(&mut self, op: Op, dst: Reg, a: Reg, b: Reg) -> Result<ClValue, VmError> {
+        let x = self.get(a);
+        let y = self.get(b);
+        if self.is_float(dst) {
+            let ty = self.builder.func.dfg.value_type(x);
+            return Ok(match op {
+                Op::OAdd => self.builder.ins().fadd(x, y),
+                Op::OSub => self.builder.ins().fsub(x, y),
+                Op::OMul => self.builder.ins().fmul(x, y),
+                Op::OSMod | Op::OUMod => {
+                    let name = if ty == types::F32 { "fmodf" } else { "fmod" };
+                    let f = self.libcall(name, &[ty, ty], &[ty])?;
+                    let call = self.builder.ins().call(f, &[x, y]);
+                    self.builder.inst_results(call)[0]
+                }
+                _ => self.builder.ins().fdiv(x, y),
+            });
+        }
+        // ui8 and ui16 are computed zero-extended to 32 bits, as in HashLink,
+        // so signed ops and shift counts see the same values
+        let ty = self.builder.func.dfg.value_type(x);
+        let (x, y) = (self.widen(x), self.widen(y));
+        let v = match op {
+            Op::OAdd => self.builder.ins().iadd(x, y),
+            Op::OSub => self.builder.ins().isub(x, y),
+            Op::OMul => self.builder.ins().imul(x, y),
+            Op::OShl => self.builder.ins().ishl(x, y),
+            Op::OSShr => self.builder.ins().sshr(x, y),
+            Op::OUShr => self.builder.ins().ushr(x, y),
+            Op::OAnd => self.builder.ins().band(x, y),
+            Op::OOr => self.builder.ins().bor(x, y),
+            Op::OXor => self.builder.ins().bxor(x, y),
+            _ => self.int_div(op, x, y),
+        };
+        Ok(if ty.bits() < 32 {
+            self.builder.ins().ireduce(ty, v)
+        } else {
+            v
+        })
+    }
+
+    /// Zero-extends integers narrower than 32 bits to `I32`.
+    fn widen(&mut self, v: ClValue) -> ClValue {
+        if self.builder.func.dfg.value_type(v).bits() < 32 {
+            self.builder.ins().uextend(types::I32, v)
+        } else {
+            v
+        }
+    }
+
+    /// Divides without trapping: by zero the result is 0, and a signed
+    /// division by -1 is a negation, so `i32::MIN / -1` wraps.
+    fn int_div(&mut self, op: Op, x: ClValue, y: ClValue) -> ClValue {
+        let ty = self.builder.func.dfg.value_type(x);
+        let zero = self.builder.ins().iconst(ty, 0);
+        let one = self.builder.ins().iconst(ty, 1);
+        let is_zero = self.builder.ins().icmp_imm(IntCC::Equal, y, 0);
+        let safe = self.builder.ins().select(is_zero, one, y);
+        let v = match op {
+            Op::OUDiv => self.builder.ins().udiv(x, safe),
+            Op::OUMod => self.builder.ins().urem(x, safe),
+            _ => {
+                let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, y, -1);
+                let safe = self.builder.ins().select(is_minus_one, one, safe);
+                if op == Op::OSDiv {
+                    let q = self.builder.ins().sdiv(x, safe);
+                    let neg = self.builder.ins().ineg(x);
+                    self.builder.ins().select(is_minus_one, neg, q)
+                } else {
+                    let r = self.builder.ins().srem(x, safe);
+                    self.builder.ins().select(is_minus_one, zero, r)
+                }
+            }
+        };
+        self.builder.ins().select(is_zero, zero, v)
+    }
+}
+
+/// Symbol of a native in its library: `hl_` for the standard library,
+/// otherwise the library name, then the native name.
+pub fn native_symbol(n: &Native) -> String {
+    let lib = n.lib.trim_start_matches('?');
+    let prefix = if lib == "std" { "hl" } else { lib };
+    format!("{}_{}", prefix, n.name)
+}
+
+// Rust's `%` on floats is C `fmod`, which libm may not be linked in for.
+extern "C" fn fmod(a: f64, b: f64) -> f64 {
+    a % b
+}
+
+extern "C" fn fmodf(a: f32, b: f32) -> f32 {
+    a % b
+}
+
+/// Signature of functions of type `t` in `code`, void returns left out.
+pub fn signature(code: &Code, module: &JITModule, t: TypeId) -> Signature {
+    let ptr_type = module.target_config().pointer_type();
+    let mut sig = module.make_signature();
+    if let ValueTypeU::FuncType { args, ret, .. } = &code.get_type(t).union {
+        for a in args {
+            sig.params
+                .push(AbiParam::new(cl_type(code.get_type(*a).kind, ptr_type)));
+        }
+        let ret = code.get_type(*ret).kind;
+        if ret != TypeKind::HVOID {
+            sig.returns.push(AbiParam::new(cl_type(ret, ptr_type)));
+        }
     }
+    sig
 }
 
-pub struct FunctionCompiler<'a> {
-    pub module: HLModule<'a>,
-    pub builder: FunctionBuilder<'a>,
+/// Widens `v` to the low bits of a dynamic call slot.
+fn to_slot(builder: &mut FunctionBuilder, v: Option<ClValue>) -> ClValue {
+    let v = match v {
+        Some(v) => v,
+        None => return builder.ins().iconst(types::I64, 0),
+    };
+    let v = match builder.func.dfg.value_type(v) {
+        types::F32 => builder.ins().bitcast(types::I32, v),
+        types::F64 => builder.ins().bitcast(types::I64, v),
+        _ => v,
+    };
+    if builder.func.dfg.value_type(v) == types::I64 {
+        v
+    } else {
+        builder.ins().uextend(types::I64, v)
+    }
+}
+
+/// Reads a value of type `ty` from the low bits of a dynamic call slot.
+fn from_slot(builder: &mut FunctionBuilder, slot: ClValue, ty: Type) -> ClValue {
+    let int = match ty {
+        types::F32 => types::I32,
+        types::F64 => types::I64,
+        ty => ty,
+    };
+    let v = if int == types::I64 {
+        slot
+    } else {
+        builder.ins().ireduce(int, slot)
+    };
+    if int == ty {
+        v
+    } else {
+        builder.ins().bitcast(ty, v)
+    }
+}
+
+/// Cranelift type of registers of `kind`. Void registers never hold a value
+/// but still get a byte, so every register has a variable.
+pub fn cl_type(kind: TypeKind, ptr_type: Type) -> Type {
+    match kind {
+        TypeKind::HVOID | TypeKind::HUI8 | TypeKind::HBOOL => types::I8,
+        TypeKind::HUI16 => types::I16,
+        TypeKind::HI32 => types::I32,
+        TypeKind::HI64 => types::I64,
+        TypeKind::HF32 => types::F32,
+        TypeKind::HF64 => types::F64,
+        _ => ptr_type,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, ptr};
+    use std::rc::Rc;
+
+    use super::{native_symbol, HLModule};
+    use crate::code::Code;
+    use crate::errors::VmError;
+    use crate::types::{ValueType, ValueTypeU};
+    use crate::vm::Value;
+
+    /// Compiles the only function of `src` and hands its address to `run`.
+    fn jit<R>(src: &str, run: impl FnOnce(*const u8) -> R) -> R {
+        let code = Code::assemble(src).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        let id = m.compile_function(0).unwrap();
+        run(m.function_ptr(id))
+    }
+
+    fn binop_i32(op: &str, a: i32, b: i32) -> i32 {
+        let src = format!(
+            "fun 0 : fun(i32, i32) -> i32
+                regs i32, i32, i32
+                {op} r2, r0, r1
+                ret r2
+            end"
+        );
+        jit(&src, |p| unsafe {
+            mem::transmute::<*const u8, extern "C" fn(i32, i32) -> i32>(p)(a, b)
+        })
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        assert_eq!(binop_i32("add", i32::MAX, 1), i32::MIN);
+        assert_eq!(binop_i32("sub", 3, 5), -2);
+        assert_eq!(binop_i32("mul", 1 << 30, 4), 0);
+        assert_eq!(binop_i32("sdiv", -7, 2), -3);
+        assert_eq!(binop_i32("sdiv", 7, 0), 0);
+        assert_eq!(binop_i32("sdiv", i32::MIN, -1), i32::MIN);
+        assert_eq!(binop_i32("smod", -7, 2), -1);
+        assert_eq!(binop_i32("smod", 7, 0), 0);
+        assert_eq!(binop_i32("smod", i32::MIN, -1), 0);
+        assert_eq!(binop_i32("udiv", -1, 2), i32::MAX);
+        assert_eq!(binop_i32("udiv", 1, 0), 0);
+        assert_eq!(binop_i32("umod", -1, 16), 15);
+        assert_eq!(binop_i32("shl", 1, 33), 2);
+        assert_eq!(binop_i32("sshr", -8, 1), -4);
+        assert_eq!(binop_i32("ushr", -8, 28), 15);
+        assert_eq!(binop_i32("and", 6, 3), 2);
+        assert_eq!(binop_i32("or", 6, 3), 7);
+        assert_eq!(binop_i32("xor", 6, 3), 5);
+    }
+
+    #[test]
+    fn narrow_integer_arithmetic() {
+        let src = |ty: &str, op: &str| {
+            format!(
+                "fun 0 : fun({ty}, {ty}) -> {ty}
+                    regs {ty}, {ty}, {ty}
+                    {op} r2, r0, r1
+                    ret r2
+                end"
+            )
+        };
+        let u8 = |op, a: u8, b: u8| {
+            jit(&src("u8", op), |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(u8, u8) -> u8>(p)(a, b)
+            })
+        };
+        assert_eq!(u8("sdiv", 200, 2), 100);
+        assert_eq!(u8("smod", 200, 7), 4);
+        assert_eq!(u8("sshr", 200, 1), 100);
+        assert_eq!(u8("shl", 200, 9), 0);
+        assert_eq!(u8("add", 200, 100), 44);
+        let u16 = |op, a: u16, b: u16| {
+            jit(&src("u16", op), |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(u16, u16) -> u16>(p)(a, b)
+            })
+        };
+        assert_eq!(u16("sdiv", 40000, 2), 20000);
+        assert_eq!(u16("smod", 40000, 7), 2);
+        assert_eq!(u16("sshr", 40000, 4), 2500);
+    }
+
+    #[test]
+    fn float_arithmetic() {
+        let src = "
+            fun 0 : fun(f64, f64) -> f64
+                regs f64, f64, f64
+                smod r2, r0, r1
+                float r0, 0.5
+                add r2, r2, r0
+                ret r2
+            end
+        ";
+        let f = |a, b| {
+            jit(src, |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(p)(a, b)
+            })
+        };
+        assert_eq!(f(7.5, 2.0), 2.0);
+        assert_eq!(f(-7.5, 2.0), -1.0);
+        assert!(f(1.0, 0.0).is_nan());
+
+        let src = "
+            fun 0 : fun(f32, f32) -> f32
+                regs f32, f32, f32
+                sdiv r2, r0, r1
+                neg r2, r2
+                ret r2
+            end
+        ";
+        let r = jit(src, |p| unsafe {
+            mem::transmute::<*const u8, extern "C" fn(f32, f32) -> f32>(p)(1.0, 4.0)
+        });
+        assert_eq!(r, -0.25);
+    }
+
+    #[test]
+    fn conversions() {
+        let src = "
+            fun 0 : fun(f64) -> i64
+                regs f64, i32, f32, i64
+                toint r1, r0
+                incr r1
+                tosfloat r2, r1
+                tosfloat r0, r2
+                toint r3, r0
+                ret r3
+            end
+        ";
+        let f = |x| {
+            jit(src, |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(f64) -> i64>(p)(x)
+            })
+        };
+        assert_eq!(f(41.9), 42);
+        assert_eq!(f(-2.5), -1);
+        assert_eq!(f(f64::NAN), i32::MIN as i64);
+        assert_eq!(f(1e20), i32::MIN as i64);
+        assert_eq!(f(-1e20), i32::MIN as i64);
+        assert_eq!(f(2147483647.5), i32::MIN as i64);
+
+        let src = "
+            fun 0 : fun(u8) -> i64
+                regs u8, i64
+                toint r1, r0
+                ret r1
+            end
+        ";
+        let r = jit(src, |p| unsafe {
+            mem::transmute::<*const u8, extern "C" fn(u8) -> i64>(p)(200)
+        });
+        assert_eq!(r, 200);
+
+        let src = "
+            fun 0 : fun(u16) -> f64
+                regs u16, f64
+                tosfloat r1, r0
+                ret r1
+            end
+        ";
+        let r = jit(src, |p| unsafe {
+            mem::transmute::<*const u8, extern "C" fn(u16) -> f64>(p)(65535)
+        });
+        assert_eq!(r, 65535.0);
+
+        let src = "
+            fun 0 : fun(i32) -> f64
+                regs i32, f64, bool
+                toufloat r1, r0
+                bool r2, true
+                not r2, r2
+                ret r1
+            end
+        ";
+        let r = jit(src, |p| unsafe {
+            mem::transmute::<*const u8, extern "C" fn(i32) -> f64>(p)(-1)
+        });
+        assert_eq!(r, u32::MAX as f64);
+    }
+
+    #[test]
+    fn loops() {
+        // sums 1..=n, counting down to a label
+        let src = "
+            fun 0 : fun(i32) -> i32
+                regs i32, i32, i32
+                int r1, 0
+                int r2, 0
+            head:
+                label
+                jsgte r2, r0, done
+                incr r2
+                add r1, r1, r2
+                jalways head
+            done:
+                ret r1
+            end
+        ";
+        let f = |n| {
+            jit(src, |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(i32) -> i32>(p)(n)
+            })
+        };
+        assert_eq!(f(10), 55);
+        assert_eq!(f(-3), 0);
+    }
+
+    #[test]
+    fn unordered_comparisons() {
+        let jump = |op: &str, a: f64, b: f64| {
+            let src = format!(
+                "fun 0 : fun(f64, f64) -> bool
+                    regs f64, f64, bool
+                    bool r2, true
+                    {op} r0, r1, taken
+                    bool r2, false
+                taken:
+                    ret r2
+                end"
+            );
+            jit(&src, |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(f64, f64) -> bool>(p)(a, b)
+            })
+        };
+        let nan = f64::NAN;
+        assert!(jump("jslt", 1.0, 2.0) && !jump("jslt", nan, 2.0));
+        assert!(jump("jsgte", 2.0, 2.0) && !jump("jsgte", 2.0, nan));
+        assert!(!jump("jeq", nan, nan) && jump("jnoteq", nan, nan));
+        assert!(jump("jnotlt", nan, 2.0) && jump("jnotlt", 3.0, 2.0));
+        assert!(!jump("jnotlt", 1.0, 2.0));
+        assert!(jump("jnotgte", 1.0, nan) && jump("jnotgte", 1.0, 2.0));
+        assert!(!jump("jnotgte", 2.0, 2.0));
+    }
+
+    #[test]
+    fn narrow_comparisons() {
+        let jump = |ty: &str, op: &str, a: u16, b: u16| {
+            let src = format!(
+                "fun 0 : fun({ty}, {ty}) -> bool
+                    regs {ty}, {ty}, bool
+                    bool r2, true
+                    {op} r0, r1, taken
+                    bool r2, false
+                taken:
+                    ret r2
+                end"
+            );
+            jit(&src, |p| unsafe {
+                if ty == "u8" {
+                    let f = mem::transmute::<*const u8, extern "C" fn(u8, u8) -> bool>(p);
+                    f(a as u8, b as u8)
+                } else {
+                    mem::transmute::<*const u8, extern "C" fn(u16, u16) -> bool>(p)(a, b)
+                }
+            })
+        };
+        assert!(!jump("u8", "jslt", 200, 100));
+        assert!(jump("u8", "jsgt", 200, 100));
+        assert!(jump("u8", "jslte", 127, 128));
+        assert!(jump("u8", "jnotlt", 255, 0));
+        assert!(!jump("u16", "jslt", 40000, 100));
+        assert!(jump("u16", "jsgte", 40000, 32768));
+    }
+
+    #[test]
+    fn switch() {
+        let src = "
+            fun 0 : fun(i32) -> i32
+                regs i32, i32
+                switch r0, [zero, one, zero], out
+                int r1, -1
+                ret r1
+            zero:
+                int r1, 10
+                ret r1
+            one:
+                int r1, 11
+            out:
+                ret r1
+            end
+        ";
+        let f = |n| {
+            jit(src, |p| unsafe {
+                mem::transmute::<*const u8, extern "C" fn(i32) -> i32>(p)(n)
+            })
+        };
+        assert_eq!([f(0), f(1), f(2), f(3), f(-1)], [10, 11, 10, -1, -1]);
+    }
+
+    #[test]
+    fn mutual_recursion() {
+        // fun 0 calls fun 1 before it is compiled, and fun 1 calls back
+        let code = Code::assemble(
+            "
+            fun 0 : fun(i32) -> bool
+                regs i32, bool, i32
+                bool r1, true
+                int r2, 0
+                jeq r0, r2, done
+                decr r0
+                call1 r1, 1, r0
+            done:
+                ret r1
+            end
+
+            fun 1 : fun(i32) -> bool
+                regs i32, bool, i32
+                bool r1, false
+                int r2, 0
+                jeq r0, r2, done
+                decr r0
+                call1 r1, 0, r0
+            done:
+                ret r1
+            end
+            ",
+        )
+        .unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        assert_eq!(m.functions_indexes, [0, 1]);
+        let even = m.compile_function(0).unwrap();
+        m.compile_function(1).unwrap();
+        let even = unsafe {
+            mem::transmute::<*const u8, extern "C" fn(i32) -> bool>(m.function_ptr(even))
+        };
+        assert!(even(10) && !even(7));
+    }
+
+    extern "C" fn test_sum5(a: i32, b: i32, c: i32, d: i32, e: i64) -> i64 {
+        (a + b + c + d) as i64 * e
+    }
+
+    #[test]
+    fn call_natives() {
+        let code = Code::assemble(
+            "
+            native test sum5 1 : fun(i32, i32, i32, i32, i64) -> i64
+
+            fun 0 : fun(i32, i64) -> i64
+                regs i32, i64, i64
+                call0 r2, 2
+                calln r2, 1, r0, r0, r0, r0, r1
+                ret r2
+            end
+
+            fun 2 : fun() -> i64
+                regs i64
+                int r0, 0
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        assert_eq!(native_symbol(&code.natives[0]), "test_sum5");
+        let code = Rc::new(code);
+        let mut m = HLModule::with_natives(code.clone(), &[("test_sum5", test_sum5 as *const u8)]);
+        m.init(false).unwrap();
+        assert_eq!(m.functions_indexes, [0, 2, 1]);
+        let id = m.compile_function(0).unwrap();
+        m.compile_function(1).unwrap();
+        let f = unsafe {
+            mem::transmute::<*const u8, extern "C" fn(i32, i64) -> i64>(m.function_ptr(id))
+        };
+        assert_eq!(f(2, -3), -24);
+    }
+
+    #[test]
+    fn missing_native() {
+        let code = Code::assemble(
+            "
+            native std sys_time 1 : fun() -> f64
+
+            fun 0 : fun() -> f64
+                regs f64
+                call0 r0, 1
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        assert!(m.functions_ptrs[1].is_none());
+        match m.compile_function(0) {
+            Err(VmError::Unsupported(message)) => assert!(message.contains("hl_sys_time")),
+            _ => panic!("the native should be missing"),
+        }
+    }
+
+    #[test]
+    fn closures() {
+        let src = "
+            fun 0 : fun(bytes, i32) -> i32
+                regs bytes, i32, fun(i32) -> i32, fun(i32) -> i32, i32
+                staticclosure r2, 1
+                callclosure r4, r2, r1
+                instanceclosure r3, 2, r0
+                callclosure r1, r3, r4
+                ret r1
+            end
+
+            fun 1 : fun(i32) -> i32
+                regs i32
+                incr r0
+                ret r0
+            end
+
+            fun 2 : fun(bytes, i32) -> i32
+                regs bytes, i32
+                jnotnull r0, bound
+                int r1, -1
+            bound:
+                ret r1
+            end
+            ";
+        let code = Code::assemble(src).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        let id = m.compile_function(0).unwrap();
+        m.compile_function(1).unwrap();
+        m.compile_function(2).unwrap();
+        let f = unsafe {
+            mem::transmute::<*const u8, extern "C" fn(*const u8, i32) -> i32>(m.function_ptr(id))
+        };
+        assert_eq!(f(src.as_ptr(), 41), 42);
+        assert_eq!(f(std::ptr::null(), 41), -1);
+    }
+
+    #[test]
+    fn dynamic_closure_call() {
+        // the closure takes (f64, i32) but is called as (i32)
+        let src = "
+            fun 0 : fun(i32) -> f64
+                regs i32, fun(f64, i32) -> f64, fun(i32) -> f64, f64
+                staticclosure r1, 1
+                mov r2, r1
+                callclosure r3, r2, r0
+                ret r3
+            end
+
+            fun 1 : fun(f64, i32) -> f64
+                regs f64, i32, f64
+                tosfloat r2, r1
+                add r0, r0, r0
+                add r2, r2, r0
+                ret r2
+            end
+            ";
+        let code = Code::assemble(src).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        let id = m.compile_function(0).unwrap();
+        m.compile_function(1).unwrap();
+        let f =
+            unsafe { mem::transmute::<*const u8, extern "C" fn(i32) -> f64>(m.function_ptr(id)) };
+        assert_eq!(f(3), 6.0);
+    }
+
+    #[test]
+    fn dynamic_call_of_method_and_untyped_closures() {
+        // fun 0 calls a closure typed as a method, fun 1 one typed dynamic
+        let src = "
+            fun 0 : fun(i32) -> f64
+                regs i32, method(i32) -> i32, fun(i32) -> f64, f64
+                staticclosure r1, 2
+                mov r2, r1
+                callclosure r3, r2, r0
+                ret r3
+            end
+
+            fun 1 : fun(i32) -> f64
+                regs i32, dynamic, fun(i32) -> f64, f64
+                staticclosure r1, 2
+                mov r2, r1
+                callclosure r3, r2, r0
+                ret r3
+            end
+
+            fun 2 : fun(i32) -> i32
+                regs i32
+                incr r0
+                ret r0
+            end
+            ";
+        let code = Code::assemble(src).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        let method = m.compile_function(0).unwrap();
+        let untyped = m.compile_function(1).unwrap();
+        m.compile_function(2).unwrap();
+        let (method, untyped) = unsafe {
+            (
+                mem::transmute::<*const u8, extern "C" fn(i32) -> f64>(m.function_ptr(method)),
+                mem::transmute::<*const u8, extern "C" fn(i32) -> f64>(m.function_ptr(untyped)),
+            )
+        };
+        assert_eq!(method(41), 42.0);
+        assert_eq!(m.take_exception(), None);
+        assert_eq!(untyped(41), 0.0);
+        assert_eq!(
+            m.take_exception().as_deref(),
+            Some("closure without a function type")
+        );
+    }
+
+    #[test]
+    fn virtual_closure() {
+        let src = "
+            type Base = obj Base { method get = 1 @0 }
+            type Point = obj Point extends Base { x: i32, method get = 2 @0 }
+            type Other = obj Other extends Base { method get = 3 @0 }
+
+            fun 0 : fun(Base) -> i32
+                regs Base, fun() -> i32, i32
+                virtualclosure r1, r0, 0
+                callclosure r2, r1
+                ret r2
+            end
+
+            fun 1 : fun(Base) -> i32
+                regs Base, i32
+                int r1, 1
+                ret r1
+            end
+
+            fun 2 : fun(Point) -> i32
+                regs Point, i32
+                int r1, 2
+                ret r1
+            end
+
+            fun 3 : fun(Other) -> i32
+                regs Other, i32
+                int r1, 3
+                ret r1
+            end
+            ";
+        let code = Code::assemble(src).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        let id = m.compile_function(0).unwrap();
+        m.compile_function(1).unwrap();
+        m.compile_function(2).unwrap();
+        let f = unsafe {
+            mem::transmute::<*const u8, extern "C" fn(*const u8) -> i32>(m.function_ptr(id))
+        };
+        // objects start with their runtime type
+        let object_of = |name: &str| {
+            let t = code.types.iter().find(|t| match &t.union {
+                ValueTypeU::ObjType { name: n, .. } => n == name,
+                _ => false,
+            });
+            [t.unwrap() as *const ValueType as usize, 0]
+        };
+        let base = object_of("Base");
+        let point = object_of("Point");
+        assert_eq!(f(base.as_ptr() as *const u8), 1);
+        assert_eq!(f(point.as_ptr() as *const u8), 2);
+        assert_eq!(m.take_exception(), None);
+
+        // fun 3 is never compiled
+        f(object_of("Other").as_ptr() as *const u8);
+        assert_eq!(
+            m.take_exception().as_deref(),
+            Some("method 0 of Other is not compiled")
+        );
+        f(ptr::null());
+        assert_eq!(m.take_exception().as_deref(), Some("Null access"));
+        // not a type of the code
+        f([8usize, 0].as_ptr() as *const u8);
+        assert_eq!(
+            m.take_exception().as_deref(),
+            Some("object of an unknown type")
+        );
+    }
+
+    #[test]
+    fn null_closure_call() {
+        let src = "
+            fun 0 : fun() -> i32
+                regs fun() -> i32, i32
+                null r0
+                callclosure r1, r0
+                int r1, 1
+                ret r1
+            end
+            ";
+        let code = Code::assemble(src).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        let id = m.compile_function(0).unwrap();
+        let f = unsafe { mem::transmute::<*const u8, extern "C" fn() -> i32>(m.function_ptr(id)) };
+        // the exception returns before `int r1, 1`
+        assert_eq!(f(), 0);
+        assert_eq!(m.take_exception().as_deref(), Some("Null access"));
+    }
+
+    #[test]
+    fn jump_out_of_function() {
+        let code = Code::assemble(
+            "
+            fun 0 : fun() -> void
+                regs void
+                jalways 5
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        assert!(matches!(m.compile_function(0), Err(VmError::Invalid(_))));
+    }
+
+    #[test]
+    fn unsupported_op() {
+        let code = Code::assemble(
+            "
+            fun 0 : fun() -> void
+                regs void, dynobj
+                new r1
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        assert!(matches!(
+            m.compile_function(0),
+            Err(VmError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn globals_of_every_kind() {
+        let code = Code::assemble(
+            "
+            type Point = obj Point { x: i32 }
+            global u8
+            global i32
+            global f64
+            global bool
+            global Point
+            global u16
+            global dynamic
+
+            fun 0 : fun() -> void
+                regs void
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(false).unwrap();
+        assert_eq!(m.globals_indexes, [0, 4, 8, 16, 24, 32, 40]);
+        assert_eq!(m.globals_size, 48);
+        assert_eq!(m.gc_roots().count(), 2);
+
+        assert_eq!(m.get_global(1).unwrap(), Value::I32(0));
+        m.set_global(1, Value::I32(-7)).unwrap();
+        m.set_global(2, Value::F64(1.5)).unwrap();
+        m.set_global(3, Value::Bool(true)).unwrap();
+        m.set_global(4, Value::Ptr(8 as *mut u8)).unwrap();
+        assert_eq!(m.get_global(1).unwrap(), Value::I32(-7));
+        assert_eq!(m.get_global(2).unwrap(), Value::F64(1.5));
+        assert_eq!(m.get_global(3).unwrap(), Value::Bool(true));
+        assert_eq!(m.get_global(0).unwrap(), Value::UI8(0));
+        let roots: Vec<_> = m.gc_roots().map(|r| unsafe { *r }).collect();
+        assert_eq!(roots, [8 as *mut u8, std::ptr::null_mut()]);
+
+        assert!(matches!(
+            m.set_global(1, Value::F64(0.0)),
+            Err(VmError::InvalidGlobal(1))
+        ));
+        assert!(matches!(m.get_global(7), Err(VmError::InvalidGlobal(7))));
+        // the globals are laid out once per module
+        assert!(matches!(m.init(false), Err(VmError::Unsupported(_))));
+    }
+
+    #[test]
+    fn example_globals() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let code = Code::from_path(path).unwrap();
+        let code = Rc::new(code);
+        let mut m = HLModule::new(code.clone());
+        m.init(true).unwrap();
+        for i in 0..code.nglobals {
+            assert!(m.get_global(i).is_ok());
+        }
+        assert!(m.globals_size >= 4096 * std::mem::size_of::<usize>());
+    }
 }
@@ -0,0 +1,241 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Iterative dataflow analyses over a `Cfg`, solved op by op.
+
+use crate::cfg::{Cfg, EdgeKind};
+
+/// A fixed-size set of small integers, such as registers or definitions.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    /// An empty set that can hold `0..len`.
+    pub fn new(len: usize) -> BitSet {
+        BitSet {
+            words: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    /// Returns whether `i` was not in the set yet.
+    pub fn insert(&mut self, i: usize) -> bool {
+        let (word, bit) = (i / 64, 1 << (i % 64));
+        let added = self.words[word] & bit == 0;
+        self.words[word] |= bit;
+        added
+    }
+
+    pub fn remove(&mut self, i: usize) {
+        self.words[i / 64] &= !(1 << (i % 64));
+    }
+
+    pub fn contains(&self, i: usize) -> bool {
+        self.words.get(i / 64).is_some_and(|w| w & (1 << (i % 64)) != 0)
+    }
+
+    /// Adds every element of `other`, returning whether the set grew.
+    pub fn union_with(&mut self, other: &BitSet) -> bool {
+        let mut changed = false;
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            changed |= *o & !*w != 0;
+            *w |= o;
+        }
+        changed
+    }
+
+    pub fn subtract(&mut self, other: &BitSet) {
+        for (w, o) in self.words.iter_mut().zip(&other.words) {
+            *w &= !o;
+        }
+    }
+
+    /// Elements in increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, w)| {
+            (0..64)
+                .filter(move |bit| w & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// A monotone analysis over the facts of a lattice.
+pub trait Analysis {
+    type Fact: Clone + PartialEq;
+    const DIRECTION: Direction;
+
+    /// Starting fact of every block, the identity of `join`.
+    fn bottom(&self) -> Self::Fact;
+
+    /// Fact before the entry, or after the exits of a backward analysis.
+    fn boundary(&self) -> Self::Fact {
+        self.bottom()
+    }
+
+    fn join(&self, into: &mut Self::Fact, other: &Self::Fact);
+
+    /// Applies the op at `pos`, in the direction of the analysis.
+    fn transfer(&self, pos: usize, fact: &mut Self::Fact);
+}
+
+/// The facts holding around every op, in program order whatever the
+/// direction of the analysis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Results<F> {
+    pub before: Vec<F>,
+    pub after: Vec<F>,
+}
+
+/// Solves `analysis` over `cfg` until it reaches a fixed point.
+///
+/// A block covered by a trap may throw at any of its ops, so its exception
+/// edges carry what holds before every op, plus what holds after the last
+/// one, which is where `OTrap` defines the exception register.
+pub fn solve<A: Analysis>(cfg: &Cfg, analysis: &A) -> Results<A::Fact> {
+    let nops = cfg.blocks.last().map_or(0, |b| b.ops.end);
+    let mut results = Results {
+        before: vec![analysis.bottom(); nops],
+        after: vec![analysis.bottom(); nops],
+    };
+
+    let mut order = cfg.reverse_postorder();
+    let mut reached = vec![false; cfg.blocks.len()];
+    for b in &order {
+        reached[*b] = true;
+    }
+    order.extend((0..cfg.blocks.len()).filter(|b| !reached[*b]));
+    if A::DIRECTION == Direction::Backward {
+        order.reverse();
+    }
+
+    // facts leaving each block along normal and exception edges, or entering
+    // it for a backward analysis
+    let mut normal = vec![analysis.bottom(); cfg.blocks.len()];
+    let mut exception = vec![analysis.bottom(); cfg.blocks.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &b in &order {
+            let (n, e) = solve_block(cfg, analysis, b, &normal, &exception, None);
+            if n != normal[b] || e != exception[b] {
+                normal[b] = n;
+                exception[b] = e;
+                changed = true;
+            }
+        }
+    }
+    for b in 0..cfg.blocks.len() {
+        solve_block(cfg, analysis, b, &normal, &exception, Some(&mut results));
+    }
+    results
+}
+
+fn solve_block<A: Analysis>(
+    cfg: &Cfg,
+    analysis: &A,
+    b: usize,
+    normal: &[A::Fact],
+    exception: &[A::Fact],
+    mut results: Option<&mut Results<A::Fact>>,
+) -> (A::Fact, A::Fact) {
+    let block = &cfg.blocks[b];
+    match A::DIRECTION {
+        Direction::Forward => {
+            let mut fact = if b == 0 {
+                analysis.boundary()
+            } else {
+                analysis.bottom()
+            };
+            for &p in &block.preds {
+                for e in cfg.blocks[p].succs.iter().filter(|e| e.target == b) {
+                    let out = match e.kind {
+                        EdgeKind::Exception => &exception[p],
+                        _ => &normal[p],
+                    };
+                    analysis.join(&mut fact, out);
+                }
+            }
+            let mut thrown = analysis.bottom();
+            for pos in block.ops.clone() {
+                analysis.join(&mut thrown, &fact);
+                if let Some(results) = results.as_deref_mut() {
+                    results.before[pos] = fact.clone();
+                }
+                analysis.transfer(pos, &mut fact);
+                if let Some(results) = results.as_deref_mut() {
+                    results.after[pos] = fact.clone();
+                }
+            }
+            analysis.join(&mut thrown, &fact);
+            (fact, thrown)
+        }
+        Direction::Backward => {
+            let mut fact = analysis.bottom();
+            let mut thrown = analysis.bottom();
+            for e in &block.succs {
+                match e.kind {
+                    EdgeKind::Exception => analysis.join(&mut thrown, &normal[e.target]),
+                    _ => analysis.join(&mut fact, &normal[e.target]),
+                }
+            }
+            if block.succs.is_empty() {
+                fact = analysis.boundary();
+            }
+            for pos in block.ops.clone().rev() {
+                analysis.join(&mut fact, &thrown);
+                if let Some(results) = results.as_deref_mut() {
+                    results.after[pos] = fact.clone();
+                }
+                analysis.transfer(pos, &mut fact);
+                // what the handler needs is live even if this op throws
+                // before writing its destination
+                analysis.join(&mut fact, &thrown);
+                if let Some(results) = results.as_deref_mut() {
+                    results.before[pos] = fact.clone();
+                }
+            }
+            (fact, thrown)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BitSet;
+
+    #[test]
+    fn bit_set() {
+        let mut s = BitSet::new(130);
+        assert!(s.insert(3));
+        assert!(!s.insert(3));
+        assert!(s.insert(129));
+        assert!(s.contains(129) && !s.contains(64) && !s.contains(500));
+        let mut t = BitSet::new(130);
+        t.insert(64);
+        assert!(s.union_with(&t));
+        assert!(!s.union_with(&t));
+        assert_eq!(s.iter().collect::<Vec<_>>(), [3, 64, 129]);
+        s.subtract(&t);
+        s.remove(3);
+        assert_eq!(s.iter().collect::<Vec<_>>(), [129]);
+    }
+}
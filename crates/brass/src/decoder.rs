@@ -1,15 +1,13 @@
 #![allow(arithmetic_overflow)]
 
-use crate::{
-    code::Code,
-    errors::{DecodeError, DecodeErrorKind},
-};
+use crate::errors::{DecodeError, DecodeErrorKind, DecodeSection};
 
 #[derive(Clone)]
 pub struct Decoder<'input> {
     pub buf: &'input [u8],
     pub file_position: usize,
-    pub code: Code,
+    /// Section currently being decoded, used to annotate errors.
+    pub section: DecodeSection,
 }
 
 impl<'input> Decoder<'input> {
@@ -17,13 +15,17 @@ impl<'input> Decoder<'input> {
         Decoder {
             buf,
             file_position: 0,
-            code: Code::new(),
+            section: DecodeSection::Header,
         }
     }
 
-    /// The position inside the file, *not* this decoder.
-    pub fn file_position(&self) -> usize {
-        self.file_position
+    /// Decodes `buf` as the part of a larger file starting at `file_position`.
+    pub fn with_position(buf: &'input [u8], file_position: usize) -> Decoder<'input> {
+        Decoder {
+            buf,
+            file_position,
+            section: DecodeSection::Header,
+        }
     }
 
     /// Advances by a specific number of bytes.
@@ -62,21 +64,50 @@ impl<'input> Decoder<'input> {
         }
         if (b & 0x40) == 0 {
             let _b:i32 = (b & 31).into();
-            let x:i32 = u8::decode(self).unwrap().into();
+            let x:i32 = u8::decode(self)?.into();
             let i:i32 =  x | (_b << 8);
             let v: i32 = i;
             return Ok(if (b & 0x20) == 0 {v}else{-v});
         }
         {
-            let c = u8::decode(self)?;
-            let d = u8::decode(self)?;
-            let e = u8::decode(self)?;
+            let c: i32 = u8::decode(self)?.into();
+            let d: i32 = u8::decode(self)?.into();
+            let e: i32 = u8::decode(self)?.into();
 
-            let i =  i32::from_le_bytes([b, c, d, e]);
-            Ok(i)
+            let v = (i32::from(b & 31) << 24) | (c << 16) | (d << 8) | e;
+            Ok(if (b & 0x20) == 0 {v}else{-v})
         }
     }
 
+    pub fn read_uindex(&mut self) -> Result<usize, DecodeError> {
+        let position = self.file_position;
+        let i = self.read_index()?;
+        if i < 0 {
+            return Err(DecodeError::with_info(DecodeErrorKind::NegativeIndex, position));
+        }
+        Ok(i as usize)
+    }
+
+    /// Checks that `count` items of at least `min_size` bytes each can still
+    /// be read, so a corrupt count fails here instead of in a huge allocation.
+    pub fn check_count(&self, count: usize, min_size: usize) -> Result<(), DecodeError> {
+        match count.checked_mul(min_size) {
+            Some(size) if size <= self.buf.len() => Ok(()),
+            _ => Err(DecodeError::with_info(
+                DecodeErrorKind::NoMoreData,
+                self.file_position,
+            )),
+        }
+    }
+
+    /// Reads a 32-bit byte length, rejecting negative values.
+    pub fn read_size(&mut self) -> Result<usize, DecodeError> {
+        let position = self.file_position;
+        let size = i32::decode(self)?;
+        usize::try_from(size)
+            .map_err(|_| DecodeError::with_info(DecodeErrorKind::NegativeIndex, position))
+    }
+
     pub fn read<T: Decode<'input>>(&mut self) -> Result<T, DecodeError> {
         T::decode(self)
     }
@@ -0,0 +1,820 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Text rendering of a `Code`, along the lines of `hl --dump`.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+
+use crate::code::Code;
+use crate::instr::{FunIdx, Instr, Reg};
+use crate::types::{HLFunction, TypeId, TypeKind, ValueTypeU};
+
+/// Nested types deeper than this are elided when printing a type.
+const MAX_TYPE_DEPTH: usize = 3;
+
+/// Short name of the types that are fully described by their kind.
+pub(crate) fn kind_name(kind: TypeKind) -> &'static str {
+    match kind {
+        TypeKind::HVOID => "void",
+        TypeKind::HUI8 => "u8",
+        TypeKind::HUI16 => "u16",
+        TypeKind::HI32 => "i32",
+        TypeKind::HI64 => "i64",
+        TypeKind::HF32 => "f32",
+        TypeKind::HF64 => "f64",
+        TypeKind::HBOOL => "bool",
+        TypeKind::HBYTES => "bytes",
+        TypeKind::HDYN => "dynamic",
+        TypeKind::HFUN => "fun",
+        TypeKind::HOBJ => "obj",
+        TypeKind::HARRAY => "array",
+        TypeKind::HTYPE => "type",
+        TypeKind::HREF => "ref",
+        TypeKind::HVIRTUAL => "virtual",
+        TypeKind::HDYNOBJ => "dynobj",
+        TypeKind::HABSTRACT => "abstract",
+        TypeKind::HENUM => "enum",
+        TypeKind::HNULL => "null",
+        TypeKind::HMETHOD => "method",
+        TypeKind::HSTRUCT => "struct",
+        TypeKind::HPACKED => "packed",
+        TypeKind::HLAST => "last",
+    }
+}
+
+impl Code {
+    /// Readable name of a type, such as `i32`, `(i32, bool) -> void` or
+    /// `null<f64>`. Classes and enums are named after their declaration.
+    pub fn type_name(&self, t: TypeId) -> String {
+        self.type_name_depth(t, 0)
+    }
+
+    fn type_name_depth(&self, t: TypeId, depth: usize) -> String {
+        let ty = match self.types.get(t.index()) {
+            Some(ty) => ty,
+            None => return format!("<bad type {}>", t.0),
+        };
+        let nested = |t: TypeId| {
+            if depth < MAX_TYPE_DEPTH {
+                self.type_name_depth(t, depth + 1)
+            } else {
+                "...".to_string()
+            }
+        };
+        match &ty.union {
+            ValueTypeU::FuncType { args, ret, .. } => {
+                let args: Vec<String> = args.iter().map(|a| nested(*a)).collect();
+                let prefix = if ty.kind == TypeKind::HMETHOD {
+                    "method:"
+                } else {
+                    ""
+                };
+                format!("{}({}) -> {}", prefix, args.join(", "), nested(*ret))
+            }
+            ValueTypeU::ObjType { name, .. } | ValueTypeU::EnumType { name, .. } => name.clone(),
+            ValueTypeU::VirtualType { fields, .. } => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|f| format!("{}: {}", f.name, nested(f.t)))
+                    .collect();
+                format!("virtual<{}>", fields.join(", "))
+            }
+            ValueTypeU::Abstract(name) => format!("abstract<{}>", name),
+            _ => match (ty.kind, ty.tparam) {
+                (TypeKind::HREF | TypeKind::HNULL | TypeKind::HPACKED, Some(p)) => {
+                    format!("{}<{}>", kind_name(ty.kind), nested(p))
+                }
+                (TypeKind::HABSTRACT, _) if ty.abs_name.is_some() => {
+                    format!("abstract<{}>", ty.abs_name.as_deref().unwrap_or_default())
+                }
+                (kind, _) => kind_name(kind).to_string(),
+            },
+        }
+    }
+
+    /// Name of every function and native by `findex`: `Class.method` for
+    /// methods and static functions, `lib@name` for natives and `fun@index`
+    /// for anonymous functions.
+    pub fn function_names(&self) -> BTreeMap<usize, String> {
+        let mut names = BTreeMap::new();
+        for f in &self.functions {
+            names.insert(f.findex, format!("fun@{}", f.findex));
+        }
+        for n in &self.natives {
+            names.insert(n.findex, format!("{}@{}", n.lib, n.name));
+        }
+        for (i, ty) in self.types.iter().enumerate() {
+            if let ValueTypeU::ObjType {
+                name,
+                proto,
+                bindings,
+                ..
+            } = &ty.union
+            {
+                for p in proto {
+                    names.insert(p.findex, format!("{}.{}", name, p.name));
+                }
+                // bindings are (field, findex) pairs; statics live on `$Class`
+                for pair in bindings.chunks_exact(2) {
+                    let field = self
+                        .type_field(TypeId(i as u32), pair[0] as usize)
+                        .map_or_else(|| format!("field{}", pair[0]), |f| f.name.clone());
+                    let class = name.strip_prefix('$').unwrap_or(name);
+                    names.insert(pair[1] as usize, format!("{}.{}", class, field));
+                }
+            }
+        }
+        names
+    }
+
+    /// Renders the whole module: header counts, constant pools, types,
+    /// globals, natives and every function.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        self.dump_to(&mut out).expect("writing to a String cannot fail");
+        out
+    }
+
+    pub fn dump_to(&self, out: &mut impl Write) -> fmt::Result {
+        let names = self.function_names();
+
+        writeln!(out, "hl v{}", self.version)?;
+        writeln!(out, "entry @{}", self.entrypoint)?;
+        writeln!(out, "{} types", self.types.len())?;
+        writeln!(out, "{} globals", self.globals.len())?;
+        writeln!(out, "{} natives", self.natives.len())?;
+        writeln!(out, "{} functions", self.functions.len())?;
+        writeln!(out, "{} constants", self.constants.len())?;
+
+        writeln!(out, "{} ints", self.ints.len())?;
+        for (i, v) in self.ints.iter().enumerate() {
+            writeln!(out, "    @{} : {}", i, v)?;
+        }
+        writeln!(out, "{} floats", self.floats.len())?;
+        for (i, v) in self.floats.iter().enumerate() {
+            writeln!(out, "    @{} : {:?}", i, v)?;
+        }
+        writeln!(out, "{} strings", self.strings.len())?;
+        for (i, v) in self.strings.iter().enumerate() {
+            writeln!(out, "    @{} : {:?}", i, v)?;
+        }
+        if self.version >= 5 {
+            writeln!(out, "{} bytes", self.bytes_pos.len())?;
+            for i in 0..self.bytes_pos.len() {
+                match self.get_bytes_constant(i) {
+                    Some(b) => writeln!(out, "    @{} : {:?}", i, b)?,
+                    None => writeln!(out, "    @{} : <bad bytes>", i)?,
+                }
+            }
+        }
+        writeln!(out, "{} files", self.debugfiles.len())?;
+        for (i, v) in self.debugfiles.iter().enumerate() {
+            writeln!(out, "    @{} : {}", i, v)?;
+        }
+
+        writeln!(out, "types:")?;
+        for i in 0..self.types.len() {
+            self.dump_type(out, TypeId(i as u32), &names)?;
+        }
+
+        writeln!(out, "globals:")?;
+        for (i, t) in self.globals.iter().enumerate() {
+            writeln!(out, "    @{} : {}", i, self.type_name(*t))?;
+        }
+
+        writeln!(out, "natives:")?;
+        for n in &self.natives {
+            writeln!(
+                out,
+                "    @{} {}@{} {}",
+                n.findex,
+                n.lib,
+                n.name,
+                self.type_name(n.t)
+            )?;
+        }
+
+        writeln!(out, "functions:")?;
+        for i in 0..self.functions.len() {
+            match self.function(i) {
+                Ok(f) => self.dump_function_to(out, f, &names)?,
+                Err(e) => writeln!(out, "fun@{}: {}", self.functions[i].findex, e)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn dump_type(
+        &self,
+        out: &mut impl Write,
+        t: TypeId,
+        names: &BTreeMap<usize, String>,
+    ) -> fmt::Result {
+        writeln!(out, "    @{} : {}", t.0, self.type_name(t))?;
+        let ty = match self.types.get(t.index()) {
+            Some(ty) => ty,
+            None => return Ok(()),
+        };
+        match &ty.union {
+            ValueTypeU::ObjType {
+                super_type,
+                fields,
+                proto,
+                ..
+            } => {
+                if let Some(s) = super_type {
+                    writeln!(out, "        extends {}", self.type_name(*s))?;
+                }
+                // inherited fields come first in the numbering
+                let first = self.type_hierarchy(t)[1..]
+                    .iter()
+                    .map(|s| match self.types.get(s.index()).map(|ty| &ty.union) {
+                        Some(ValueTypeU::ObjType { fields, .. }) => fields.len(),
+                        _ => 0,
+                    })
+                    .sum::<usize>();
+                writeln!(out, "        {} fields", fields.len())?;
+                for (i, f) in fields.iter().enumerate() {
+                    writeln!(out, "          @{} {} {}", first + i, f.name, self.type_name(f.t))?;
+                }
+                writeln!(out, "        {} methods", proto.len())?;
+                for p in proto {
+                    let name = names.get(&p.findex).map(String::as_str).unwrap_or("?");
+                    // only methods that can be overridden have a slot
+                    if p.pindex >= 0 {
+                        write!(out, "          @{} ", p.pindex)?;
+                    } else {
+                        write!(out, "          ")?;
+                    }
+                    writeln!(out, "{} {}[{}]", p.name, name, p.findex)?;
+                }
+            }
+            ValueTypeU::EnumType { constructs, .. } => {
+                for (i, c) in constructs.iter().enumerate() {
+                    let params: Vec<String> =
+                        c.params.iter().map(|p| self.type_name(*p)).collect();
+                    writeln!(out, "        @{} {}({})", i, c.name, params.join(", "))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Renders one function: its signature, register types and one line
+    /// per op with its source location.
+    pub fn dump_function(&self, f: &HLFunction) -> String {
+        let mut out = String::new();
+        self.dump_function_to(&mut out, f, &self.function_names())
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    fn dump_function_to(
+        &self,
+        out: &mut impl Write,
+        f: &HLFunction,
+        names: &BTreeMap<usize, String>,
+    ) -> fmt::Result {
+        let name = names.get(&f.findex).cloned().unwrap_or_default();
+        writeln!(out, "fun@{} {} {}", f.findex, name, self.type_name(f.t))?;
+        for (i, t) in f.regs.iter().enumerate() {
+            writeln!(out, "    r{} {}", i, self.type_name(*t))?;
+        }
+
+        let instrs = match f.instrs() {
+            Ok(instrs) => instrs,
+            Err(e) => return writeln!(out, "    {}", e),
+        };
+        let labels = jump_labels(&instrs);
+        let printer = Printer {
+            code: self,
+            f,
+            names,
+            labels: &labels,
+        };
+        for (i, instr) in instrs.iter().enumerate() {
+            if let Some(label) = labels.get(&i) {
+                writeln!(out, "  L{}:", label)?;
+            }
+            let text = printer.instr(i, instr);
+            match self.op_location(f, i) {
+                Some(loc) => writeln!(out, "    {:>4}: {:<48} ; {}:{}", i, text, loc.file, loc.line)?,
+                None => writeln!(out, "    {:>4}: {}", i, text)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Targets of all jumps, numbered in op order.
+fn jump_labels(instrs: &[Instr]) -> BTreeMap<usize, usize> {
+    let mut targets = BTreeMap::new();
+    let mut add = |pos: usize, offset: i32| {
+        let target = pos as i64 + 1 + offset as i64;
+        if target >= 0 && target as usize <= instrs.len() {
+            targets.insert(target as usize, 0);
+        }
+    };
+    for (i, instr) in instrs.iter().enumerate() {
+        match *instr {
+            Instr::Switch {
+                ref targets, end, ..
+            } => {
+                for t in targets {
+                    add(i, *t as i32);
+                }
+                add(i, end as i32);
+            }
+            _ => {
+                if let Some(offset) = jump_offset(instr) {
+                    add(i, offset);
+                }
+            }
+        }
+    }
+    for (n, label) in targets.values_mut().enumerate() {
+        *label = n;
+    }
+    targets
+}
+
+/// Relative offset of jumps and of the handler of traps.
+pub(crate) fn jump_offset(instr: &Instr) -> Option<i32> {
+    match *instr {
+        Instr::JTrue { offset, .. }
+        | Instr::JFalse { offset, .. }
+        | Instr::JNull { offset, .. }
+        | Instr::JNotNull { offset, .. }
+        | Instr::JSLt { offset, .. }
+        | Instr::JSGte { offset, .. }
+        | Instr::JSGt { offset, .. }
+        | Instr::JSLte { offset, .. }
+        | Instr::JULt { offset, .. }
+        | Instr::JUGte { offset, .. }
+        | Instr::JNotLt { offset, .. }
+        | Instr::JNotGte { offset, .. }
+        | Instr::JEq { offset, .. }
+        | Instr::JNotEq { offset, .. }
+        | Instr::JAlways { offset }
+        | Instr::Trap { offset, .. } => Some(offset),
+        _ => None,
+    }
+}
+
+struct Printer<'a> {
+    code: &'a Code,
+    f: &'a HLFunction,
+    names: &'a BTreeMap<usize, String>,
+    labels: &'a BTreeMap<usize, usize>,
+}
+
+fn args(regs: &[Reg]) -> String {
+    regs.iter()
+        .map(|r| format!("r{}", r.0))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl Printer<'_> {
+    fn fun(&self, fun: FunIdx) -> String {
+        self.names
+            .get(&fun.index())
+            .cloned()
+            .unwrap_or_else(|| format!("fun@{}", fun.0))
+    }
+
+    fn label(&self, pos: usize, offset: i32) -> String {
+        let target = pos as i64 + 1 + offset as i64;
+        match usize::try_from(target).ok().and_then(|t| self.labels.get(&t)) {
+            Some(label) => format!("L{}", label),
+            None => format!("@{}", target),
+        }
+    }
+
+    fn field(&self, obj: Reg, fid: u32) -> String {
+        self.f
+            .regs
+            .get(obj.index())
+            .and_then(|t| self.code.type_field(*t, fid as usize))
+            .map(|f| f.name.clone())
+            .unwrap_or_else(|| format!("field{}", fid))
+    }
+
+    fn method(&self, obj: Reg, fid: u32) -> String {
+        let t = self.f.regs.get(obj.index());
+        if let Some(p) = t.and_then(|t| self.code.type_method(*t, fid as usize)) {
+            return p.name.clone();
+        }
+        // virtuals call their fields
+        self.field(obj, fid)
+    }
+
+    fn construct(&self, value: Reg, construct: u32) -> String {
+        let t = self.f.regs.get(value.index());
+        match t
+            .and_then(|t| self.code.types.get(t.index()))
+            .map(|ty| &ty.union)
+        {
+            Some(ValueTypeU::EnumType { constructs, .. }) => constructs
+                .get(construct as usize)
+                .map(|c| c.name.clone())
+                .unwrap_or_else(|| format!("construct{}", construct)),
+            _ => format!("construct{}", construct),
+        }
+    }
+
+    fn string(&self, index: u32) -> String {
+        match self.code.strings.get(index as usize) {
+            Some(s) => format!("{:?}", s),
+            None => format!("string@{}", index),
+        }
+    }
+
+    fn instr(&self, pos: usize, instr: &Instr) -> String {
+        let code = self.code;
+        let jump = |cond: String, offset: i32| format!("if {} goto {}", cond, self.label(pos, offset));
+        let binop = |dst: Reg, a: Reg, op: &str, b: Reg| format!("r{} = r{} {} r{}", dst.0, a.0, op, b.0);
+        match *instr {
+            Instr::Mov { dst, src } => format!("r{} = r{}", dst.0, src.0),
+            Instr::Int { dst, index } => match code.ints.get(index as usize) {
+                Some(v) => format!("r{} = {}", dst.0, v),
+                None => format!("r{} = int@{}", dst.0, index),
+            },
+            Instr::Float { dst, index } => match code.floats.get(index as usize) {
+                Some(v) => format!("r{} = {:?}", dst.0, v),
+                None => format!("r{} = float@{}", dst.0, index),
+            },
+            Instr::Bool { dst, value } => format!("r{} = {}", dst.0, value != 0),
+            Instr::Bytes { dst, index } if code.version >= 5 => {
+                match code.get_bytes_constant(index as usize) {
+                    Some(b) => format!("r{} = bytes {:?}", dst.0, b),
+                    None => format!("r{} = bytes <bad bytes {}>", dst.0, index),
+                }
+            }
+            Instr::Bytes { dst, index } => format!("r{} = bytes {}", dst.0, self.string(index)),
+            Instr::String { dst, index } => format!("r{} = {}", dst.0, self.string(index)),
+            Instr::Null { dst } => format!("r{} = null", dst.0),
+
+            Instr::Add { dst, a, b } => binop(dst, a, "+", b),
+            Instr::Sub { dst, a, b } => binop(dst, a, "-", b),
+            Instr::Mul { dst, a, b } => binop(dst, a, "*", b),
+            Instr::SDiv { dst, a, b } => binop(dst, a, "/", b),
+            Instr::UDiv { dst, a, b } => binop(dst, a, "/u", b),
+            Instr::SMod { dst, a, b } => binop(dst, a, "%", b),
+            Instr::UMod { dst, a, b } => binop(dst, a, "%u", b),
+            Instr::Shl { dst, a, b } => binop(dst, a, "<<", b),
+            Instr::SShr { dst, a, b } => binop(dst, a, ">>", b),
+            Instr::UShr { dst, a, b } => binop(dst, a, ">>>", b),
+            Instr::And { dst, a, b } => binop(dst, a, "&", b),
+            Instr::Or { dst, a, b } => binop(dst, a, "|", b),
+            Instr::Xor { dst, a, b } => binop(dst, a, "^", b),
+            Instr::Neg { dst, src } => format!("r{} = -r{}", dst.0, src.0),
+            Instr::Not { dst, src } => format!("r{} = !r{}", dst.0, src.0),
+            Instr::Incr { dst } => format!("r{}++", dst.0),
+            Instr::Decr { dst } => format!("r{}--", dst.0),
+
+            Instr::Call0 { dst, fun } => format!("r{} = call {}()", dst.0, self.fun(fun)),
+            Instr::Call1 { dst, fun, a } => {
+                format!("r{} = call {}({})", dst.0, self.fun(fun), args(&[a]))
+            }
+            Instr::Call2 { dst, fun, a, b } => {
+                format!("r{} = call {}({})", dst.0, self.fun(fun), args(&[a, b]))
+            }
+            Instr::Call3 { dst, fun, a, b, c } => {
+                format!("r{} = call {}({})", dst.0, self.fun(fun), args(&[a, b, c]))
+            }
+            Instr::Call4 { dst, fun, a, b, c, d } => {
+                format!("r{} = call {}({})", dst.0, self.fun(fun), args(&[a, b, c, d]))
+            }
+            Instr::CallN { dst, fun, ref args } => {
+                format!("r{} = call {}({})", dst.0, self.fun(fun), self::args(args))
+            }
+            Instr::CallMethod {
+                dst,
+                field,
+                ref args,
+            } => match args.split_first() {
+                Some((obj, rest)) => format!(
+                    "r{} = call r{}.{}({})",
+                    dst.0,
+                    obj.0,
+                    self.method(*obj, field),
+                    self::args(rest)
+                ),
+                None => format!("r{} = call method{}()", dst.0, field),
+            },
+            Instr::CallThis {
+                dst,
+                field,
+                ref args,
+            } => format!(
+                "r{} = call this.{}({})",
+                dst.0,
+                self.method(Reg(0), field),
+                self::args(args)
+            ),
+            Instr::CallClosure { dst, fun, ref args } => {
+                format!("r{} = call r{}({})", dst.0, fun.0, self::args(args))
+            }
+
+            Instr::StaticClosure { dst, fun } => format!("r{} = closure {}", dst.0, self.fun(fun)),
+            Instr::InstanceClosure { dst, fun, obj } => {
+                format!("r{} = closure {}(r{})", dst.0, self.fun(fun), obj.0)
+            }
+            Instr::VirtualClosure { dst, obj, field } => {
+                format!("r{} = closure r{}.{}", dst.0, obj.0, self.method(obj, field))
+            }
+
+            Instr::GetGlobal { dst, global } => format!("r{} = global@{}", dst.0, global.0),
+            Instr::SetGlobal { global, src } => format!("global@{} = r{}", global.0, src.0),
+            Instr::Field { dst, obj, field } => {
+                format!("r{} = r{}.{}", dst.0, obj.0, self.field(obj, field))
+            }
+            Instr::SetField { obj, field, src } => {
+                format!("r{}.{} = r{}", obj.0, self.field(obj, field), src.0)
+            }
+            Instr::GetThis { dst, field } => {
+                format!("r{} = this.{}", dst.0, self.field(Reg(0), field))
+            }
+            Instr::SetThis { field, src } => {
+                format!("this.{} = r{}", self.field(Reg(0), field), src.0)
+            }
+            Instr::DynGet { dst, obj, name } => {
+                format!("r{} = r{}[{}]", dst.0, obj.0, self.string(name))
+            }
+            Instr::DynSet { obj, name, src } => {
+                format!("r{}[{}] = r{}", obj.0, self.string(name), src.0)
+            }
+
+            Instr::JTrue { cond, offset } => jump(format!("r{}", cond.0), offset),
+            Instr::JFalse { cond, offset } => jump(format!("!r{}", cond.0), offset),
+            Instr::JNull { reg, offset } => jump(format!("r{} == null", reg.0), offset),
+            Instr::JNotNull { reg, offset } => jump(format!("r{} != null", reg.0), offset),
+            Instr::JSLt { a, b, offset } => jump(format!("r{} < r{}", a.0, b.0), offset),
+            Instr::JSGte { a, b, offset } => jump(format!("r{} >= r{}", a.0, b.0), offset),
+            Instr::JSGt { a, b, offset } => jump(format!("r{} > r{}", a.0, b.0), offset),
+            Instr::JSLte { a, b, offset } => jump(format!("r{} <= r{}", a.0, b.0), offset),
+            Instr::JULt { a, b, offset } => jump(format!("r{} <u r{}", a.0, b.0), offset),
+            Instr::JUGte { a, b, offset } => jump(format!("r{} >=u r{}", a.0, b.0), offset),
+            Instr::JNotLt { a, b, offset } => jump(format!("!(r{} < r{})", a.0, b.0), offset),
+            Instr::JNotGte { a, b, offset } => jump(format!("!(r{} >= r{})", a.0, b.0), offset),
+            Instr::JEq { a, b, offset } => jump(format!("r{} == r{}", a.0, b.0), offset),
+            Instr::JNotEq { a, b, offset } => jump(format!("r{} != r{}", a.0, b.0), offset),
+            Instr::JAlways { offset } => format!("goto {}", self.label(pos, offset)),
+
+            Instr::ToDyn { dst, src } => format!("r{} = todyn r{}", dst.0, src.0),
+            Instr::ToSFloat { dst, src } => format!("r{} = tosfloat r{}", dst.0, src.0),
+            Instr::ToUFloat { dst, src } => format!("r{} = toufloat r{}", dst.0, src.0),
+            Instr::ToInt { dst, src } => format!("r{} = toint r{}", dst.0, src.0),
+            Instr::SafeCast { dst, src } => format!("r{} = safecast r{}", dst.0, src.0),
+            Instr::UnsafeCast { dst, src } => format!("r{} = unsafecast r{}", dst.0, src.0),
+            Instr::ToVirtual { dst, src } => format!("r{} = tovirtual r{}", dst.0, src.0),
+
+            Instr::Label {} => "label".to_string(),
+            Instr::Ret { reg } => format!("ret r{}", reg.0),
+            Instr::Throw { exc } => format!("throw r{}", exc.0),
+            Instr::Rethrow { exc } => format!("rethrow r{}", exc.0),
+            Instr::Switch {
+                reg,
+                ref targets,
+                end,
+            } => {
+                let targets: Vec<String> =
+                    targets.iter().map(|t| self.label(pos, *t as i32)).collect();
+                format!(
+                    "switch r{} [{}] end {}",
+                    reg.0,
+                    targets.join(", "),
+                    self.label(pos, end as i32)
+                )
+            }
+            Instr::NullCheck { reg } => format!("nullcheck r{}", reg.0),
+            Instr::Trap { exc, offset } => {
+                format!("trap r{} catch {}", exc.0, self.label(pos, offset))
+            }
+            Instr::EndTrap { .. } => "endtrap".to_string(),
+
+            Instr::GetI8 { dst, bytes, index } => {
+                format!("r{} = i8 r{}[r{}]", dst.0, bytes.0, index.0)
+            }
+            Instr::GetI16 { dst, bytes, index } => {
+                format!("r{} = i16 r{}[r{}]", dst.0, bytes.0, index.0)
+            }
+            Instr::GetMem { dst, bytes, index } => {
+                format!("r{} = mem r{}[r{}]", dst.0, bytes.0, index.0)
+            }
+            Instr::GetArray { dst, array, index } => {
+                format!("r{} = r{}[r{}]", dst.0, array.0, index.0)
+            }
+            Instr::SetI8 { bytes, index, src } => {
+                format!("i8 r{}[r{}] = r{}", bytes.0, index.0, src.0)
+            }
+            Instr::SetI16 { bytes, index, src } => {
+                format!("i16 r{}[r{}] = r{}", bytes.0, index.0, src.0)
+            }
+            Instr::SetMem { bytes, index, src } => {
+                format!("mem r{}[r{}] = r{}", bytes.0, index.0, src.0)
+            }
+            Instr::SetArray { array, index, src } => {
+                format!("r{}[r{}] = r{}", array.0, index.0, src.0)
+            }
+
+            Instr::New { dst } => {
+                let t = self.f.regs.get(dst.index());
+                let name = t.map(|t| code.type_name(*t)).unwrap_or_default();
+                format!("r{} = new {}", dst.0, name)
+            }
+            Instr::ArraySize { dst, array } => format!("r{} = arraysize r{}", dst.0, array.0),
+            Instr::Type { dst, ty } => format!("r{} = type {}", dst.0, code.type_name(ty)),
+            Instr::GetType { dst, src } => format!("r{} = typeof r{}", dst.0, src.0),
+            Instr::GetTID { dst, src } => format!("r{} = typeid r{}", dst.0, src.0),
+
+            Instr::Ref { dst, src } => format!("r{} = &r{}", dst.0, src.0),
+            Instr::Unref { dst, src } => format!("r{} = *r{}", dst.0, src.0),
+            Instr::Setref { dst, src } => format!("*r{} = r{}", dst.0, src.0),
+
+            Instr::MakeEnum {
+                dst,
+                construct,
+                ref args,
+            } => format!(
+                "r{} = {}({})",
+                dst.0,
+                self.construct(dst, construct),
+                self::args(args)
+            ),
+            Instr::EnumAlloc { dst, construct } => {
+                format!("r{} = alloc {}", dst.0, self.construct(dst, construct))
+            }
+            Instr::EnumIndex { dst, value } => format!("r{} = enumindex r{}", dst.0, value.0),
+            Instr::EnumField {
+                dst,
+                value,
+                construct,
+                field,
+            } => format!(
+                "r{} = (r{} as {}).{}",
+                dst.0,
+                value.0,
+                self.construct(value, construct),
+                field
+            ),
+            Instr::SetEnumField { value, field, src } => {
+                format!("r{}.{} = r{}", value.0, field, src.0)
+            }
+
+            Instr::Assert {} => "assert".to_string(),
+            Instr::RefData { dst, src } => format!("r{} = refdata r{}", dst.0, src.0),
+            Instr::RefOffset { dst, reg, offset } => {
+                format!("r{} = refoffset r{} + r{}", dst.0, reg.0, offset.0)
+            }
+            Instr::Nop {} => "nop".to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::Code;
+    use crate::op::{Op, Opcode};
+    use crate::types::{HLFunction, TypeId, TypeKind, ValueType, ValueTypeU};
+
+    fn example() -> Code {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let buf = std::fs::read(path).expect("Could not read hashlink binary");
+        Code::read(&buf).expect("Could not decode hashlink binary")
+    }
+
+    #[test]
+    fn dump_example() {
+        let code = example();
+        let text = code.dump();
+        assert!(text.starts_with("hl v4\nentry @394\n439 types\n"));
+        assert!(text.contains("\n    @13 : String\n        2 fields\n          @0 bytes bytes\n"));
+        assert_eq!(text.matches("\nfun@").count(), code.functions.len());
+        // one line per op, each with its source location
+        let ops: usize = code.functions.iter().map(|f| f.ops.len()).sum();
+        assert_eq!(text.lines().filter(|l| l.contains(" ; ")).count(), ops);
+    }
+
+    #[test]
+    fn symbolic_operands() {
+        let code = example();
+        let names = code.function_names();
+        let main = code
+            .functions
+            .iter()
+            .find(|f| names[&f.findex] == "Main.main")
+            .unwrap();
+        let text = code.dump_function(main);
+        assert!(text.starts_with(&format!("fun@{} Main.main () -> void\n", main.findex)));
+        assert!(text.contains("  1: r1 = r2.trace "), "{}", text);
+        assert!(text.contains(" 14: r0 = call r1(r3, r7) "), "{}", text);
+        assert!(text.contains("; Main.hx:3\n"), "{}", text);
+
+        let f = code
+            .functions
+            .iter()
+            .find(|f| names[&f.findex] == "Date.__string")
+            .unwrap();
+        let text = code.dump_function(f);
+        assert!(text.contains("  0: r2 = call Date.toString(r0) "), "{}", text);
+    }
+
+    #[test]
+    fn jump_targets_are_labelled() {
+        let code = example();
+        let names = code.function_names();
+        let f = code
+            .functions
+            .iter()
+            .find(|f| names[&f.findex] == "Std.isOfType")
+            .unwrap();
+        let text = code.dump_function(f);
+        assert!(text.contains("if r2 != null goto L0 "), "{}", text);
+        assert!(text.contains("\n  L0:\n"), "{}", text);
+        assert!(text.contains(" end L15 "), "{}", text);
+    }
+
+    #[test]
+    fn dump_bad_indexes() {
+        let op = |op, p1, p2| Opcode {
+            op,
+            p1: Some(p1),
+            p2: Some(p2),
+            ..Opcode::default()
+        };
+        let mut code = Code::new();
+        code.version = 5;
+        code.types = vec![
+            ValueType {
+                kind: TypeKind::HVOID,
+                ..ValueType::default()
+            },
+            ValueType {
+                kind: TypeKind::HOBJ,
+                union: ValueTypeU::ObjType {
+                    name: "A".to_string(),
+                    super_type: Some(TypeId(9)),
+                    fields: Vec::new(),
+                    nfields: 0,
+                    nproto: 0,
+                    nbindings: 0,
+                    proto: Vec::new(),
+                    bindings: Vec::new(),
+                    global_value: Vec::new(),
+                    rt: None,
+                },
+                ..ValueType::default()
+            },
+        ];
+        code.globals = vec![TypeId(7)];
+        code.bytes = vec![1, 2];
+        code.bytes_pos = vec![0, 10];
+        code.functions = vec![HLFunction {
+            t: TypeId(8),
+            findex: 0,
+            nregs: 2,
+            nops: 4,
+            rf: 0,
+            regs: vec![TypeId(0), TypeId(6)],
+            ops: vec![
+                op(Op::OBytes, 0, 3),
+                op(Op::OType, 0, 42),
+                op(Op::OEnumAlloc, 1, 0),
+                Opcode {
+                    op: Op::ORet,
+                    p1: Some(0),
+                    ..Opcode::default()
+                },
+            ],
+            debug: Vec::new(),
+            assigns: Vec::new(),
+            obj: None,
+            field: None,
+        }];
+
+        let text = code.dump();
+        assert!(text.contains("    @1 : <bad bytes>\n"), "{}", text);
+        assert!(text.contains("        extends <bad type 9>\n"), "{}", text);
+        assert!(text.contains("    @0 : <bad type 7>\n"), "{}", text);
+        assert!(text.contains("fun@0 fun@0 <bad type 8>\n"), "{}", text);
+        assert!(text.contains("    r1 <bad type 6>\n"), "{}", text);
+        assert!(text.contains("r0 = bytes <bad bytes 3>"), "{}", text);
+        assert!(text.contains("r0 = type <bad type 42>"), "{}", text);
+        assert!(text.contains("r1 = alloc construct0"), "{}", text);
+    }
+}
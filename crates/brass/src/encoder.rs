@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use crate::errors::{EncodeError, EncodeErrorKind};
+
+#[derive(Clone, Default)]
+pub struct Encoder {
+    pub buf: Vec<u8>,
+    /// String pool lookup used to turn names back into string indexes.
+    pub strings: HashMap<String, usize>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder::default()
+    }
+
+    /// Writes raw bytes as they are.
+    pub fn write_bytes(&mut self, buf: &[u8]) {
+        self.buf.extend_from_slice(buf);
+    }
+
+    /// Writes a variable-length index, the inverse of `Decoder::read_index`.
+    pub fn write_index(&mut self, i: i32) -> Result<(), EncodeError> {
+        let (v, sign) = if i < 0 { (i.unsigned_abs(), 0x20) } else { (i as u32, 0) };
+        if sign == 0 && v < 0x80 {
+            self.buf.push(v as u8);
+        } else if v < 0x2000 {
+            self.buf.push((v >> 8) as u8 | 0x80 | sign);
+            self.buf.push(v as u8);
+        } else if v < 0x2000_0000 {
+            self.buf.push((v >> 24) as u8 | 0xC0 | sign);
+            self.buf.push((v >> 16) as u8);
+            self.buf.push((v >> 8) as u8);
+            self.buf.push(v as u8);
+        } else {
+            return Err(EncodeError::new(EncodeErrorKind::IndexOutOfRange).with_value(i));
+        }
+        Ok(())
+    }
+
+    pub fn write_uindex(&mut self, i: usize) -> Result<(), EncodeError> {
+        let i = i32::try_from(i)
+            .map_err(|_| EncodeError::new(EncodeErrorKind::IndexOutOfRange).with_value(i as i64))?;
+        self.write_index(i)
+    }
+
+    /// Writes the pool index of `s`, which must be part of the string pool.
+    pub fn write_string(&mut self, s: &str) -> Result<(), EncodeError> {
+        let index = *self
+            .strings
+            .get(s)
+            .ok_or_else(|| EncodeError::new(EncodeErrorKind::UnknownString))?;
+        self.write_uindex(index)
+    }
+
+    pub fn write<T: Encode>(&mut self, value: T) {
+        value.encode(self)
+    }
+}
+
+pub trait Encode: Sized {
+    fn encode(self, encoder: &mut Encoder);
+}
+
+macro_rules! impl_encode {
+    ($($t:ty,)*) => {
+        $(
+            impl Encode for $t {
+                fn encode(self, encoder: &mut Encoder) {
+                    encoder.write_bytes(&self.to_le_bytes());
+                }
+            }
+        )*
+    }
+}
+
+impl_encode! {
+    u8,
+    i8,
+    u16,
+    i16,
+    u32,
+    i32,
+    u64,
+    i64,
+}
+
+impl Encode for f64 {
+    fn encode(self, encoder: &mut Encoder) {
+        encoder.write(self.to_bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Encoder;
+    use crate::decoder::Decoder;
+
+    #[test]
+    fn index_round_trip() {
+        let values = [
+            0, 1, 0x7F, 0x80, 0x1FFF, 0x2000, 0x1FFF_FFFF, -1, -0x7F, -0x1FFF, -0x2000, -0x1FFF_FFFF,
+        ];
+        let mut encoder = Encoder::new();
+        for v in values {
+            encoder.write_index(v).unwrap();
+        }
+        assert!(encoder.write_index(0x2000_0000).is_err());
+
+        let mut decoder = Decoder::new(&encoder.buf);
+        for v in values {
+            assert_eq!(decoder.read_index().unwrap(), v);
+        }
+        assert_eq!(decoder.buf.len(), 0);
+    }
+}
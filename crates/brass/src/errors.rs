@@ -1,3 +1,8 @@
+use std::fmt;
+use std::io;
+
+use crate::verify::Diagnostic;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DecodeErrorKind {
     InvalidBytecodeHeader,
@@ -13,13 +18,78 @@ pub enum DecodeErrorKind {
     InvalidString,
     NegativeIndex,
     InvalidDebugFile,
-    OutsideRange
+    OutsideRange,
+    InvalidFunctionIndex,
+    InvalidSuperType,
+    InvalidBytesOffset,
+}
+
+impl DecodeErrorKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            DecodeErrorKind::InvalidBytecodeHeader => "invalid bytecode header",
+            DecodeErrorKind::UnsupportedBytecodeVersion => "unsupported bytecode version",
+            DecodeErrorKind::NoMoreData => "unexpected end of data",
+            DecodeErrorKind::InvalidType => "invalid type kind",
+            DecodeErrorKind::InvalidTypeIndex => "invalid type index",
+            DecodeErrorKind::InvalidOpcode => "invalid opcode",
+            DecodeErrorKind::CouldNotProcessOpcode => "could not process opcode",
+            DecodeErrorKind::CouldNotReadIndex => "could not read index",
+            DecodeErrorKind::CouldNotReadStringAtIndex => "could not read string at index",
+            DecodeErrorKind::InvalidStringIndex => "invalid string index",
+            DecodeErrorKind::InvalidString => "invalid string",
+            DecodeErrorKind::NegativeIndex => "negative index",
+            DecodeErrorKind::InvalidDebugFile => "invalid debug file",
+            DecodeErrorKind::OutsideRange => "debug line run outside range",
+            DecodeErrorKind::InvalidFunctionIndex => "invalid function index",
+            DecodeErrorKind::InvalidSuperType => "invalid super type",
+            DecodeErrorKind::InvalidBytesOffset => "invalid bytes offset",
+        }
+    }
+}
+
+/// The part of the bytecode file that was being decoded when an error occurred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeSection {
+    Header,
+    Ints,
+    Floats,
+    Strings,
+    Bytes,
+    DebugFiles,
+    Types,
+    Globals,
+    Natives,
+    Functions,
+    Constants,
+}
+
+impl fmt::Display for DecodeSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DecodeSection::Header => "header",
+            DecodeSection::Ints => "ints",
+            DecodeSection::Floats => "floats",
+            DecodeSection::Strings => "strings",
+            DecodeSection::Bytes => "bytes",
+            DecodeSection::DebugFiles => "debug files",
+            DecodeSection::Types => "types",
+            DecodeSection::Globals => "globals",
+            DecodeSection::Natives => "natives",
+            DecodeSection::Functions => "functions",
+            DecodeSection::Constants => "constants",
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Copy)]
 pub struct DecodeError {
     kind: DecodeErrorKind,
     position: Option<usize>,
+    section: Option<DecodeSection>,
+    function: Option<usize>,
+    op: Option<usize>,
+    value: Option<i64>,
 }
 
 impl DecodeError {
@@ -27,6 +97,338 @@ impl DecodeError {
         DecodeError {
             kind,
             position: Some(position),
+            section: None,
+            function: None,
+            op: None,
+            value: None,
+        }
+    }
+
+    /// An error that is not tied to a position in the input.
+    pub(crate) fn new(kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            position: None,
+            ..DecodeError::with_info(kind, 0)
         }
     }
-}
\ No newline at end of file
+
+    /// Records the offending value, such as the opcode byte or the out of range index.
+    pub(crate) fn with_value(mut self, value: impl Into<i64>) -> DecodeError {
+        self.value = Some(value.into());
+        self
+    }
+
+    /// Records the section, keeping the innermost one if already set.
+    pub(crate) fn in_section(mut self, section: DecodeSection) -> DecodeError {
+        self.section.get_or_insert(section);
+        self
+    }
+
+    pub(crate) fn in_function(mut self, function: usize) -> DecodeError {
+        self.function.get_or_insert(function);
+        self
+    }
+
+    pub(crate) fn at_op(mut self, op: usize) -> DecodeError {
+        self.op.get_or_insert(op);
+        self
+    }
+
+    pub fn kind(&self) -> DecodeErrorKind {
+        self.kind
+    }
+
+    /// Byte offset in the input where the offending value starts.
+    pub fn position(&self) -> Option<usize> {
+        self.position
+    }
+
+    pub fn section(&self) -> Option<DecodeSection> {
+        self.section
+    }
+
+    /// Index of the function being decoded, in file order.
+    pub fn function(&self) -> Option<usize> {
+        self.function
+    }
+
+    /// Index of the op being decoded inside `function`.
+    pub fn op(&self) -> Option<usize> {
+        self.op
+    }
+
+    pub fn value(&self) -> Option<i64> {
+        self.value
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.function, self.section) {
+            (Some(function), _) => {
+                write!(f, "function #{}", function)?;
+                if let Some(op) = self.op {
+                    write!(f, ", op {}", op)?;
+                }
+                f.write_str(": ")?;
+            }
+            (None, Some(section)) => write!(f, "{}: ", section)?,
+            (None, None) => {}
+        }
+
+        f.write_str(self.kind.describe())?;
+        match (self.kind, self.value) {
+            (DecodeErrorKind::InvalidOpcode, Some(value)) => write!(f, " 0x{:02X}", value)?,
+            (_, Some(value)) => write!(f, " {}", value)?,
+            (_, None) => {}
+        }
+        if let Some(position) = self.position {
+            write!(f, " at byte 0x{:X}", position)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeErrorKind {
+    /// A value does not fit the variable-length index encoding.
+    IndexOutOfRange,
+    /// A type reference does not point into `Code::types`.
+    UnknownType,
+    /// A name is not part of the string pool.
+    UnknownString,
+    /// An opcode is missing operands required by its layout.
+    MissingOperand,
+}
+
+#[derive(Debug, Clone, PartialEq, Copy)]
+pub struct EncodeError {
+    kind: EncodeErrorKind,
+    value: Option<i64>,
+}
+
+impl EncodeError {
+    pub(crate) fn new(kind: EncodeErrorKind) -> EncodeError {
+        EncodeError { kind, value: None }
+    }
+
+    pub(crate) fn with_value(mut self, value: impl Into<i64>) -> EncodeError {
+        self.value = Some(value.into());
+        self
+    }
+
+    pub fn kind(&self) -> EncodeErrorKind {
+        self.kind
+    }
+
+    pub fn value(&self) -> Option<i64> {
+        self.value
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self.kind {
+            EncodeErrorKind::IndexOutOfRange => "index out of range",
+            EncodeErrorKind::UnknownType => "type is not part of the type table",
+            EncodeErrorKind::UnknownString => "string is not part of the string pool",
+            EncodeErrorKind::MissingOperand => "missing operand for opcode",
+        })?;
+        if let Some(value) = self.value {
+            write!(f, " {}", value)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsmErrorKind {
+    UnexpectedToken,
+    UnexpectedEndOfLine,
+    UnterminatedString,
+    /// A string, unlike a bytes literal, must be valid UTF-8.
+    InvalidString,
+    InvalidNumber,
+    UnknownDirective,
+    UnknownMnemonic,
+    UnknownType,
+    DuplicateType,
+    UnknownLabel,
+    DuplicateLabel,
+    /// An op has more or fewer operands than its layout.
+    OperandCount,
+    /// A `fun` block is missing its `end`, or an op appears outside of one.
+    OutsideFunction,
+}
+
+impl AsmErrorKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            AsmErrorKind::UnexpectedToken => "unexpected token",
+            AsmErrorKind::UnexpectedEndOfLine => "unexpected end of line",
+            AsmErrorKind::UnterminatedString => "unterminated string",
+            AsmErrorKind::InvalidString => "invalid UTF-8 in string",
+            AsmErrorKind::InvalidNumber => "invalid number",
+            AsmErrorKind::UnknownDirective => "unknown directive",
+            AsmErrorKind::UnknownMnemonic => "unknown mnemonic",
+            AsmErrorKind::UnknownType => "unknown type",
+            AsmErrorKind::DuplicateType => "duplicate type",
+            AsmErrorKind::UnknownLabel => "unknown label",
+            AsmErrorKind::DuplicateLabel => "duplicate label",
+            AsmErrorKind::OperandCount => "wrong number of operands for",
+            AsmErrorKind::OutsideFunction => "unbalanced function block at",
+        }
+    }
+}
+
+/// An error in assembly source, tied to its 1-based line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    kind: AsmErrorKind,
+    line: usize,
+    token: Option<String>,
+}
+
+impl AsmError {
+    pub(crate) fn new(kind: AsmErrorKind, line: usize) -> AsmError {
+        AsmError {
+            kind,
+            line,
+            token: None,
+        }
+    }
+
+    /// Records the offending token.
+    pub(crate) fn with_token(mut self, token: impl Into<String>) -> AsmError {
+        self.token = Some(token.into());
+        self
+    }
+
+    pub fn kind(&self) -> AsmErrorKind {
+        self.kind
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.kind.describe())?;
+        if let Some(token) = &self.token {
+            write!(f, " `{}`", token)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// An error reading a bytecode file from disk.
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Decode(DecodeError),
+}
+
+impl From<DecodeError> for LoadError {
+    fn from(e: DecodeError) -> LoadError {
+        LoadError::Decode(e)
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => e.fmt(f),
+            LoadError::Decode(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Decode(e) => Some(e),
+        }
+    }
+}
+
+/// An error loading or running a module in a `Vm`.
+#[derive(Debug)]
+pub enum VmError {
+    Decode(DecodeError),
+    /// The bytecode failed verification.
+    Invalid(Vec<Diagnostic>),
+    /// `Code::entrypoint` is not the findex of a bytecode function.
+    NoEntrypoint(usize),
+    /// A global index out of range, or a value not matching the global's type.
+    InvalidGlobal(usize),
+    /// A Haxe exception escaped the entrypoint, rendered as a string.
+    Uncaught(String),
+    /// The module is valid but uses something the VM cannot execute yet.
+    Unsupported(String),
+}
+
+impl From<DecodeError> for VmError {
+    fn from(e: DecodeError) -> VmError {
+        VmError::Decode(e)
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Decode(e) => e.fmt(f),
+            VmError::Invalid(diagnostics) => match diagnostics.first() {
+                Some(d) if diagnostics.len() > 1 => {
+                    write!(f, "{} (and {} more)", d, diagnostics.len() - 1)
+                }
+                Some(d) => d.fmt(f),
+                None => f.write_str("invalid bytecode"),
+            },
+            VmError::NoEntrypoint(findex) => {
+                write!(f, "entrypoint fun@{} is not a function", findex)
+            }
+            VmError::InvalidGlobal(index) => write!(f, "invalid access to global {}", index),
+            VmError::Uncaught(message) => write!(f, "uncaught exception: {}", message),
+            VmError::Unsupported(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeError, DecodeErrorKind, DecodeSection};
+
+    #[test]
+    fn display_includes_context() {
+        let e = DecodeError::with_info(DecodeErrorKind::InvalidOpcode, 0x1F20)
+            .with_value(0xA3)
+            .at_op(17)
+            .in_function(42)
+            .in_section(DecodeSection::Functions);
+        assert_eq!(
+            e.to_string(),
+            "function #42, op 17: invalid opcode 0xA3 at byte 0x1F20"
+        );
+
+        let e = DecodeError::with_info(DecodeErrorKind::InvalidTypeIndex, 0x40)
+            .with_value(-3)
+            .in_section(DecodeSection::Globals)
+            .in_section(DecodeSection::Header);
+        assert_eq!(e.to_string(), "globals: invalid type index -3 at byte 0x40");
+    }
+}
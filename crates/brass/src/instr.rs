@@ -0,0 +1,510 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::decoder::{Decode, Decoder};
+use crate::errors::{DecodeError, DecodeErrorKind};
+use crate::op::{Op, Opcode};
+use crate::types::TypeId;
+
+/// A register of the current function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Reg(pub u32);
+
+/// A function or native, by `findex`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FunIdx(pub u32);
+
+/// Index in `Code::globals`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct GlobalIdx(pub u32);
+
+impl Reg {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl FunIdx {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl GlobalIdx {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A value that can appear as an encoded instruction operand.
+pub trait Operand: Copy {
+    fn from_raw(raw: i32) -> Option<Self>;
+    fn to_raw(self) -> i32;
+}
+
+impl Operand for i32 {
+    fn from_raw(raw: i32) -> Option<Self> {
+        Some(raw)
+    }
+
+    fn to_raw(self) -> i32 {
+        self
+    }
+}
+
+impl Operand for u8 {
+    fn from_raw(raw: i32) -> Option<Self> {
+        u8::try_from(raw).ok()
+    }
+
+    fn to_raw(self) -> i32 {
+        self.into()
+    }
+}
+
+impl Operand for u32 {
+    fn from_raw(raw: i32) -> Option<Self> {
+        u32::try_from(raw).ok()
+    }
+
+    fn to_raw(self) -> i32 {
+        self as i32
+    }
+}
+
+macro_rules! impl_operand {
+    ($($t:ident,)*) => {
+        $(
+            impl Operand for $t {
+                fn from_raw(raw: i32) -> Option<Self> {
+                    u32::from_raw(raw).map($t)
+                }
+
+                fn to_raw(self) -> i32 {
+                    self.0.to_raw()
+                }
+            }
+        )*
+    }
+}
+
+impl_operand! {
+    Reg,
+    FunIdx,
+    GlobalIdx,
+    TypeId,
+}
+
+/// Supplies the operands of one instruction in the order they are encoded.
+trait OperandSource {
+    fn next<T: Operand>(&mut self) -> Result<T, DecodeError>;
+
+    /// Argument count of the variable length calls and `OMakeEnum`.
+    fn nargs(&mut self) -> Result<usize, DecodeError>;
+}
+
+impl OperandSource for Decoder<'_> {
+    fn next<T: Operand>(&mut self) -> Result<T, DecodeError> {
+        let position = self.file_position;
+        let raw = self.read_index()?;
+        T::from_raw(raw).ok_or_else(|| {
+            DecodeError::with_info(DecodeErrorKind::NegativeIndex, position).with_value(raw)
+        })
+    }
+
+    fn nargs(&mut self) -> Result<usize, DecodeError> {
+        Ok(u8::decode(self)?.into())
+    }
+}
+
+/// The fields of an `Opcode`, in encoding order.
+struct OpcodeOperands {
+    op: Op,
+    raw: Vec<Option<i32>>,
+    next: usize,
+}
+
+impl OpcodeOperands {
+    fn new(o: &Opcode) -> Self {
+        let extra = o.extra.iter().map(|e| i32::try_from(*e).ok());
+        let raw = match o.op {
+            // the case count is p2 and the default target p3, after the cases
+            Op::OSwitch => [o.p1, o.p2]
+                .into_iter()
+                .chain(extra)
+                .chain([o.p3])
+                .collect(),
+            _ => [o.p1, o.p2, o.p3].into_iter().chain(extra).collect(),
+        };
+        OpcodeOperands { op: o.op, raw, next: 0 }
+    }
+
+    /// Fails if operands are left that the instruction does not use.
+    fn finish(&self) -> Result<(), DecodeError> {
+        if self.raw[self.next.min(self.raw.len())..].iter().any(Option::is_some) {
+            return Err(DecodeError::new(DecodeErrorKind::CouldNotProcessOpcode)
+                .with_value(self.op as u8));
+        }
+        Ok(())
+    }
+}
+
+impl OperandSource for OpcodeOperands {
+    fn next<T: Operand>(&mut self) -> Result<T, DecodeError> {
+        let raw = self.raw.get(self.next).copied().flatten().ok_or_else(|| {
+            DecodeError::new(DecodeErrorKind::CouldNotProcessOpcode).with_value(self.op as u8)
+        })?;
+        self.next += 1;
+        T::from_raw(raw)
+            .ok_or_else(|| DecodeError::new(DecodeErrorKind::NegativeIndex).with_value(raw))
+    }
+
+    fn nargs(&mut self) -> Result<usize, DecodeError> {
+        Ok(self.next::<u8>()?.into())
+    }
+}
+
+macro_rules! instrs {
+    ($($op:ident => $name:ident { $($field:ident: $t:ty),* },)*) => {
+        /// One instruction with named, typed operands. Jump offsets are relative
+        /// to the next op.
+        #[derive(Clone, Debug, PartialEq)]
+        pub enum Instr {
+            $($name { $($field: $t),* },)*
+            CallN { dst: Reg, fun: FunIdx, args: Vec<Reg> },
+            /// The object is the first of `args`.
+            CallMethod { dst: Reg, field: u32, args: Vec<Reg> },
+            CallThis { dst: Reg, field: u32, args: Vec<Reg> },
+            CallClosure { dst: Reg, fun: Reg, args: Vec<Reg> },
+            MakeEnum { dst: Reg, construct: u32, args: Vec<Reg> },
+            Switch { reg: Reg, targets: Vec<u32>, end: u32 },
+        }
+
+        impl Instr {
+            pub fn op(&self) -> Op {
+                match self {
+                    $(Instr::$name { .. } => Op::$op,)*
+                    Instr::CallN { .. } => Op::OCallN,
+                    Instr::CallMethod { .. } => Op::OCallMethod,
+                    Instr::CallThis { .. } => Op::OCallThis,
+                    Instr::CallClosure { .. } => Op::OCallClosure,
+                    Instr::MakeEnum { .. } => Op::OMakeEnum,
+                    Instr::Switch { .. } => Op::OSwitch,
+                }
+            }
+
+            fn read_operands<S: OperandSource>(op: Op, src: &mut S) -> Result<Instr, DecodeError> {
+                // struct fields are evaluated in the order written, which is
+                // the encoding order
+                Ok(match op {
+                    $(Op::$op => Instr::$name { $($field: src.next()?),* },)*
+                    Op::OCallN => Instr::CallN {
+                        dst: src.next()?,
+                        fun: src.next()?,
+                        args: read_args(src)?,
+                    },
+                    Op::OCallMethod => Instr::CallMethod {
+                        dst: src.next()?,
+                        field: src.next()?,
+                        args: read_args(src)?,
+                    },
+                    Op::OCallThis => Instr::CallThis {
+                        dst: src.next()?,
+                        field: src.next()?,
+                        args: read_args(src)?,
+                    },
+                    Op::OCallClosure => Instr::CallClosure {
+                        dst: src.next()?,
+                        fun: src.next()?,
+                        args: read_args(src)?,
+                    },
+                    Op::OMakeEnum => Instr::MakeEnum {
+                        dst: src.next()?,
+                        construct: src.next()?,
+                        args: read_args(src)?,
+                    },
+                    Op::OSwitch => {
+                        let reg = src.next()?;
+                        let count: u32 = src.next()?;
+                        Instr::Switch {
+                            reg,
+                            targets: (0..count).map(|_| src.next()).collect::<Result<_, _>>()?,
+                            end: src.next()?,
+                        }
+                    }
+                    Op::OLast => {
+                        return Err(DecodeError::new(DecodeErrorKind::InvalidOpcode)
+                            .with_value(op as u8))
+                    }
+                })
+            }
+
+            /// Operands in the `p1`, `p2`, `p3`, `extra` order of `Opcode`.
+            fn raw_operands(&self) -> Vec<i32> {
+                match self {
+                    $(Instr::$name { $($field),* } => vec![$($field.to_raw()),*],)*
+                    Instr::CallN { dst, fun, args } => call_operands(*dst, fun.to_raw(), args),
+                    Instr::CallMethod { dst, field, args }
+                    | Instr::CallThis { dst, field, args } => {
+                        call_operands(*dst, field.to_raw(), args)
+                    }
+                    Instr::CallClosure { dst, fun, args } => call_operands(*dst, fun.to_raw(), args),
+                    Instr::MakeEnum { dst, construct, args } => {
+                        call_operands(*dst, construct.to_raw(), args)
+                    }
+                    Instr::Switch { reg, targets, end } => [reg.to_raw(), targets.len() as i32, end.to_raw()]
+                        .into_iter()
+                        .chain(targets.iter().map(|t| t.to_raw()))
+                        .collect(),
+                }
+            }
+        }
+    };
+}
+
+fn read_args<S: OperandSource>(src: &mut S) -> Result<Vec<Reg>, DecodeError> {
+    let nargs = src.nargs()?;
+    (0..nargs).map(|_| src.next()).collect()
+}
+
+fn call_operands(dst: Reg, callee: i32, args: &[Reg]) -> Vec<i32> {
+    [dst.to_raw(), callee, args.len() as i32]
+        .into_iter()
+        .chain(args.iter().map(|a| a.to_raw()))
+        .collect()
+}
+
+instrs! {
+    OMov => Mov { dst: Reg, src: Reg },
+    OInt => Int { dst: Reg, index: u32 },
+    OFloat => Float { dst: Reg, index: u32 },
+    OBool => Bool { dst: Reg, value: i32 },
+    OBytes => Bytes { dst: Reg, index: u32 },
+    OString => String { dst: Reg, index: u32 },
+    ONull => Null { dst: Reg },
+
+    OAdd => Add { dst: Reg, a: Reg, b: Reg },
+    OSub => Sub { dst: Reg, a: Reg, b: Reg },
+    OMul => Mul { dst: Reg, a: Reg, b: Reg },
+    OSDiv => SDiv { dst: Reg, a: Reg, b: Reg },
+    OUDiv => UDiv { dst: Reg, a: Reg, b: Reg },
+    OSMod => SMod { dst: Reg, a: Reg, b: Reg },
+    OUMod => UMod { dst: Reg, a: Reg, b: Reg },
+    OShl => Shl { dst: Reg, a: Reg, b: Reg },
+    OSShr => SShr { dst: Reg, a: Reg, b: Reg },
+    OUShr => UShr { dst: Reg, a: Reg, b: Reg },
+    OAnd => And { dst: Reg, a: Reg, b: Reg },
+    OOr => Or { dst: Reg, a: Reg, b: Reg },
+    OXor => Xor { dst: Reg, a: Reg, b: Reg },
+
+    ONeg => Neg { dst: Reg, src: Reg },
+    ONot => Not { dst: Reg, src: Reg },
+    OIncr => Incr { dst: Reg },
+    ODecr => Decr { dst: Reg },
+
+    OCall0 => Call0 { dst: Reg, fun: FunIdx },
+    OCall1 => Call1 { dst: Reg, fun: FunIdx, a: Reg },
+    OCall2 => Call2 { dst: Reg, fun: FunIdx, a: Reg, b: Reg },
+    OCall3 => Call3 { dst: Reg, fun: FunIdx, a: Reg, b: Reg, c: Reg },
+    OCall4 => Call4 { dst: Reg, fun: FunIdx, a: Reg, b: Reg, c: Reg, d: Reg },
+
+    OStaticClosure => StaticClosure { dst: Reg, fun: FunIdx },
+    OInstanceClosure => InstanceClosure { dst: Reg, fun: FunIdx, obj: Reg },
+    OVirtualClosure => VirtualClosure { dst: Reg, obj: Reg, field: u32 },
+
+    OGetGlobal => GetGlobal { dst: Reg, global: GlobalIdx },
+    OSetGlobal => SetGlobal { global: GlobalIdx, src: Reg },
+    OField => Field { dst: Reg, obj: Reg, field: u32 },
+    OSetField => SetField { obj: Reg, field: u32, src: Reg },
+    OGetThis => GetThis { dst: Reg, field: u32 },
+    OSetThis => SetThis { field: u32, src: Reg },
+    ODynGet => DynGet { dst: Reg, obj: Reg, name: u32 },
+    ODynSet => DynSet { obj: Reg, name: u32, src: Reg },
+
+    OJTrue => JTrue { cond: Reg, offset: i32 },
+    OJFalse => JFalse { cond: Reg, offset: i32 },
+    OJNull => JNull { reg: Reg, offset: i32 },
+    OJNotNull => JNotNull { reg: Reg, offset: i32 },
+    OJSLt => JSLt { a: Reg, b: Reg, offset: i32 },
+    OJSGte => JSGte { a: Reg, b: Reg, offset: i32 },
+    OJSGt => JSGt { a: Reg, b: Reg, offset: i32 },
+    OJSLte => JSLte { a: Reg, b: Reg, offset: i32 },
+    OJULt => JULt { a: Reg, b: Reg, offset: i32 },
+    OJUGte => JUGte { a: Reg, b: Reg, offset: i32 },
+    OJNotLt => JNotLt { a: Reg, b: Reg, offset: i32 },
+    OJNotGte => JNotGte { a: Reg, b: Reg, offset: i32 },
+    OJEq => JEq { a: Reg, b: Reg, offset: i32 },
+    OJNotEq => JNotEq { a: Reg, b: Reg, offset: i32 },
+    OJAlways => JAlways { offset: i32 },
+
+    OToDyn => ToDyn { dst: Reg, src: Reg },
+    OToSFloat => ToSFloat { dst: Reg, src: Reg },
+    OToUFloat => ToUFloat { dst: Reg, src: Reg },
+    OToInt => ToInt { dst: Reg, src: Reg },
+    OSafeCast => SafeCast { dst: Reg, src: Reg },
+    OUnsafeCast => UnsafeCast { dst: Reg, src: Reg },
+    OToVirtual => ToVirtual { dst: Reg, src: Reg },
+
+    OLabel => Label {},
+    ORet => Ret { reg: Reg },
+    OThrow => Throw { exc: Reg },
+    ORethrow => Rethrow { exc: Reg },
+    ONullCheck => NullCheck { reg: Reg },
+    OTrap => Trap { exc: Reg, offset: i32 },
+    OEndTrap => EndTrap { flag: i32 },
+
+    OGetI8 => GetI8 { dst: Reg, bytes: Reg, index: Reg },
+    OGetI16 => GetI16 { dst: Reg, bytes: Reg, index: Reg },
+    OGetMem => GetMem { dst: Reg, bytes: Reg, index: Reg },
+    OGetArray => GetArray { dst: Reg, array: Reg, index: Reg },
+    OSetI8 => SetI8 { bytes: Reg, index: Reg, src: Reg },
+    OSetI16 => SetI16 { bytes: Reg, index: Reg, src: Reg },
+    OSetMem => SetMem { bytes: Reg, index: Reg, src: Reg },
+    OSetArray => SetArray { array: Reg, index: Reg, src: Reg },
+
+    ONew => New { dst: Reg },
+    OArraySize => ArraySize { dst: Reg, array: Reg },
+    OType => Type { dst: Reg, ty: TypeId },
+    OGetType => GetType { dst: Reg, src: Reg },
+    OGetTID => GetTID { dst: Reg, src: Reg },
+
+    ORef => Ref { dst: Reg, src: Reg },
+    OUnref => Unref { dst: Reg, src: Reg },
+    OSetref => Setref { dst: Reg, src: Reg },
+
+    OEnumAlloc => EnumAlloc { dst: Reg, construct: u32 },
+    OEnumIndex => EnumIndex { dst: Reg, value: Reg },
+    OEnumField => EnumField { dst: Reg, value: Reg, construct: u32, field: u32 },
+    OSetEnumField => SetEnumField { value: Reg, field: u32, src: Reg },
+
+    OAssert => Assert {},
+    ORefData => RefData { dst: Reg, src: Reg },
+    ORefOffset => RefOffset { dst: Reg, reg: Reg, offset: Reg },
+    ONop => Nop {},
+}
+
+impl Instr {
+    /// Decodes one instruction straight from the bytecode, without going
+    /// through `Opcode`, and advances `buf` past it. Error positions are
+    /// relative to the start of `buf`.
+    pub fn read(buf: &mut &[u8]) -> Result<Instr, DecodeError> {
+        let mut decoder = Decoder::new(buf);
+        let n = u8::decode(&mut decoder)?;
+        let op = Op::try_from(n)
+            .ok()
+            .filter(|op| *op != Op::OLast)
+            .ok_or_else(|| {
+                DecodeError::with_info(DecodeErrorKind::InvalidOpcode, 0).with_value(n)
+            })?;
+        let instr = Instr::read_operands(op, &mut decoder)?;
+        *buf = decoder.buf;
+        Ok(instr)
+    }
+}
+
+impl TryFrom<&Opcode> for Instr {
+    type Error = DecodeError;
+
+    /// Fails when operands are missing, out of range or left over.
+    fn try_from(o: &Opcode) -> Result<Self, Self::Error> {
+        let mut src = OpcodeOperands::new(o);
+        let instr = Instr::read_operands(o.op, &mut src)?;
+        src.finish()?;
+        Ok(instr)
+    }
+}
+
+impl From<&Instr> for Opcode {
+    fn from(instr: &Instr) -> Self {
+        let raw = instr.raw_operands();
+        Opcode {
+            op: instr.op(),
+            p1: raw.first().copied(),
+            p2: raw.get(1).copied(),
+            p3: raw.get(2).copied(),
+            extra: raw.iter().skip(3).map(|e| *e as isize).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FunIdx, Instr, Reg};
+    use crate::code::Code;
+    use crate::encoder::Encoder;
+    use crate::op::{Op, Opcode};
+
+    #[test]
+    fn opcode_round_trip() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let buf = std::fs::read(path).expect("Could not read hashlink binary");
+        let code = Code::read(&buf).expect("Could not decode hashlink binary");
+
+        for f in &code.functions {
+            let mut encoder = Encoder::new();
+            for o in &f.ops {
+                Code::write_opcode(&mut encoder, o).unwrap();
+            }
+            let mut buf = &encoder.buf[..];
+            for o in &f.ops {
+                let instr = Instr::try_from(o).unwrap();
+                assert_eq!(Opcode::from(&instr), *o);
+                assert_eq!(Instr::read(&mut buf).unwrap(), instr);
+            }
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn typed_operands() {
+        let call = Opcode {
+            op: Op::OCall2,
+            p1: Some(0),
+            p2: Some(12),
+            p3: Some(1),
+            extra: vec![2],
+        };
+        assert_eq!(
+            Instr::try_from(&call).unwrap(),
+            Instr::Call2 {
+                dst: Reg(0),
+                fun: FunIdx(12),
+                a: Reg(1),
+                b: Reg(2)
+            }
+        );
+
+        let switch = Instr::Switch {
+            reg: Reg(3),
+            targets: vec![4, 9],
+            end: 12,
+        };
+        let o = Opcode::from(&switch);
+        assert_eq!((o.p1, o.p2, o.p3, &o.extra[..]), (Some(3), Some(2), Some(12), &[4, 9][..]));
+        assert_eq!(Instr::try_from(&o).unwrap(), switch);
+
+        let missing = Opcode {
+            extra: Vec::new(),
+            ..call.clone()
+        };
+        assert!(Instr::try_from(&missing).is_err());
+        let negative = Opcode { p1: Some(-1), ..call.clone() };
+        assert!(Instr::try_from(&negative).is_err());
+        let left_over = Opcode {
+            extra: vec![2, 3],
+            ..call
+        };
+        assert!(Instr::try_from(&left_over).is_err());
+    }
+}
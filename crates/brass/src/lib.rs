@@ -12,6 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! A HashLink virtual machine.
+//!
+//! Load a module with `Code::read` or `Code::from_path`, inspect its types,
+//! functions and natives through the accessors of `Code`, and run it with
+//! a `Vm`, which compiles it to native code on the first run:
+//!
+//! ```no_run
+//! use brass::{Code, Vm};
+//!
+//! let code = Code::from_path("main.hl")?;
+//! let mut vm = Vm::new(code)?;
+//! vm.run(&[])?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
 // extern crate stickyimmix;
 
 extern crate custom_derive;
@@ -21,11 +36,38 @@ extern crate cranelift;
 extern crate cranelift_jit;
 
 mod op;
+mod instr;
 mod types;
 mod decoder;
+mod encoder;
 mod compiler;
+mod runtime;
 mod errors;
 mod code;
 mod code_hash;
 mod native;
+mod verify;
+mod disasm;
+mod asm;
+mod vm;
+mod cfg;
+mod dataflow;
+mod liveness;
 
+pub use cfg::{BasicBlock, Cfg, Edge, EdgeKind, Loop};
+pub use code::{Code, SourceLocation};
+pub use code_hash::CodeHash;
+pub use errors::{
+    AsmError, AsmErrorKind, DecodeError, DecodeErrorKind, DecodeSection, EncodeError,
+    EncodeErrorKind, LoadError, VmError,
+};
+pub use instr::{FunIdx, GlobalIdx, Instr, Reg};
+pub use liveness::{Def, Liveness, ReachingDefs};
+pub use native::Native;
+pub use op::Op;
+pub use types::{
+    Constant, EnumConstruct, HLFunction, ObjField, ObjProto, TypeId, TypeKind, ValueType,
+    ValueTypeU, VarAssign,
+};
+pub use verify::{verify, verify_function, Diagnostic};
+pub use vm::{Value, Vm};
@@ -0,0 +1,627 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Register liveness, reaching definitions and def-use chains.
+//!
+//! A register passed to `ORef` can be read and written through the
+//! reference at any later call or `OSetref`, so such registers are reported
+//! by `is_address_taken` and kept live everywhere.
+
+use crate::cfg::Cfg;
+use crate::dataflow::{solve, Analysis, BitSet, Direction};
+use crate::errors::DecodeError;
+use crate::instr::{Instr, Reg};
+use crate::types::HLFunction;
+
+impl Instr {
+    /// Registers read by this instruction, in operand order.
+    pub fn uses(&self) -> Vec<Reg> {
+        match *self {
+            Instr::Mov { src, .. }
+            | Instr::Neg { src, .. }
+            | Instr::Not { src, .. }
+            | Instr::SetGlobal { src, .. }
+            | Instr::ToDyn { src, .. }
+            | Instr::ToSFloat { src, .. }
+            | Instr::ToUFloat { src, .. }
+            | Instr::ToInt { src, .. }
+            | Instr::SafeCast { src, .. }
+            | Instr::UnsafeCast { src, .. }
+            | Instr::ToVirtual { src, .. }
+            | Instr::GetType { src, .. }
+            | Instr::GetTID { src, .. }
+            | Instr::Ref { src, .. }
+            | Instr::Unref { src, .. }
+            | Instr::RefData { src, .. } => vec![src],
+
+            Instr::Int { .. }
+            | Instr::Float { .. }
+            | Instr::Bool { .. }
+            | Instr::Bytes { .. }
+            | Instr::String { .. }
+            | Instr::Null { .. }
+            | Instr::Call0 { .. }
+            | Instr::StaticClosure { .. }
+            | Instr::GetGlobal { .. }
+            | Instr::JAlways { .. }
+            | Instr::Label {}
+            | Instr::Trap { .. }
+            | Instr::EndTrap { .. }
+            | Instr::New { .. }
+            | Instr::Type { .. }
+            | Instr::EnumAlloc { .. }
+            | Instr::Assert {}
+            | Instr::Nop {} => Vec::new(),
+
+            Instr::Add { a, b, .. }
+            | Instr::Sub { a, b, .. }
+            | Instr::Mul { a, b, .. }
+            | Instr::SDiv { a, b, .. }
+            | Instr::UDiv { a, b, .. }
+            | Instr::SMod { a, b, .. }
+            | Instr::UMod { a, b, .. }
+            | Instr::Shl { a, b, .. }
+            | Instr::SShr { a, b, .. }
+            | Instr::UShr { a, b, .. }
+            | Instr::And { a, b, .. }
+            | Instr::Or { a, b, .. }
+            | Instr::Xor { a, b, .. }
+            | Instr::Call2 { a, b, .. }
+            | Instr::JSLt { a, b, .. }
+            | Instr::JSGte { a, b, .. }
+            | Instr::JSGt { a, b, .. }
+            | Instr::JSLte { a, b, .. }
+            | Instr::JULt { a, b, .. }
+            | Instr::JUGte { a, b, .. }
+            | Instr::JNotLt { a, b, .. }
+            | Instr::JNotGte { a, b, .. }
+            | Instr::JEq { a, b, .. }
+            | Instr::JNotEq { a, b, .. } => vec![a, b],
+
+            Instr::Incr { dst } | Instr::Decr { dst } => vec![dst],
+            Instr::Call1 { a, .. } => vec![a],
+            Instr::Call3 { a, b, c, .. } => vec![a, b, c],
+            Instr::Call4 { a, b, c, d, .. } => vec![a, b, c, d],
+            Instr::CallN { ref args, .. }
+            | Instr::CallMethod { ref args, .. }
+            | Instr::MakeEnum { ref args, .. } => args.clone(),
+            Instr::CallThis { ref args, .. } => [Reg(0)].into_iter().chain(args.clone()).collect(),
+            Instr::CallClosure { fun, ref args, .. } => {
+                [fun].into_iter().chain(args.clone()).collect()
+            }
+
+            Instr::InstanceClosure { obj, .. }
+            | Instr::VirtualClosure { obj, .. }
+            | Instr::Field { obj, .. }
+            | Instr::DynGet { obj, .. } => vec![obj],
+            Instr::SetField { obj, src, .. } | Instr::DynSet { obj, src, .. } => vec![obj, src],
+            Instr::GetThis { .. } => vec![Reg(0)],
+            Instr::SetThis { src, .. } => vec![Reg(0), src],
+
+            Instr::JTrue { cond, .. } | Instr::JFalse { cond, .. } => vec![cond],
+            Instr::JNull { reg, .. }
+            | Instr::JNotNull { reg, .. }
+            | Instr::Ret { reg }
+            | Instr::Switch { reg, .. }
+            | Instr::NullCheck { reg } => vec![reg],
+            Instr::Throw { exc } | Instr::Rethrow { exc } => vec![exc],
+
+            Instr::GetI8 { bytes, index, .. }
+            | Instr::GetI16 { bytes, index, .. }
+            | Instr::GetMem { bytes, index, .. } => vec![bytes, index],
+            Instr::GetArray { array, index, .. } => vec![array, index],
+            Instr::SetI8 { bytes, index, src }
+            | Instr::SetI16 { bytes, index, src }
+            | Instr::SetMem { bytes, index, src } => vec![bytes, index, src],
+            Instr::SetArray { array, index, src } => vec![array, index, src],
+
+            Instr::ArraySize { array, .. } => vec![array],
+            // writes through the reference in `dst`
+            Instr::Setref { dst, src } => vec![dst, src],
+            Instr::EnumIndex { value, .. } | Instr::EnumField { value, .. } => vec![value],
+            Instr::SetEnumField { value, src, .. } => vec![value, src],
+            Instr::RefOffset { reg, offset, .. } => vec![reg, offset],
+        }
+    }
+
+    /// The register written by this instruction. `OTrap` writes its register
+    /// when an exception is caught.
+    pub fn def(&self) -> Option<Reg> {
+        match *self {
+            Instr::Mov { dst, .. }
+            | Instr::Int { dst, .. }
+            | Instr::Float { dst, .. }
+            | Instr::Bool { dst, .. }
+            | Instr::Bytes { dst, .. }
+            | Instr::String { dst, .. }
+            | Instr::Null { dst }
+            | Instr::Add { dst, .. }
+            | Instr::Sub { dst, .. }
+            | Instr::Mul { dst, .. }
+            | Instr::SDiv { dst, .. }
+            | Instr::UDiv { dst, .. }
+            | Instr::SMod { dst, .. }
+            | Instr::UMod { dst, .. }
+            | Instr::Shl { dst, .. }
+            | Instr::SShr { dst, .. }
+            | Instr::UShr { dst, .. }
+            | Instr::And { dst, .. }
+            | Instr::Or { dst, .. }
+            | Instr::Xor { dst, .. }
+            | Instr::Neg { dst, .. }
+            | Instr::Not { dst, .. }
+            | Instr::Incr { dst }
+            | Instr::Decr { dst }
+            | Instr::Call0 { dst, .. }
+            | Instr::Call1 { dst, .. }
+            | Instr::Call2 { dst, .. }
+            | Instr::Call3 { dst, .. }
+            | Instr::Call4 { dst, .. }
+            | Instr::CallN { dst, .. }
+            | Instr::CallMethod { dst, .. }
+            | Instr::CallThis { dst, .. }
+            | Instr::CallClosure { dst, .. }
+            | Instr::StaticClosure { dst, .. }
+            | Instr::InstanceClosure { dst, .. }
+            | Instr::VirtualClosure { dst, .. }
+            | Instr::GetGlobal { dst, .. }
+            | Instr::Field { dst, .. }
+            | Instr::GetThis { dst, .. }
+            | Instr::DynGet { dst, .. }
+            | Instr::ToDyn { dst, .. }
+            | Instr::ToSFloat { dst, .. }
+            | Instr::ToUFloat { dst, .. }
+            | Instr::ToInt { dst, .. }
+            | Instr::SafeCast { dst, .. }
+            | Instr::UnsafeCast { dst, .. }
+            | Instr::ToVirtual { dst, .. }
+            | Instr::GetI8 { dst, .. }
+            | Instr::GetI16 { dst, .. }
+            | Instr::GetMem { dst, .. }
+            | Instr::GetArray { dst, .. }
+            | Instr::New { dst }
+            | Instr::ArraySize { dst, .. }
+            | Instr::Type { dst, .. }
+            | Instr::GetType { dst, .. }
+            | Instr::GetTID { dst, .. }
+            | Instr::Ref { dst, .. }
+            | Instr::Unref { dst, .. }
+            | Instr::MakeEnum { dst, .. }
+            | Instr::EnumAlloc { dst, .. }
+            | Instr::EnumIndex { dst, .. }
+            | Instr::EnumField { dst, .. }
+            | Instr::RefData { dst, .. }
+            | Instr::RefOffset { dst, .. } => Some(dst),
+            Instr::Trap { exc, .. } => Some(exc),
+
+            Instr::SetGlobal { .. }
+            | Instr::SetField { .. }
+            | Instr::SetThis { .. }
+            | Instr::DynSet { .. }
+            | Instr::JTrue { .. }
+            | Instr::JFalse { .. }
+            | Instr::JNull { .. }
+            | Instr::JNotNull { .. }
+            | Instr::JSLt { .. }
+            | Instr::JSGte { .. }
+            | Instr::JSGt { .. }
+            | Instr::JSLte { .. }
+            | Instr::JULt { .. }
+            | Instr::JUGte { .. }
+            | Instr::JNotLt { .. }
+            | Instr::JNotGte { .. }
+            | Instr::JEq { .. }
+            | Instr::JNotEq { .. }
+            | Instr::JAlways { .. }
+            | Instr::Label {}
+            | Instr::Ret { .. }
+            | Instr::Throw { .. }
+            | Instr::Rethrow { .. }
+            | Instr::Switch { .. }
+            | Instr::NullCheck { .. }
+            | Instr::EndTrap { .. }
+            | Instr::SetI8 { .. }
+            | Instr::SetI16 { .. }
+            | Instr::SetMem { .. }
+            | Instr::SetArray { .. }
+            | Instr::Setref { .. }
+            | Instr::SetEnumField { .. }
+            | Instr::Assert {}
+            | Instr::Nop {} => None,
+        }
+    }
+}
+
+/// Registers passed to `ORef`.
+fn address_taken(instrs: &[Instr], nregs: usize) -> BitSet {
+    let mut regs = BitSet::new(nregs);
+    for instr in instrs {
+        match *instr {
+            Instr::Ref { src, .. } if src.index() < nregs => {
+                regs.insert(src.index());
+            }
+            _ => {}
+        }
+    }
+    regs
+}
+
+/// Registers live before and after every op.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Liveness {
+    pub(crate) live_in: Vec<BitSet>,
+    pub(crate) live_out: Vec<BitSet>,
+    pub(crate) address_taken: BitSet,
+}
+
+struct LiveRegs<'a> {
+    instrs: &'a [Instr],
+    nregs: usize,
+    pinned: &'a BitSet,
+}
+
+impl Analysis for LiveRegs<'_> {
+    type Fact = BitSet;
+    const DIRECTION: Direction = Direction::Backward;
+
+    fn bottom(&self) -> BitSet {
+        self.pinned.clone()
+    }
+
+    fn join(&self, into: &mut BitSet, other: &BitSet) {
+        into.union_with(other);
+    }
+
+    fn transfer(&self, pos: usize, live: &mut BitSet) {
+        let instr = &self.instrs[pos];
+        // out of range registers are reported by the verifier, not tracked
+        if let Some(dst) = instr.def().filter(|r| r.index() < self.nregs) {
+            if !self.pinned.contains(dst.index()) {
+                live.remove(dst.index());
+            }
+        }
+        for r in instr.uses().into_iter().filter(|r| r.index() < self.nregs) {
+            live.insert(r.index());
+        }
+    }
+}
+
+impl Liveness {
+    pub fn new(cfg: &Cfg, instrs: &[Instr], nregs: usize) -> Liveness {
+        let pinned = address_taken(instrs, nregs);
+        let results = solve(
+            cfg,
+            &LiveRegs {
+                instrs,
+                nregs,
+                pinned: &pinned,
+            },
+        );
+        Liveness {
+            live_in: results.before,
+            live_out: results.after,
+            address_taken: pinned,
+        }
+    }
+
+    /// Registers live before the op at `pos`, in register order.
+    pub fn live_in(&self, pos: usize) -> impl Iterator<Item = Reg> + '_ {
+        self.live_in[pos].iter().map(|r| Reg(r as u32))
+    }
+
+    /// Registers live after the op at `pos`, in register order.
+    pub fn live_out(&self, pos: usize) -> impl Iterator<Item = Reg> + '_ {
+        self.live_out[pos].iter().map(|r| Reg(r as u32))
+    }
+
+    /// Whether `reg` is passed to `ORef`, which keeps it live everywhere.
+    pub fn is_address_taken(&self, reg: Reg) -> bool {
+        self.address_taken.contains(reg.index())
+    }
+
+    /// Whether the value written by the op at `pos` is never read, making it
+    /// a dead store unless the op has other effects.
+    pub fn is_dead_store(&self, instrs: &[Instr], pos: usize) -> bool {
+        match instrs[pos].def() {
+            Some(dst) => !self.live_out[pos].contains(dst.index()),
+            None => false,
+        }
+    }
+}
+
+/// A write to a register, either by an op or by the caller for the values
+/// registers hold on entry, such as arguments.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Def {
+    pub reg: Reg,
+    /// `None` for the value on entry.
+    pub op: Option<usize>,
+}
+
+/// Definitions reaching every op, with the def-use chains they imply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReachingDefs {
+    /// Every definition: first the entry value of each register, in register
+    /// order, then the ops that write a register, in op order.
+    pub defs: Vec<Def>,
+    /// Indices in `defs` of the definitions reaching each op.
+    pub(crate) reaching: Vec<BitSet>,
+    /// Ops reading each definition, in op order.
+    pub uses: Vec<Vec<usize>>,
+    pub(crate) address_taken: BitSet,
+}
+
+struct Reaching<'a> {
+    defs_of_op: &'a [Option<usize>],
+    defs_of_reg: &'a [BitSet],
+    instrs: &'a [Instr],
+    ndefs: usize,
+    nregs: usize,
+}
+
+impl Analysis for Reaching<'_> {
+    type Fact = BitSet;
+    const DIRECTION: Direction = Direction::Forward;
+
+    fn bottom(&self) -> BitSet {
+        BitSet::new(self.ndefs)
+    }
+
+    fn boundary(&self) -> BitSet {
+        let mut entry = BitSet::new(self.ndefs);
+        for r in 0..self.nregs {
+            entry.insert(r);
+        }
+        entry
+    }
+
+    fn join(&self, into: &mut BitSet, other: &BitSet) {
+        into.union_with(other);
+    }
+
+    fn transfer(&self, pos: usize, reaching: &mut BitSet) {
+        if let (Some(d), Some(dst)) = (self.defs_of_op[pos], self.instrs[pos].def()) {
+            reaching.subtract(&self.defs_of_reg[dst.index()]);
+            reaching.insert(d);
+        }
+    }
+}
+
+impl ReachingDefs {
+    pub fn new(cfg: &Cfg, instrs: &[Instr], nregs: usize) -> ReachingDefs {
+        let mut defs: Vec<Def> = (0..nregs)
+            .map(|r| Def {
+                reg: Reg(r as u32),
+                op: None,
+            })
+            .collect();
+        let mut defs_of_op = vec![None; instrs.len()];
+        for (pos, instr) in instrs.iter().enumerate() {
+            if let Some(reg) = instr.def().filter(|r| r.index() < nregs) {
+                defs_of_op[pos] = Some(defs.len());
+                defs.push(Def { reg, op: Some(pos) });
+            }
+        }
+        let mut defs_of_reg = vec![BitSet::new(defs.len()); nregs];
+        for (i, d) in defs.iter().enumerate() {
+            defs_of_reg[d.reg.index()].insert(i);
+        }
+
+        let reaching = solve(
+            cfg,
+            &Reaching {
+                defs_of_op: &defs_of_op,
+                defs_of_reg: &defs_of_reg,
+                instrs,
+                ndefs: defs.len(),
+                nregs,
+            },
+        )
+        .before;
+
+        let mut uses = vec![Vec::new(); defs.len()];
+        for (pos, instr) in instrs.iter().enumerate() {
+            for r in instr.uses().into_iter().filter(|r| r.index() < nregs) {
+                for d in reaching[pos].iter() {
+                    if defs[d].reg == r && uses[d].last() != Some(&pos) {
+                        uses[d].push(pos);
+                    }
+                }
+            }
+        }
+
+        ReachingDefs {
+            defs,
+            reaching,
+            uses,
+            address_taken: address_taken(instrs, nregs),
+        }
+    }
+
+    /// Indices in `defs` of the definitions reaching the op at `pos`.
+    pub fn reaching(&self, pos: usize) -> impl Iterator<Item = usize> + '_ {
+        self.reaching[pos].iter()
+    }
+
+    /// Whether `reg` is passed to `ORef`, so that any call or `OSetref` may
+    /// also define it.
+    pub fn is_address_taken(&self, reg: Reg) -> bool {
+        self.address_taken.contains(reg.index())
+    }
+
+    /// Definitions of `reg` that the op at `pos` may read.
+    pub fn defs_of(&self, pos: usize, reg: Reg) -> Vec<Def> {
+        self.reaching[pos]
+            .iter()
+            .map(|d| self.defs[d])
+            .filter(|d| d.reg == reg)
+            .collect()
+    }
+}
+
+impl HLFunction {
+    /// Live registers around every op of this function.
+    pub fn liveness(&self) -> Result<Liveness, DecodeError> {
+        let instrs = self.instrs()?;
+        Ok(Liveness::new(&Cfg::new(&instrs), &instrs, self.regs.len()))
+    }
+
+    /// Reaching definitions and def-use chains of this function.
+    pub fn reaching_defs(&self) -> Result<ReachingDefs, DecodeError> {
+        let instrs = self.instrs()?;
+        Ok(ReachingDefs::new(&Cfg::new(&instrs), &instrs, self.regs.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Def;
+    use crate::code::Code;
+    use crate::instr::Reg;
+
+    const LOOP: &str = "
+        fun 0 : fun(i32) -> i32
+            regs i32, i32, i32
+            int r1, 0
+            int r2, 1
+        top:
+            label
+            jsgte r1, r0, out
+            incr r1
+            jalways top
+        out:
+            ret r1
+        end
+    ";
+
+    fn set(regs: impl Iterator<Item = Reg>) -> Vec<u32> {
+        regs.map(|r| r.0).collect()
+    }
+
+    #[test]
+    fn liveness_through_loop() {
+        let code = Code::assemble(LOOP).unwrap();
+        let f = &code.functions[0];
+        let live = f.liveness().unwrap();
+        assert_eq!(set(live.live_in(0)), [0]);
+        // r2 is never read
+        assert!(live.is_dead_store(&f.instrs().unwrap(), 1));
+        assert_eq!(set(live.live_in(3)), [0, 1]);
+        // r0 stays live around the back edge
+        assert_eq!(set(live.live_out(5)), [0, 1]);
+        assert_eq!(set(live.live_in(6)), [1]);
+    }
+
+    #[test]
+    fn out_of_range_registers_are_ignored() {
+        let code = Code::assemble(
+            "
+            fun 0 : fun(i32) -> i32
+                regs i32
+                mov r65, r0
+                ref r1, r70
+                ret r65
+            end
+            ",
+        )
+        .unwrap();
+        let f = &code.functions[0];
+        let live = f.liveness().unwrap();
+        assert_eq!(set(live.live_in(0)), [0]);
+        assert_eq!(live.live_out(0).count(), 0);
+        assert!(!live.is_address_taken(Reg(70)));
+        let rd = f.reaching_defs().unwrap();
+        assert_eq!(rd.defs.len(), 1);
+    }
+
+    #[test]
+    fn def_use_chains() {
+        let code = Code::assemble(LOOP).unwrap();
+        let f = &code.functions[0];
+        let rd = f.reaching_defs().unwrap();
+        let init = Def {
+            reg: Reg(1),
+            op: Some(0),
+        };
+        let incr = Def {
+            reg: Reg(1),
+            op: Some(4),
+        };
+        assert_eq!(rd.defs_of(3, Reg(1)), [init, incr]);
+        assert_eq!(rd.defs_of(6, Reg(1)), [init, incr]);
+        assert_eq!(
+            rd.defs_of(3, Reg(0)),
+            [Def {
+                reg: Reg(0),
+                op: None
+            }]
+        );
+        let d = rd.defs.iter().position(|d| *d == init).unwrap();
+        assert_eq!(rd.uses[d], [3, 4, 6]);
+        let d = rd.defs.iter().position(|d| d.op == Some(1)).unwrap();
+        assert!(rd.uses[d].is_empty());
+    }
+
+    #[test]
+    fn trap_handler_keeps_registers_live() {
+        let code = Code::assemble(
+            "
+            fun 0 : fun(i32) -> i32
+                regs i32, dynamic, i32
+                trap r1, catch
+                int r2, 0
+                call0 r0, 0
+                endtrap 0
+                ret r2
+            catch:
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let f = &code.functions[0];
+        let live = f.liveness().unwrap();
+        // the call may throw before writing r0, which the handler returns
+        assert!(live.live_in(2).any(|r| r == Reg(0)));
+        assert!(!live.live_in(0).any(|r| r == Reg(1)));
+
+        let rd = f.reaching_defs().unwrap();
+        let defs = rd.defs_of(5, Reg(0));
+        assert!(defs.contains(&Def {
+            reg: Reg(0),
+            op: None
+        }));
+        assert!(defs.contains(&Def {
+            reg: Reg(0),
+            op: Some(2)
+        }));
+    }
+
+    #[test]
+    fn example_functions() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let code = Code::from_path(path).unwrap();
+        for f in &code.functions {
+            let live = f.liveness().unwrap();
+            assert_eq!(live.live_in.len(), f.ops.len());
+            let rd = f.reaching_defs().unwrap();
+            // every register read has a definition reaching it
+            for (pos, instr) in f.instrs().unwrap().iter().enumerate() {
+                for r in instr.uses() {
+                    assert!(!rd.defs_of(pos, r).is_empty());
+                }
+            }
+        }
+    }
+}
@@ -1,2 +1,169 @@
 // cli entry
-fn main() {}
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::process;
+
+use brass::{verify, Code, CodeHash, DecodeError, Diagnostic, Vm, VmError};
+
+/// An uncaught Haxe exception ended the program, as with `hl`.
+const EXIT_UNCAUGHT: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+/// The file could not be read, decoded or verified.
+const EXIT_INVALID: i32 = 3;
+/// The module is valid but uses something the VM cannot execute yet.
+const EXIT_UNSUPPORTED: i32 = 4;
+
+/// Bytes shown on each side of a decode error position.
+const CONTEXT_BYTES: usize = 16;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: brass run <file.hl> [args...]\n       \
+         brass dump <file.hl>\n       \
+         brass verify <file.hl>\n       \
+         brass hash <file.hl>"
+    );
+    process::exit(EXIT_USAGE);
+}
+
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(EXIT_INVALID);
+    })
+}
+
+/// Prints a decode error followed by the function it happened in, when
+/// known, and a hex dump of the bytes around its position.
+fn report_decode_error(path: &str, buf: &[u8], code: Option<&Code>, e: &DecodeError) {
+    eprintln!("{}: {}", path, e);
+    if let (Some(code), Some(i)) = (code, e.function()) {
+        if let Some(f) = code.functions().get(i) {
+            let names = code.function_names();
+            eprintln!("  in {}", names[&f.findex]);
+        }
+    }
+    let position = match e.position() {
+        Some(position) if position < buf.len() => position,
+        _ => return,
+    };
+    let start = position.saturating_sub(CONTEXT_BYTES) & !0xF;
+    let end = (position + CONTEXT_BYTES + 1).min(buf.len());
+    for row in (start..end).step_by(16) {
+        let bytes = &buf[row..(row + 16).min(end)];
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        eprintln!("  {:08x}  {}", row, hex.join(" "));
+        if (row..row + 16).contains(&position) {
+            eprintln!("  {:8}  {}^^", "", "   ".repeat(position - row));
+        }
+    }
+}
+
+/// Decodes every function of `path`, exiting on the first error.
+fn load(path: &str) -> Code {
+    let buf = read_file(path);
+    let mut code = Code::read_lazy(&buf).unwrap_or_else(|e| {
+        report_decode_error(path, &buf, None, &e);
+        process::exit(EXIT_INVALID);
+    });
+    if let Err(e) = code.load_functions() {
+        report_decode_error(path, &buf, Some(&code), &e);
+        process::exit(EXIT_INVALID);
+    }
+    code
+}
+
+fn dump(path: &str) {
+    let buf = read_file(path);
+    let code = Code::read_lazy(&buf).unwrap_or_else(|e| {
+        report_decode_error(path, &buf, None, &e);
+        process::exit(EXIT_INVALID);
+    });
+    print!("{}", code.dump());
+}
+
+fn report_diagnostics(path: &str, names: &BTreeMap<usize, String>, diagnostics: &[Diagnostic]) {
+    for d in diagnostics {
+        let name = names.get(&d.findex).map_or("?", String::as_str);
+        eprintln!("{}: {} ({})", path, d, name);
+    }
+}
+
+fn verify_file(path: &str) {
+    let code = load(path);
+    let diagnostics = verify(&code);
+    if !diagnostics.is_empty() {
+        report_diagnostics(path, &code.function_names(), &diagnostics);
+        process::exit(EXIT_INVALID);
+    }
+    println!("{}: ok", path);
+}
+
+/// Prints the signature and body hash of every function, then the signature
+/// of every native, as computed for hot reloading.
+fn hash(path: &str) {
+    let code = load(path);
+    let names = code.function_names();
+    let mut code_hash = CodeHash::alloc(&code);
+    code_hash.finalize();
+    for (i, f) in code.functions().iter().enumerate() {
+        println!(
+            "{:>6} {:08x} {:08x} {}",
+            f.findex, code_hash.functions_signs[i], code_hash.functions_hashes[i], names[&f.findex]
+        );
+    }
+    for (i, n) in code.natives().iter().enumerate() {
+        println!(
+            "{:>6} {:08x} {:8} {}",
+            n.findex,
+            code_hash.functions_signs[code.functions().len() + i],
+            "",
+            names[&n.findex]
+        );
+    }
+}
+
+fn run(path: &str, args: &[&str]) -> i32 {
+    let code = load(path);
+    let names = code.function_names();
+    let mut vm = match Vm::new(code) {
+        Ok(vm) => vm,
+        Err(VmError::Invalid(diagnostics)) => {
+            report_diagnostics(path, &names, &diagnostics);
+            return EXIT_INVALID;
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return EXIT_INVALID;
+        }
+    };
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    match vm.run(&args) {
+        Ok(()) => 0,
+        Err(VmError::Uncaught(message)) => {
+            eprintln!("Uncaught exception: {}", message);
+            EXIT_UNCAUGHT
+        }
+        Err(e @ VmError::Unsupported(_)) => {
+            eprintln!("{}: {}", path, e);
+            EXIT_UNSUPPORTED
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            EXIT_INVALID
+        }
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["run", path, ref rest @ ..] => process::exit(run(path, rest)),
+        ["dump", path] => dump(path),
+        ["verify", path] => verify_file(path),
+        ["hash", path] => hash(path),
+        _ => usage(),
+    }
+}
@@ -1,10 +1,10 @@
-use crate::types::ValueType;
+use crate::types::TypeId;
 
 #[derive(Clone)]
 #[derive(Debug)]
 pub struct Native {
     pub lib:String,
     pub name:String,
-    pub t:ValueType,
+    pub t:TypeId,
     pub findex:usize
 }
\ No newline at end of file
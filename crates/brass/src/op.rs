@@ -12,7 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use num_enum::{IntoPrimitive, TryFromPrimitive};
+use num_enum::TryFromPrimitive;
 use strum_macros::IntoStaticStr;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive, IntoStaticStr)]
@@ -151,8 +151,8 @@ pub struct Opcode {
     pub extra: Vec<isize>,
 }
 
-impl Opcode {
-    pub fn default() -> Self {
+impl Default for Opcode {
+    fn default() -> Self {
         Opcode {
             op: Op::ONop,
             p1: None,
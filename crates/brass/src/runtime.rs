@@ -0,0 +1,363 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Runtime values and helpers that compiled code calls into.
+//!
+//! Runtime types are the `ValueType`s of the `Code` being run, so a type
+//! pointer is the address of an element of `Code::types`. Objects start with
+//! the pointer to their type, as in HashLink.
+
+use std::{
+    alloc::{self, Layout},
+    cell::RefCell,
+    mem, ptr,
+};
+
+use crate::{
+    code::Code,
+    types::{TypeId, TypeKind, ValueType, ValueTypeU},
+};
+
+/// A function value, laid out as HashLink's `vclosure`. With a bound value,
+/// `fun` takes it as an extra first argument.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Closure {
+    pub t: *const ValueType,
+    pub fun: *const u8,
+    pub has_value: i32,
+    pub value: *mut u8,
+}
+
+/// An array, laid out as HashLink's `varray`: `size` elements of type `at`
+/// follow the header.
+#[repr(C)]
+#[derive(Debug)]
+pub struct Array {
+    pub t: *const ValueType,
+    pub at: *const ValueType,
+    pub size: i32,
+    pad: i32,
+}
+
+/// Dynamic call wrapper of a function type: calls `fun`, binding `value` if
+/// `has_value` is set, with arguments read from 8-byte slots, and returns
+/// the result in the low bits of a slot.
+pub type Wrapper = extern "C" fn(*const u8, *mut u8, i32, *const u64) -> u64;
+
+/// State reached by the helpers, at an address that compiled code embeds.
+pub struct Runtime {
+    pub code: *const Code,
+    /// Address of every compiled bytecode function, by findex.
+    pub functions: Vec<*const u8>,
+    /// Dynamic call wrapper of every function type, by type index.
+    pub wrappers: Vec<Option<Wrapper>>,
+    /// Set by a throw, until the exception reaches the entrypoint.
+    pub has_exception: u8,
+    pub exception: *mut u8,
+    /// Message of an exception raised by the runtime itself.
+    message: Option<String>,
+}
+
+impl Runtime {
+    pub fn new(code: &Code) -> Runtime {
+        Runtime {
+            code,
+            functions: vec![ptr::null(); code.nfunctions + code.nnatives],
+            wrappers: vec![None; code.types.len()],
+            has_exception: 0,
+            exception: ptr::null_mut(),
+            message: None,
+        }
+    }
+
+    /// Raises an exception from a helper; compiled code checks for it on
+    /// return.
+    fn raise(&mut self, message: &str) {
+        self.has_exception = 1;
+        self.exception = ptr::null_mut();
+        self.message = Some(message.to_string());
+    }
+
+    /// Clears the pending exception, describing the value thrown.
+    pub fn take_exception(&mut self) -> Option<String> {
+        if self.has_exception == 0 {
+            return None;
+        }
+        self.has_exception = 0;
+        let exc = mem::replace(&mut self.exception, ptr::null_mut());
+        if let Some(message) = self.message.take() {
+            return Some(message);
+        }
+        if exc.is_null() {
+            return Some("null".to_string());
+        }
+        let t = unsafe { *(exc as *const *const ValueType) };
+        match self.type_id(t) {
+            Some(id) => Some(format!("instance of {}", self.code().type_name(id))),
+            None => Some("instance of an unknown type".to_string()),
+        }
+    }
+
+    fn code(&self) -> &Code {
+        unsafe { &*self.code }
+    }
+
+    /// Id of a runtime type pointer, or `None` if it does not point to one
+    /// of the types of the code.
+    fn type_id(&self, t: *const ValueType) -> Option<TypeId> {
+        let types = &self.code().types;
+        let offset = (t as usize).checked_sub(types.as_ptr() as usize)?;
+        let size = mem::size_of::<ValueType>();
+        let index = offset / size;
+        (offset % size == 0 && index < types.len()).then_some(TypeId(index as u32))
+    }
+
+    /// Argument and return kinds of a function type.
+    fn signature(&self, t: *const ValueType) -> (Vec<TypeKind>, TypeKind) {
+        let code = self.code();
+        match unsafe { &(*t).union } {
+            ValueTypeU::FuncType { args, ret, .. } => (
+                args.iter().map(|a| code.get_type(*a).kind).collect(),
+                code.get_type(*ret).kind,
+            ),
+            _ => (Vec::new(), TypeKind::HVOID),
+        }
+    }
+}
+
+/// Converts a value between the kinds of caller and callee. Numbers keep
+/// their value, other kinds their bits.
+fn convert(bits: u64, from: TypeKind, to: TypeKind) -> u64 {
+    let int = match from {
+        TypeKind::HUI8 | TypeKind::HBOOL => Some(bits as u8 as i64),
+        TypeKind::HUI16 => Some(bits as u16 as i64),
+        TypeKind::HI32 => Some(bits as i32 as i64),
+        TypeKind::HI64 => Some(bits as i64),
+        _ => None,
+    };
+    let float = match from {
+        TypeKind::HF32 => Some(f32::from_bits(bits as u32) as f64),
+        TypeKind::HF64 => Some(f64::from_bits(bits)),
+        _ => None,
+    };
+    match (int, float, to) {
+        (Some(i), _, TypeKind::HF32) => (i as f32).to_bits() as u64,
+        (Some(i), _, TypeKind::HF64) => (i as f64).to_bits(),
+        (Some(i), _, _) => i as u64,
+        (_, Some(f), TypeKind::HF32) => (f as f32).to_bits() as u64,
+        (_, Some(f), TypeKind::HF64) => f.to_bits(),
+        (_, Some(f), _) => f as i64 as u64,
+        _ => bits,
+    }
+}
+
+/// Program arguments returned by `sys_args`, kept per thread the way
+/// HashLink's `hl_sys_init` keeps them per process.
+struct SysArgs {
+    array_type: *const ValueType,
+    bytes_type: *const ValueType,
+    args: Vec<*const u16>,
+}
+
+thread_local! {
+    static SYS_ARGS: RefCell<SysArgs> = const {
+        RefCell::new(SysArgs {
+            array_type: ptr::null(),
+            bytes_type: ptr::null(),
+            args: Vec::new(),
+        })
+    };
+}
+
+/// Sets the arguments that `sys_args` gives the program of `code`, as
+/// NUL-terminated UTF-16 strings. There is no collector yet, so they leak.
+pub fn set_sys_args(code: &Code, args: &[String]) {
+    let type_of = |kind| {
+        code.types
+            .iter()
+            .find(|t| t.kind == kind)
+            .map_or(ptr::null(), |t| t as *const ValueType)
+    };
+    let args = args
+        .iter()
+        .map(|a| {
+            let s: Box<[u16]> = a.encode_utf16().chain([0]).collect();
+            Box::leak(s).as_ptr()
+        })
+        .collect();
+    SYS_ARGS.with(|sys| {
+        *sys.borrow_mut() = SysArgs {
+            array_type: type_of(TypeKind::HARRAY),
+            bytes_type: type_of(TypeKind::HBYTES),
+            args,
+        }
+    });
+}
+
+/// HashLink's `sys_args`: a new array of the program arguments as bytes.
+/// There is no collector yet, so the array leaks.
+pub extern "C" fn sys_args() -> *mut Array {
+    SYS_ARGS.with(|sys| {
+        let sys = sys.borrow();
+        let layout = Layout::new::<Array>()
+            .extend(Layout::array::<*const u16>(sys.args.len()).unwrap())
+            .unwrap()
+            .0;
+        unsafe {
+            let array = alloc::alloc(layout) as *mut Array;
+            if array.is_null() {
+                alloc::handle_alloc_error(layout);
+            }
+            array.write(Array {
+                t: sys.array_type,
+                at: sys.bytes_type,
+                size: sys.args.len() as i32,
+                pad: 0,
+            });
+            let data = array.add(1) as *mut *const u16;
+            ptr::copy_nonoverlapping(sys.args.as_ptr(), data, sys.args.len());
+            array
+        }
+    })
+}
+
+/// Allocates a closure. There is no collector yet, so closures leak.
+pub extern "C" fn alloc_closure(
+    t: *const ValueType,
+    fun: *const u8,
+    has_value: i32,
+    value: *mut u8,
+) -> *mut Closure {
+    Box::into_raw(Box::new(Closure {
+        t,
+        fun,
+        has_value,
+        value,
+    }))
+}
+
+/// Raises HashLink's exception for a null value being used.
+///
+/// # Safety
+///
+/// `rt` must be the runtime of the calling code.
+pub unsafe extern "C" fn null_access(rt: *mut Runtime) {
+    (*rt).raise("Null access");
+}
+
+/// Binds `obj` to the method in slot `pindex` of its runtime type, giving a
+/// closure of type `t`. Raises an exception, returning null, if `obj` is
+/// null or the method has no compiled code.
+///
+/// # Safety
+///
+/// `obj` must be null or an object whose type is in the code of `rt`.
+pub unsafe extern "C" fn virtual_closure(
+    rt: *mut Runtime,
+    obj: *mut u8,
+    pindex: i32,
+    t: *const ValueType,
+) -> *mut Closure {
+    let rt = &mut *rt;
+    if obj.is_null() {
+        rt.raise("Null access");
+        return ptr::null_mut();
+    }
+    let obj_type = match rt.type_id(*(obj as *const *const ValueType)) {
+        Some(t) => t,
+        None => {
+            rt.raise("object of an unknown type");
+            return ptr::null_mut();
+        }
+    };
+    let fun = rt
+        .code()
+        .type_method(obj_type, pindex as usize)
+        .and_then(|p| rt.functions.get(p.findex).copied())
+        .unwrap_or(ptr::null());
+    if fun.is_null() {
+        let message = format!(
+            "method {} of {} is not compiled",
+            pindex,
+            rt.code().type_name(obj_type)
+        );
+        rt.raise(&message);
+        return ptr::null_mut();
+    }
+    alloc_closure(t, fun, 1, obj)
+}
+
+/// Calls a closure whose type differs from the type `t` it was called with,
+/// converting the arguments in `args` and the result. Missing arguments are
+/// zero and extra ones are dropped. Raises an exception, returning 0, if the
+/// closure does not have a function type.
+///
+/// # Safety
+///
+/// `c` must be a closure of the code of `rt`, and `args` must hold one slot
+/// per argument of `t`.
+pub unsafe extern "C" fn dyn_call(
+    rt: *mut Runtime,
+    c: *const Closure,
+    t: *const ValueType,
+    args: *const u64,
+) -> u64 {
+    let rt = &mut *rt;
+    let c = &*c;
+    let wrapper = rt
+        .type_id(c.t)
+        .and_then(|id| rt.wrappers.get(id.index()).copied().flatten());
+    let wrapper = match wrapper {
+        Some(w) => w,
+        None => {
+            rt.raise("closure without a function type");
+            return 0;
+        }
+    };
+    let (from_args, from_ret) = rt.signature(t);
+    let (to_args, to_ret) = rt.signature(c.t);
+    let converted: Vec<u64> = to_args
+        .iter()
+        .enumerate()
+        .map(|(i, to)| match from_args.get(i) {
+            Some(from) => convert(*args.add(i), *from, *to),
+            None => 0,
+        })
+        .collect();
+    let ret = wrapper(c.fun, c.value, c.has_value, converted.as_ptr());
+    convert(ret, to_ret, from_ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::convert;
+    use crate::types::TypeKind;
+
+    #[test]
+    fn conversions() {
+        let f = |v: f64| v.to_bits();
+        assert_eq!(
+            convert(-3i64 as u64, TypeKind::HI32, TypeKind::HF64),
+            f(-3.0)
+        );
+        assert_eq!(convert(f(2.75), TypeKind::HF64, TypeKind::HI32) as i32, 2);
+        assert_eq!(
+            convert(1.5f32.to_bits() as u64, TypeKind::HF32, TypeKind::HF64),
+            f(1.5)
+        );
+        assert_eq!(convert(0x1ff, TypeKind::HUI8, TypeKind::HI64), 0xff);
+        assert_eq!(convert(0x1234, TypeKind::HDYN, TypeKind::HOBJ), 0x1234);
+    }
+}
@@ -1,12 +1,11 @@
-use std::any::Any;
-
-
 use crc32fast::Hasher as Crc32Hasher;
 use std::hash::Hasher;
 use num_enum::IntoPrimitive;
 use num_enum::TryFromPrimitive;
 use strum_macros::Display;
 
+use crate::errors::DecodeError;
+use crate::instr::Instr;
 use crate::op::Opcode;
 use strum_macros::IntoStaticStr;
 
@@ -56,17 +55,27 @@ pub enum TypeKind {
     // HForceInt = 0x7FFFFFFF,
 }
 
+/// Index of a type in `Code::types`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct TypeId(pub u32);
+
+impl TypeId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
 #[derive(PartialEq)]
 #[derive(Debug, Clone, Display)]
 pub enum ValueTypeU {
     FuncType {
-        args: Vec<ValueType>,
+        args: Vec<TypeId>,
         nargs: usize,
-        ret: Box<ValueType>,
+        ret: TypeId,
     },
     ObjType {
         name: String,
-        super_type: Box<ValueType>,
+        super_type: Option<TypeId>,
         fields: Vec<ObjField>,
         nfields: usize,
         nproto: usize,
@@ -86,7 +95,7 @@ pub enum ValueTypeU {
         constructs: Vec<EnumConstruct>,
         global_value: Vec<isize>,
     },
-    Ref(Box<ValueType>),
+    Ref(TypeId),
     Abstract(String),
     Null,
     Void,
@@ -97,27 +106,28 @@ pub enum ValueTypeU {
 pub struct ValueType {
     pub union: ValueTypeU,
     pub abs_name: Option<String>,
-    pub tparam: Option<Box<ValueType>>,
+    pub tparam: Option<TypeId>,
     pub kind: TypeKind,
 }
 
 impl ValueType {
-    pub fn hash(self, isrec: bool) -> u32 {
+    /// Hashes this type, with `types` resolving the ids it refers to.
+    pub fn hash(&self, types: &[ValueType], isrec: bool) -> u32 {
         let mut hasher = Crc32Hasher::new();
         let kind = self.kind;
         match kind {
             TypeKind::HFUN | TypeKind::HMETHOD => {
-                if let ValueTypeU::FuncType { args, nargs, ret } = self.union {
-                    hasher.write_usize(nargs);
-                    for i in 0..nargs {
+                if let ValueTypeU::FuncType { args, nargs, ret } = &self.union {
+                    hasher.write_usize(*nargs);
+                    for i in 0..*nargs {
                         if !isrec {
-                            let a = args.get(i).unwrap().clone();
-                            hasher.write_u32(a.hash(true));
+                            let a = &types[args[i].index()];
+                            hasher.write_u32(a.hash(types, true));
                         }
                     }
                     if !isrec {
-                        let r = ret.as_ref().clone();
-                        hasher.write_u32(r.hash(true));
+                        let r = &types[ret.index()];
+                        hasher.write_u32(r.hash(types, true));
                     }
                 }
             }
@@ -133,32 +143,31 @@ impl ValueType {
                     bindings: _,
                     global_value: _,
                     rt: _,
-                } = self.union
+                } = &self.union
                 {
                     hasher.write(name.as_bytes());
-                    hasher.write_usize(nfields);
-                    hasher.write_usize(nproto);
+                    hasher.write_usize(*nfields);
+                    hasher.write_usize(*nproto);
 
-                    for i in 0..nfields {
-                        hasher.write_u32(fields.get(i).unwrap().clone().hashed_name);
+                    for field in &fields[..*nfields] {
+                        hasher.write_u32(field.hashed_name);
                         if !isrec {
-                            hasher.write_u32(fields.get(i).unwrap().clone().t.hash(true));
+                            hasher.write_u32(types[field.t.index()].hash(types, true));
                         }
                     }
                 }
             }
-            TypeKind::HREF | TypeKind::HNULL => {
-                if !isrec {
-                    hasher.write_u32(self.tparam.unwrap().hash(true));
-                }
+            TypeKind::HREF | TypeKind::HNULL if !isrec => {
+                let p = &types[self.tparam.unwrap().index()];
+                hasher.write_u32(p.hash(types, true));
             }
             TypeKind::HVIRTUAL => {
-                if let ValueTypeU::VirtualType { nfields, fields } = self.union {
-                    hasher.write_usize(nfields);
-                    for i in 0..nfields {
-                        hasher.write_u32(fields.get(i).unwrap().clone().hashed_name);
+                if let ValueTypeU::VirtualType { nfields, fields } = &self.union {
+                    hasher.write_usize(*nfields);
+                    for field in &fields[..*nfields] {
+                        hasher.write_u32(field.hashed_name);
                         if !isrec {
-                            hasher.write_u32(fields.get(i).unwrap().clone().t.hash(true));
+                            hasher.write_u32(types[field.t.index()].hash(types, true));
                         }
                     }
                 }
@@ -169,24 +178,22 @@ impl ValueType {
                     nconstructs,
                     constructs,
                     global_value: _,
-                } = self.union
+                } = &self.union
                 {
                     hasher.write(name.as_bytes());
-                    for i in 0..nconstructs {
-                        let con = constructs.get(i).unwrap().clone();
+                    for con in &constructs[..*nconstructs] {
                         hasher.write_usize(con.nparams);
                         hasher.write(con.name.as_bytes());
-                        for k in 0..con.nparams {
-                            let p = con.params.get(k).unwrap().clone();
+                        for p in &con.params[..con.nparams] {
                             if !isrec {
-                                hasher.write_u32(p.hash(true));
+                                hasher.write_u32(types[p.index()].hash(types, true));
                             }
                         }
                     }
                 }
             }
             TypeKind::HABSTRACT => {
-                hasher.write(self.abs_name.unwrap().as_bytes());
+                hasher.write(self.abs_name.as_ref().unwrap().as_bytes());
             }
             _ => {}
         }
@@ -195,8 +202,8 @@ impl ValueType {
     }
 }
 
-impl ValueType {
-    pub fn default() -> Self {
+impl Default for ValueType {
+    fn default() -> Self {
         ValueType {
             union: ValueTypeU::Null,
             abs_name: None,
@@ -211,7 +218,7 @@ impl ValueType {
 pub struct ObjField {
     pub name: String,
     pub hashed_name: u32,
-    pub t: ValueType,
+    pub t: TypeId,
 }
 #[derive(Clone, Debug)]
 #[derive(PartialEq)]
@@ -227,7 +234,7 @@ pub struct ObjProto {
 pub struct EnumConstruct {
     pub name: String,
     pub nparams: usize,
-    pub params: Vec<ValueType>,
+    pub params: Vec<TypeId>,
     pub size: usize,
     pub hasptr: bool,
     pub offsets: Vec<i32>,
@@ -240,16 +247,54 @@ pub struct RuntimeObj {}
 #[derive(Clone, Debug)]
 #[derive(PartialEq)]
 pub struct HLFunction {
-    pub t: ValueType,
+    pub t: TypeId,
     pub findex: usize,
     pub nregs: usize,
     pub nops: usize,
     pub rf:u32,
-    pub regs: Vec<ValueType>,
-    pub ops: Vec<Opcode>,
+    pub regs: Vec<TypeId>,
+    pub(crate) ops: Vec<Opcode>,
     pub debug: Vec<i32>,
+    /// Debug records of local variables being assigned, in op order.
+    pub assigns: Vec<VarAssign>,
     pub obj:Option<ValueTypeU>,
-    pub field: Option<FuncField>,
+    pub(crate) field: Option<FuncField>,
+}
+
+impl HLFunction {
+    /// The ops of this function as typed instructions.
+    pub fn instrs(&self) -> Result<Vec<Instr>, DecodeError> {
+        self.ops
+            .iter()
+            .enumerate()
+            .map(|(i, o)| Instr::try_from(o).map_err(|e| e.at_op(i)))
+            .collect()
+    }
+
+    /// Variables assigned at or before the op at `op_index`, most recent
+    /// assignment first. A name assigned several times is only listed once.
+    ///
+    /// This is not lexical scope: the bytecode records where each variable is
+    /// assigned but not where its block ends, so variables of blocks that
+    /// have already closed are still listed.
+    pub fn assigned_vars(&self, op_index: usize) -> Vec<&VarAssign> {
+        let mut vars: Vec<&VarAssign> = Vec::new();
+        for a in self.assigns.iter().rev() {
+            if a.op_index as i64 <= op_index as i64 && !vars.iter().any(|v| v.name == a.name) {
+                vars.push(a);
+            }
+        }
+        vars
+    }
+}
+
+/// A local variable assigned by the op at `op_index`.
+#[derive(Clone, Debug)]
+#[derive(PartialEq)]
+pub struct VarAssign {
+    pub name: String,
+    /// Negative for values that are set before the first op, such as arguments.
+    pub op_index: i32,
 }
 
 #[derive(Clone, Debug)]
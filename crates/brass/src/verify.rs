@@ -0,0 +1,933 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Register typing rules of the HashLink compiler, checked op by op so that
+//! malformed bytecode is rejected before it reaches the JIT.
+
+use std::fmt;
+
+use crate::code::Code;
+use crate::disasm::kind_name;
+use crate::instr::{FunIdx, Instr, Reg};
+use crate::op::Op;
+use crate::types::{HLFunction, TypeId, TypeKind, ValueType, ValueTypeU};
+
+/// Guards the recursive type comparisons against cyclic types.
+const MAX_DEPTH: usize = 32;
+
+/// A problem found in one function, at one op unless it concerns the
+/// function as a whole.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub findex: usize,
+    pub op: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "function #{}", self.findex)?;
+        if let Some(op) = self.op {
+            write!(f, ", op {}", op)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// Checks every function of `code`, decoding lazily read bodies as needed.
+pub fn verify(code: &Code) -> Vec<Diagnostic> {
+    let ftypes = function_types(code);
+    let mut diagnostics = Vec::new();
+    for i in 0..code.functions.len() {
+        match code.function(i) {
+            Ok(f) => diagnostics.extend(Verifier::new(code, &ftypes, f).run()),
+            Err(e) => diagnostics.push(Diagnostic {
+                findex: code.functions[i].findex,
+                op: e.op(),
+                message: e.to_string(),
+            }),
+        }
+    }
+    diagnostics
+}
+
+/// Checks a single function of `code`.
+pub fn verify_function(code: &Code, f: &HLFunction) -> Vec<Diagnostic> {
+    Verifier::new(code, &function_types(code), f).run()
+}
+
+/// Type of every function and native, by `findex`.
+fn function_types(code: &Code) -> Vec<Option<TypeId>> {
+    let mut ftypes = Vec::new();
+    let functions = code.functions.iter().map(|f| (f.findex, f.t));
+    let natives = code.natives.iter().map(|n| (n.findex, n.t));
+    for (findex, t) in functions.chain(natives) {
+        if findex >= ftypes.len() {
+            ftypes.resize(findex + 1, None);
+        }
+        ftypes[findex] = Some(t);
+    }
+    ftypes
+}
+
+fn is_dynamic(t: &ValueType) -> bool {
+    matches!(
+        t.kind,
+        TypeKind::HDYN
+            | TypeKind::HFUN
+            | TypeKind::HOBJ
+            | TypeKind::HARRAY
+            | TypeKind::HVIRTUAL
+            | TypeKind::HDYNOBJ
+            | TypeKind::HNULL
+            | TypeKind::HENUM
+    )
+}
+
+fn is_nullable(t: &ValueType) -> bool {
+    matches!(
+        t.kind,
+        TypeKind::HBYTES
+            | TypeKind::HDYN
+            | TypeKind::HFUN
+            | TypeKind::HOBJ
+            | TypeKind::HARRAY
+            | TypeKind::HVIRTUAL
+            | TypeKind::HDYNOBJ
+            | TypeKind::HABSTRACT
+            | TypeKind::HENUM
+            | TypeKind::HNULL
+            | TypeKind::HREF
+            | TypeKind::HTYPE
+            | TypeKind::HMETHOD
+            | TypeKind::HSTRUCT
+            | TypeKind::HPACKED
+    )
+}
+
+fn is_int(kind: TypeKind) -> bool {
+    matches!(
+        kind,
+        TypeKind::HUI8 | TypeKind::HUI16 | TypeKind::HI32 | TypeKind::HI64
+    )
+}
+
+fn is_float(kind: TypeKind) -> bool {
+    matches!(kind, TypeKind::HF32 | TypeKind::HF64)
+}
+
+struct Verifier<'a> {
+    code: &'a Code,
+    ftypes: &'a [Option<TypeId>],
+    f: &'a HLFunction,
+}
+
+type Check = Result<(), String>;
+
+impl<'a> Verifier<'a> {
+    fn new(code: &'a Code, ftypes: &'a [Option<TypeId>], f: &'a HLFunction) -> Self {
+        Verifier { code, ftypes, f }
+    }
+
+    fn run(&self) -> Vec<Diagnostic> {
+        let mut diagnostics = Vec::new();
+        let mut report = |op: Option<usize>, message: String| {
+            diagnostics.push(Diagnostic {
+                findex: self.f.findex,
+                op,
+                message,
+            })
+        };
+
+        if let Err(message) = self.check_header() {
+            report(None, message);
+            return diagnostics;
+        }
+        for (i, o) in self.f.ops.iter().enumerate() {
+            let result = Instr::try_from(o)
+                .map_err(|e| e.to_string())
+                .and_then(|instr| self.check(i, &instr));
+            if let Err(message) = result {
+                report(Some(i), message);
+            }
+        }
+        diagnostics
+    }
+
+    fn check_header(&self) -> Check {
+        let f = self.f;
+        if f.regs.len() != f.nregs || f.ops.len() != f.nops {
+            return Err("register or op count does not match the function body".to_string());
+        }
+        let (args, _) = self.fun_sig(f.t).ok_or("function type is not a function")?;
+        for (i, t) in args.iter().enumerate() {
+            self.reg(Reg(i as u32), *t)
+                .map_err(|e| format!("argument {}: {}", i, e))?;
+        }
+        Ok(())
+    }
+
+    fn ty(&self, t: TypeId) -> Result<&'a ValueType, String> {
+        self.code
+            .types
+            .get(t.index())
+            .ok_or_else(|| format!("type {} out of range", t.0))
+    }
+
+    fn kind(&self, t: TypeId) -> Result<TypeKind, String> {
+        Ok(self.ty(t)?.kind)
+    }
+
+    fn type_name(&self, t: TypeId) -> String {
+        self.code.type_name(t)
+    }
+
+    fn rtype(&self, r: Reg) -> Result<TypeId, String> {
+        self.f
+            .regs
+            .get(r.index())
+            .copied()
+            .ok_or_else(|| format!("register {} out of range", r.0))
+    }
+
+    fn rkind(&self, r: Reg) -> Result<TypeKind, String> {
+        self.kind(self.rtype(r)?)
+    }
+
+    fn fun_sig(&self, t: TypeId) -> Option<(&'a [TypeId], TypeId)> {
+        match &self.ty(t).ok()?.union {
+            ValueTypeU::FuncType { args, ret, .. } => Some((args, *ret)),
+            _ => None,
+        }
+    }
+
+    fn same(&self, a: TypeId, b: TypeId) -> bool {
+        a == b || matches!((self.ty(a), self.ty(b)), (Ok(x), Ok(y)) if x == y)
+    }
+
+    /// Whether a value of type `t1` can be used where `t2` is expected.
+    fn safe_cast(&self, t1: TypeId, t2: TypeId) -> bool {
+        self.safe_cast_depth(t1, t2, 0)
+    }
+
+    fn safe_cast_depth(&self, t1: TypeId, t2: TypeId, depth: usize) -> bool {
+        if self.same(t1, t2) {
+            return true;
+        }
+        let (a, b) = match (self.ty(t1), self.ty(t2)) {
+            (Ok(a), Ok(b)) => (a, b),
+            _ => return false,
+        };
+        if depth > MAX_DEPTH {
+            return false;
+        }
+        match (&a.union, &b.union) {
+            _ if b.kind == TypeKind::HDYN => is_dynamic(a),
+            (ValueTypeU::VirtualType { fields: f1, .. }, ValueTypeU::VirtualType { fields: f2, .. }) => {
+                f2.iter().all(|field| {
+                    f1.iter()
+                        .any(|other| other.name == field.name && self.same(other.t, field.t))
+                })
+            }
+            (ValueTypeU::ObjType { .. }, ValueTypeU::ObjType { name, .. }) if a.kind == b.kind => {
+                self.code.type_hierarchy(t1).iter().any(|t| match self.ty(*t).map(|ty| &ty.union) {
+                    Ok(ValueTypeU::ObjType { name: n, .. }) => n == name,
+                    _ => false,
+                })
+            }
+            (
+                ValueTypeU::FuncType { args: args1, ret: ret1, .. },
+                ValueTypeU::FuncType { args: args2, ret: ret2, .. },
+            ) if a.kind == b.kind && args1.len() == args2.len() => {
+                args1.iter().zip(args2).all(|(x, y)| {
+                    self.safe_cast_depth(*y, *x, depth + 1)
+                        || (self.kind(*x) == Ok(TypeKind::HDYN)
+                            && self.ty(*y).is_ok_and(is_dynamic))
+                }) && self.safe_cast_depth(*ret1, *ret2, depth + 1)
+            }
+            _ => false,
+        }
+    }
+
+    fn reg(&self, r: Reg, t: TypeId) -> Check {
+        let rt = self.rtype(r)?;
+        if self.safe_cast(rt, t) {
+            Ok(())
+        } else {
+            Err(format!(
+                "register {} is {} but should be {}",
+                r.0,
+                self.type_name(rt),
+                self.type_name(t)
+            ))
+        }
+    }
+
+    /// Like `reg` for the basic types that have a single representation.
+    fn reg_kind(&self, r: Reg, kind: TypeKind) -> Check {
+        let rt = self.rtype(r)?;
+        let ty = self.ty(rt)?;
+        if ty.kind == kind || (kind == TypeKind::HDYN && is_dynamic(ty)) {
+            Ok(())
+        } else {
+            Err(format!(
+                "register {} is {} but should be {}",
+                r.0,
+                self.type_name(rt),
+                kind_name(kind)
+            ))
+        }
+    }
+
+    fn expect(&self, ok: bool, r: Reg, what: &str) -> Check {
+        if ok {
+            Ok(())
+        } else {
+            let rt = self.rtype(r)?;
+            Err(format!("register {} is {} but should be {}", r.0, self.type_name(rt), what))
+        }
+    }
+
+    fn numeric(&self, r: Reg) -> Check {
+        let kind = self.rkind(r)?;
+        self.expect(is_int(kind) || is_float(kind), r, "numeric")
+    }
+
+    fn int(&self, r: Reg) -> Check {
+        self.expect(is_int(self.rkind(r)?), r, "integral")
+    }
+
+    fn float(&self, r: Reg) -> Check {
+        self.expect(is_float(self.rkind(r)?), r, "a float")
+    }
+
+    fn dynamic(&self, r: Reg) -> Check {
+        let ty = self.ty(self.rtype(r)?)?;
+        self.expect(is_dynamic(ty), r, "castable to dynamic")
+    }
+
+    fn is_obj(&self, r: Reg) -> Check {
+        let kind = self.rkind(r)?;
+        self.expect(matches!(kind, TypeKind::HOBJ | TypeKind::HSTRUCT), r, "an object")
+    }
+
+    /// Checks `value` against the type of the value it is assigned from.
+    fn check_into(&self, t: TypeId, dst: Reg) -> Check {
+        let rt = self.rtype(dst)?;
+        if self.safe_cast(t, rt) {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} does not fit register {} of type {}",
+                self.type_name(t),
+                dst.0,
+                self.type_name(rt)
+            ))
+        }
+    }
+
+    fn index(&self, what: &str, index: u32, len: usize) -> Check {
+        if (index as usize) < len {
+            Ok(())
+        } else {
+            Err(format!("{} {} out of range", what, index))
+        }
+    }
+
+    fn jump(&self, pos: usize, offset: i32) -> Check {
+        let target = pos as i64 + 1 + offset as i64;
+        if target < 0 || target >= self.f.ops.len() as i64 {
+            return Err(format!("jump to {} outside of the function", target));
+        }
+        if offset < 0 && self.f.ops[target as usize].op != Op::OLabel {
+            return Err(format!("jump back to {} without a label", target));
+        }
+        Ok(())
+    }
+
+    fn fun_type(&self, fun: FunIdx) -> Result<TypeId, String> {
+        self.ftypes
+            .get(fun.index())
+            .copied()
+            .flatten()
+            .ok_or_else(|| format!("function {} out of range", fun.0))
+    }
+
+    fn call_sig(&self, t: TypeId, args: &[Reg], dst: Reg) -> Check {
+        let (targs, ret) = self
+            .fun_sig(t)
+            .ok_or_else(|| format!("{} is not a function", self.type_name(t)))?;
+        if targs.len() != args.len() {
+            return Err(format!(
+                "call has {} arguments but the function takes {}",
+                args.len(),
+                targs.len()
+            ));
+        }
+        for (a, t) in args.iter().zip(targs) {
+            self.reg(*a, *t)?;
+        }
+        self.check_into(ret, dst)
+    }
+
+    fn call(&self, fun: FunIdx, args: &[Reg], dst: Reg) -> Check {
+        self.call_sig(self.fun_type(fun)?, args, dst)
+    }
+
+    /// Type of field `fid` of the object or virtual held by `obj`.
+    fn field(&self, obj: Reg, fid: u32) -> Result<TypeId, String> {
+        let t = self.rtype(obj)?;
+        match self.kind(t)? {
+            TypeKind::HOBJ | TypeKind::HSTRUCT | TypeKind::HVIRTUAL => self
+                .code
+                .type_field(t, fid as usize)
+                .map(|field| field.t)
+                .ok_or_else(|| format!("{} has no field {}", self.type_name(t), fid)),
+            _ => Err(format!("register {} is {} but should be an object", obj.0, self.type_name(t))),
+        }
+    }
+
+    /// Type of the method in virtual table slot `fid` of `obj`'s class.
+    fn method(&self, obj: Reg, fid: u32) -> Result<TypeId, String> {
+        let t = self.rtype(obj)?;
+        match self.code.type_method(t, fid as usize) {
+            Some(p) => self.fun_type(FunIdx(p.findex as u32)),
+            None => Err(format!("{} has no method {}", self.type_name(t), fid)),
+        }
+    }
+
+    fn method_call(&self, obj: Reg, fid: u32, args: &[Reg], dst: Reg) -> Check {
+        match self.rkind(obj)? {
+            TypeKind::HOBJ | TypeKind::HSTRUCT => {
+                // methods take the object as their first argument
+                let t = self.method(obj, fid)?;
+                self.call_sig(t, args, dst)
+            }
+            TypeKind::HVIRTUAL => {
+                let t = self.field(obj, fid)?;
+                match self.kind(t)? {
+                    TypeKind::HMETHOD | TypeKind::HFUN => self.call_sig(t, &args[1..], dst),
+                    _ => Ok(()),
+                }
+            }
+            _ => self.is_obj(obj),
+        }
+    }
+
+    /// Checks that `r` holds a function from `args` to `ret`.
+    fn closure(&self, r: Reg, args: &[TypeId], ret: TypeId) -> Check {
+        let rt = self.rtype(r)?;
+        match self.fun_sig(rt) {
+            Some((rargs, rret))
+                if rargs.len() == args.len()
+                    && rargs.iter().zip(args).all(|(a, b)| self.same(*a, *b))
+                    && self.same(rret, ret) =>
+            {
+                Ok(())
+            }
+            _ => Err(format!(
+                "register {} is {} but should be the closure type",
+                r.0,
+                self.type_name(rt)
+            )),
+        }
+    }
+
+    fn enum_params(&self, r: Reg, construct: u32) -> Result<&'a [TypeId], String> {
+        let t = self.rtype(r)?;
+        match &self.ty(t)?.union {
+            ValueTypeU::EnumType { constructs, .. } => constructs
+                .get(construct as usize)
+                .map(|c| &c.params[..])
+                .ok_or_else(|| format!("{} has no constructor {}", self.type_name(t), construct)),
+            _ => Err(format!("register {} is {} but should be an enum", r.0, self.type_name(t))),
+        }
+    }
+
+    fn ref_param(&self, r: Reg) -> Result<Option<TypeId>, String> {
+        let ty = self.ty(self.rtype(r)?)?;
+        Ok(match ty.kind {
+            TypeKind::HREF => ty.tparam,
+            _ => None,
+        })
+    }
+
+    fn check(&self, pos: usize, instr: &Instr) -> Check {
+        let code = self.code;
+        match *instr {
+            Instr::Mov { dst, src } => self.reg(src, self.rtype(dst)?),
+            Instr::Int { dst, index } => {
+                self.index("int", index, code.ints.len())?;
+                self.expect(is_int(self.rkind(dst)?), dst, "integral")
+            }
+            Instr::Float { dst, index } => {
+                self.index("float", index, code.floats.len())?;
+                self.float(dst)
+            }
+            Instr::Bool { dst, .. } => self.reg_kind(dst, TypeKind::HBOOL),
+            Instr::Bytes { dst, index } => {
+                // before version 5 bytes constants are taken from the strings
+                let len = if code.version >= 5 {
+                    code.bytes_pos.len()
+                } else {
+                    code.strings.len()
+                };
+                self.index("bytes", index, len)?;
+                self.reg_kind(dst, TypeKind::HBYTES)
+            }
+            Instr::String { dst, index } => {
+                self.index("string", index, code.strings.len())?;
+                self.reg_kind(dst, TypeKind::HBYTES)
+            }
+            Instr::Null { dst } => {
+                let ty = self.ty(self.rtype(dst)?)?;
+                self.expect(is_nullable(ty), dst, "nullable")
+            }
+
+            Instr::Add { dst, a, b }
+            | Instr::Sub { dst, a, b }
+            | Instr::Mul { dst, a, b }
+            | Instr::SDiv { dst, a, b }
+            | Instr::UDiv { dst, a, b }
+            | Instr::SMod { dst, a, b }
+            | Instr::UMod { dst, a, b } => {
+                self.numeric(dst)?;
+                self.reg(a, self.rtype(dst)?)?;
+                self.reg(b, self.rtype(dst)?)
+            }
+            Instr::Shl { dst, a, b }
+            | Instr::SShr { dst, a, b }
+            | Instr::UShr { dst, a, b }
+            | Instr::And { dst, a, b }
+            | Instr::Or { dst, a, b }
+            | Instr::Xor { dst, a, b } => {
+                self.int(dst)?;
+                self.reg(a, self.rtype(dst)?)?;
+                self.reg(b, self.rtype(dst)?)
+            }
+            Instr::Neg { dst, src } => {
+                self.numeric(dst)?;
+                self.reg(src, self.rtype(dst)?)
+            }
+            Instr::Not { dst, src } => {
+                self.reg_kind(dst, TypeKind::HBOOL)?;
+                self.reg_kind(src, TypeKind::HBOOL)
+            }
+            Instr::Incr { dst } | Instr::Decr { dst } => self.int(dst),
+
+            Instr::Call0 { dst, fun } => self.call(fun, &[], dst),
+            Instr::Call1 { dst, fun, a } => self.call(fun, &[a], dst),
+            Instr::Call2 { dst, fun, a, b } => self.call(fun, &[a, b], dst),
+            Instr::Call3 { dst, fun, a, b, c } => self.call(fun, &[a, b, c], dst),
+            Instr::Call4 { dst, fun, a, b, c, d } => self.call(fun, &[a, b, c, d], dst),
+            Instr::CallN { dst, fun, ref args } => self.call(fun, args, dst),
+            Instr::CallMethod { dst, field, ref args } => match args.first() {
+                Some(obj) => self.method_call(*obj, field, args, dst),
+                None => Err("method call without an object".to_string()),
+            },
+            Instr::CallThis { dst, field, ref args } => {
+                let args: Vec<Reg> = [Reg(0)].into_iter().chain(args.iter().copied()).collect();
+                self.method_call(Reg(0), field, &args, dst)
+            }
+            Instr::CallClosure { dst, fun, ref args } => {
+                let t = self.rtype(fun)?;
+                match self.kind(t)? {
+                    TypeKind::HFUN => self.call_sig(t, args, dst),
+                    TypeKind::HDYN => args.iter().try_for_each(|a| self.rtype(*a).map(drop)),
+                    _ => Err(format!(
+                        "register {} is {} but should be a closure",
+                        fun.0,
+                        self.type_name(t)
+                    )),
+                }
+            }
+
+            Instr::StaticClosure { dst, fun } => self.reg(dst, self.fun_type(fun)?),
+            Instr::InstanceClosure { dst, fun, obj } => {
+                let t = self.fun_type(fun)?;
+                match self.fun_sig(t) {
+                    Some((args, ret)) if !args.is_empty() => {
+                        self.reg(obj, args[0])?;
+                        let ty = self.ty(args[0])?;
+                        self.expect(is_nullable(ty), obj, "nullable")?;
+                        self.closure(dst, &args[1..], ret)
+                    }
+                    _ => Err(format!("function {} takes no object", fun.0)),
+                }
+            }
+            Instr::VirtualClosure { dst, obj, field } => match self.rkind(obj)? {
+                TypeKind::HOBJ | TypeKind::HSTRUCT => {
+                    let t = self.method(obj, field)?;
+                    match self.fun_sig(t) {
+                        Some((args, ret)) if !args.is_empty() => {
+                            self.reg(obj, args[0])?;
+                            self.closure(dst, &args[1..], ret)
+                        }
+                        _ => Err(format!("method {} takes no object", field)),
+                    }
+                }
+                TypeKind::HVIRTUAL => self.field(obj, field).map(drop),
+                _ => self.is_obj(obj),
+            },
+
+            Instr::GetGlobal { dst, global } => {
+                self.index("global", global.0, code.globals.len())?;
+                let g = code.globals[global.index()];
+                if self.safe_cast(g, self.rtype(dst)?) {
+                    Ok(())
+                } else {
+                    self.reg(dst, g)
+                }
+            }
+            Instr::SetGlobal { global, src } => {
+                self.index("global", global.0, code.globals.len())?;
+                self.reg(src, code.globals[global.index()])
+            }
+            Instr::Field { dst, obj, field } => self.check_into(self.field(obj, field)?, dst),
+            Instr::SetField { obj, field, src } => self.reg(src, self.field(obj, field)?),
+            Instr::GetThis { dst, field } => self.check_into(self.field(Reg(0), field)?, dst),
+            Instr::SetThis { field, src } => self.reg(src, self.field(Reg(0), field)?),
+            Instr::DynGet { dst, obj, name } => {
+                self.rtype(dst)?;
+                self.index("string", name, code.strings.len())?;
+                let kind = self.rkind(obj)?;
+                self.expect(
+                    matches!(kind, TypeKind::HDYNOBJ | TypeKind::HDYN | TypeKind::HVIRTUAL),
+                    obj,
+                    "a dynamic object",
+                )
+            }
+            Instr::DynSet { obj, name, src } => {
+                self.rtype(src)?;
+                self.index("string", name, code.strings.len())?;
+                let kind = self.rkind(obj)?;
+                self.expect(
+                    matches!(kind, TypeKind::HDYNOBJ | TypeKind::HDYN | TypeKind::HVIRTUAL),
+                    obj,
+                    "a dynamic object",
+                )
+            }
+
+            Instr::JTrue { cond, offset } | Instr::JFalse { cond, offset } => {
+                let ty = self.ty(self.rtype(cond)?)?;
+                if ty.kind != TypeKind::HBOOL && !is_dynamic(ty) {
+                    self.reg_kind(cond, TypeKind::HBOOL)?;
+                }
+                self.jump(pos, offset)
+            }
+            Instr::JNull { reg, offset } | Instr::JNotNull { reg, offset } => {
+                self.rtype(reg)?;
+                self.jump(pos, offset)
+            }
+            Instr::JSLt { a, b, offset }
+            | Instr::JSGte { a, b, offset }
+            | Instr::JSGt { a, b, offset }
+            | Instr::JSLte { a, b, offset }
+            | Instr::JULt { a, b, offset }
+            | Instr::JUGte { a, b, offset }
+            | Instr::JNotLt { a, b, offset }
+            | Instr::JNotGte { a, b, offset } => {
+                let ta = self.rtype(a)?;
+                if !self.safe_cast(ta, self.rtype(b)?) {
+                    self.reg(b, ta)?;
+                }
+                self.jump(pos, offset)
+            }
+            Instr::JEq { a, b, offset } | Instr::JNotEq { a, b, offset } => {
+                let (ta, tb) = (self.rtype(a)?, self.rtype(b)?);
+                let objects = |k| matches!(k, TypeKind::HOBJ | TypeKind::HVIRTUAL);
+                let dynamics = |k| matches!(k, TypeKind::HDYN | TypeKind::HFUN);
+                let (ka, kb) = (self.kind(ta)?, self.kind(tb)?);
+                if !(objects(ka) && objects(kb)
+                    || dynamics(ka) && dynamics(kb)
+                    || self.safe_cast(tb, ta))
+                {
+                    self.reg(a, tb)?;
+                }
+                self.jump(pos, offset)
+            }
+            Instr::JAlways { offset } => self.jump(pos, offset),
+
+            Instr::ToDyn { dst, src } => {
+                self.dynamic(dst)?;
+                let ty = self.ty(self.rtype(src)?)?;
+                if is_dynamic(ty) {
+                    // dynamic values are cast, not wrapped
+                    self.reg_kind(src, TypeKind::HI32)?;
+                }
+                Ok(())
+            }
+            Instr::ToSFloat { dst, src } | Instr::ToUFloat { dst, src } => {
+                self.float(dst)?;
+                self.numeric(src)
+            }
+            Instr::ToInt { dst, src } => {
+                self.int(dst)?;
+                self.numeric(src)
+            }
+            Instr::SafeCast { dst, src } => {
+                self.rtype(dst)?;
+                self.rtype(src).map(drop)
+            }
+            Instr::UnsafeCast { dst, src } => {
+                self.dynamic(dst)?;
+                self.dynamic(src)
+            }
+            Instr::ToVirtual { dst, src } => {
+                self.reg_kind(dst, TypeKind::HVIRTUAL)?;
+                let kind = self.rkind(src)?;
+                self.expect(
+                    matches!(
+                        kind,
+                        TypeKind::HOBJ | TypeKind::HDYNOBJ | TypeKind::HDYN | TypeKind::HVIRTUAL
+                    ),
+                    src,
+                    "an object",
+                )
+            }
+
+            Instr::Label {} | Instr::EndTrap { .. } | Instr::Assert {} | Instr::Nop {} => Ok(()),
+            Instr::Ret { reg } => {
+                let (_, ret) = self.fun_sig(self.f.t).ok_or("function type is not a function")?;
+                self.reg(reg, ret)
+            }
+            Instr::Throw { exc } | Instr::Rethrow { exc } => self.reg_kind(exc, TypeKind::HDYN),
+            Instr::Switch {
+                reg,
+                ref targets,
+                end,
+            } => {
+                self.reg_kind(reg, TypeKind::HI32)?;
+                for t in targets {
+                    self.jump(pos, *t as i32)?;
+                }
+                // a switch that ends the function jumps to its very end
+                if pos + 1 + end as usize != self.f.ops.len() {
+                    self.jump(pos, end as i32)?;
+                }
+                Ok(())
+            }
+            Instr::NullCheck { reg } => self.rtype(reg).map(drop),
+            Instr::Trap { exc, offset } => {
+                self.reg_kind(exc, TypeKind::HDYN)?;
+                self.jump(pos, offset)
+            }
+
+            Instr::GetI8 { dst, bytes, index } | Instr::GetI16 { dst, bytes, index } => {
+                self.reg_kind(dst, TypeKind::HI32)?;
+                self.reg_kind(bytes, TypeKind::HBYTES)?;
+                self.reg_kind(index, TypeKind::HI32)
+            }
+            Instr::GetMem { dst, bytes, index } => {
+                let kind = self.rkind(dst)?;
+                self.expect(
+                    matches!(kind, TypeKind::HI32 | TypeKind::HI64) || is_float(kind),
+                    dst,
+                    "numeric",
+                )?;
+                self.reg_kind(bytes, TypeKind::HBYTES)?;
+                self.reg_kind(index, TypeKind::HI32)
+            }
+            Instr::GetArray { dst, array, index } => {
+                self.rtype(dst)?;
+                self.reg_kind(array, TypeKind::HARRAY)?;
+                self.reg_kind(index, TypeKind::HI32)
+            }
+            Instr::SetI8 { bytes, index, src } | Instr::SetI16 { bytes, index, src } => {
+                self.reg_kind(bytes, TypeKind::HBYTES)?;
+                self.reg_kind(index, TypeKind::HI32)?;
+                self.reg_kind(src, TypeKind::HI32)
+            }
+            Instr::SetMem { bytes, index, src } => {
+                self.reg_kind(bytes, TypeKind::HBYTES)?;
+                self.reg_kind(index, TypeKind::HI32)?;
+                let kind = self.rkind(src)?;
+                self.expect(
+                    matches!(kind, TypeKind::HI32 | TypeKind::HI64) || is_float(kind),
+                    src,
+                    "numeric",
+                )
+            }
+            Instr::SetArray { array, index, src } => {
+                self.rtype(src)?;
+                self.reg_kind(array, TypeKind::HARRAY)?;
+                self.reg_kind(index, TypeKind::HI32)
+            }
+
+            Instr::New { dst } => {
+                let kind = self.rkind(dst)?;
+                self.expect(
+                    matches!(
+                        kind,
+                        TypeKind::HDYNOBJ | TypeKind::HVIRTUAL | TypeKind::HOBJ | TypeKind::HSTRUCT
+                    ),
+                    dst,
+                    "an object",
+                )
+            }
+            Instr::ArraySize { dst, array } => {
+                self.reg_kind(array, TypeKind::HARRAY)?;
+                self.reg_kind(dst, TypeKind::HI32)
+            }
+            Instr::Type { dst, ty } => {
+                self.index("type", ty.0, code.types.len())?;
+                self.reg_kind(dst, TypeKind::HTYPE)
+            }
+            Instr::GetType { dst, src } => {
+                self.reg_kind(dst, TypeKind::HTYPE)?;
+                self.dynamic(src)
+            }
+            Instr::GetTID { dst, src } => {
+                self.reg_kind(dst, TypeKind::HI32)?;
+                self.reg_kind(src, TypeKind::HTYPE)
+            }
+
+            Instr::Ref { dst, src } => {
+                let t = self.rtype(src)?;
+                let ok = self.ref_param(dst)?.is_some_and(|p| self.same(p, t));
+                self.expect(ok, dst, &format!("a reference to {}", self.type_name(t)))
+            }
+            Instr::Unref { dst, src } => match self.ref_param(src)? {
+                Some(t) => self.check_into(t, dst),
+                None => self.expect(false, src, "a reference"),
+            },
+            Instr::Setref { dst, src } => match self.ref_param(dst)? {
+                Some(t) => self.reg(src, t),
+                None => self.expect(false, dst, "a reference"),
+            },
+
+            Instr::MakeEnum {
+                dst,
+                construct,
+                ref args,
+            } => {
+                let params = self.enum_params(dst, construct)?;
+                if params.len() != args.len() {
+                    return Err(format!(
+                        "constructor {} takes {} arguments, not {}",
+                        construct,
+                        params.len(),
+                        args.len()
+                    ));
+                }
+                args.iter().zip(params).try_for_each(|(a, t)| self.reg(*a, *t))
+            }
+            Instr::EnumAlloc { dst, construct } => self.enum_params(dst, construct).map(drop),
+            Instr::EnumIndex { dst, value } => {
+                self.reg_kind(dst, TypeKind::HI32)?;
+                let kind = self.rkind(value)?;
+                self.expect(kind == TypeKind::HENUM, value, "an enum")
+            }
+            Instr::EnumField {
+                dst,
+                value,
+                construct,
+                field,
+            } => {
+                let params = self.enum_params(value, construct)?;
+                let t = params
+                    .get(field as usize)
+                    .ok_or_else(|| format!("constructor {} has no field {}", construct, field))?;
+                self.check_into(*t, dst)
+            }
+            Instr::SetEnumField { value, field, src } => {
+                let params = self.enum_params(value, 0)?;
+                let t = params
+                    .get(field as usize)
+                    .ok_or_else(|| format!("constructor 0 has no field {}", field))?;
+                self.reg(src, *t)
+            }
+
+            Instr::RefData { dst, src } => {
+                self.reg_kind(src, TypeKind::HARRAY)?;
+                self.reg_kind(dst, TypeKind::HREF)
+            }
+            Instr::RefOffset { dst, reg, offset } => {
+                self.reg_kind(dst, TypeKind::HREF)?;
+                self.reg(reg, self.rtype(dst)?)?;
+                self.reg_kind(offset, TypeKind::HI32)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, verify_function};
+    use crate::code::Code;
+    use crate::op::{Op, Opcode};
+    use crate::types::{TypeId, TypeKind};
+
+    fn example() -> Code {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let buf = std::fs::read(path).expect("Could not read hashlink binary");
+        Code::read(&buf).expect("Could not decode hashlink binary")
+    }
+
+    #[test]
+    fn example_is_well_formed() {
+        let diagnostics = verify(&example());
+        assert!(diagnostics.is_empty(), "{:#?}", &diagnostics[..diagnostics.len().min(20)]);
+    }
+
+    #[test]
+    fn reports_malformed_ops() {
+        let code = example();
+        let mut f = code
+            .functions
+            .iter()
+            .find(|f| f.ops.iter().any(|o| o.op == Op::OAdd))
+            .unwrap()
+            .clone();
+        let add = f.ops.iter().position(|o| o.op == Op::OAdd).unwrap();
+        let nregs = f.nregs as i32;
+        f.ops[add].p2 = Some(nregs);
+        f.ops[0] = Opcode {
+            op: Op::OJAlways,
+            p1: Some(-5),
+            p2: None,
+            p3: None,
+            extra: Vec::new(),
+        };
+
+        let diagnostics = verify_function(&code, &f);
+        let ops: Vec<_> = diagnostics.iter().map(|d| d.op).collect();
+        assert_eq!(ops, [Some(0), Some(add)], "{:#?}", diagnostics);
+        assert_eq!(
+            diagnostics[1].to_string(),
+            format!("function #{}, op {}: register {} out of range", f.findex, add, nregs)
+        );
+    }
+
+    #[test]
+    fn reports_type_mismatches() {
+        let code = example();
+        let is_obj = |t: &TypeId| code.get_type(*t).kind == TypeKind::HOBJ;
+        let mut f = code
+            .functions
+            .iter()
+            .find(|f| f.ops.iter().any(|o| o.op == Op::OAdd) && f.regs.iter().any(is_obj))
+            .unwrap()
+            .clone();
+        // an add writing into an object register
+        let add = f.ops.iter().position(|o| o.op == Op::OAdd).unwrap();
+        let obj = f.regs.iter().position(is_obj).unwrap();
+        f.ops[add].p1 = Some(obj as i32);
+
+        let diagnostics = verify_function(&code, &f);
+        assert_eq!(diagnostics.len(), 1, "{:#?}", diagnostics);
+        assert_eq!(diagnostics[0].op, Some(add));
+        assert!(diagnostics[0].message.contains("should be numeric"));
+    }
+}
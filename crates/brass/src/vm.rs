@@ -0,0 +1,283 @@
+// Copyright 2022 Zenturi Software Co.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::mem;
+use std::rc::Rc;
+
+use crate::code::Code;
+use crate::compiler::HLModule;
+use crate::errors::VmError;
+use crate::runtime;
+use crate::verify::verify;
+
+/// A value stored in a global, typed after its `TypeKind`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Void,
+    UI8(u8),
+    UI16(u16),
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    Bool(bool),
+    /// Any of the pointer kinds, null included.
+    Ptr(*mut u8),
+}
+
+/// A verified module, ready to run its entrypoint.
+pub struct Vm {
+    module: HLModule,
+    /// The compiled entrypoint, once `run` compiled the module.
+    entrypoint: Option<extern "C" fn()>,
+}
+
+impl Vm {
+    /// Decodes any lazily read function bodies, verifies the whole module
+    /// and lays out its globals and functions for the JIT.
+    pub fn new(code: Code) -> Result<Vm, VmError> {
+        Vm::with_natives(code, &[])
+    }
+
+    /// Like `new`, but natives can also resolve to `natives`: pairs of a
+    /// symbol, `hl_<name>` for the `std` library and `<lib>_<name>`
+    /// otherwise, and the address of an `extern "C"` function with the
+    /// native's signature.
+    ///
+    /// The runtime provides `hl_sys_args` itself; a native given here takes
+    /// precedence over it.
+    pub fn with_natives(mut code: Code, natives: &[(&str, *const u8)]) -> Result<Vm, VmError> {
+        code.load_functions()?;
+        let diagnostics = verify(&code);
+        if !diagnostics.is_empty() {
+            return Err(VmError::Invalid(diagnostics));
+        }
+        let entrypoint = code.entrypoint as usize;
+        if code.function_position(entrypoint).is_none() {
+            return Err(VmError::NoEntrypoint(entrypoint));
+        }
+        let mut module = HLModule::with_natives(Rc::new(code), natives);
+        module.init(false)?;
+        Ok(Vm {
+            module,
+            entrypoint: None,
+        })
+    }
+
+    pub fn code(&self) -> &Code {
+        &self.module.code
+    }
+
+    /// Reads global `index`.
+    pub fn global(&self, index: usize) -> Result<Value, VmError> {
+        self.module.get_global(index)
+    }
+
+    /// Writes global `index`, failing if `value` does not match its type.
+    pub fn set_global(&mut self, index: usize, value: Value) -> Result<(), VmError> {
+        self.module.set_global(index, value)
+    }
+
+    /// Addresses of the globals holding references, for a collector to scan.
+    pub fn gc_roots(&self) -> impl Iterator<Item = *mut *mut u8> + '_ {
+        self.module.gc_roots()
+    }
+
+    /// Runs the entrypoint, compiling every function on the first run. The
+    /// program reads `args` through the `sys_args` native. An exception
+    /// reaching the entrypoint gives `VmError::Uncaught`.
+    pub fn run(&mut self, args: &[String]) -> Result<(), VmError> {
+        let entrypoint = match self.entrypoint {
+            Some(f) => f,
+            None => self.compile()?,
+        };
+        runtime::set_sys_args(&self.module.code, args);
+        entrypoint();
+        match self.module.take_exception() {
+            Some(message) => Err(VmError::Uncaught(message)),
+            None => Ok(()),
+        }
+    }
+
+    fn compile(&mut self) -> Result<extern "C" fn(), VmError> {
+        for index in 0..self.module.code.functions.len() {
+            self.module.compile_function(index)?;
+        }
+        self.module.finalize();
+        let findex = self.module.code.entrypoint as usize;
+        let id = self.module.functions_ptrs[findex].ok_or(VmError::NoEntrypoint(findex))?;
+        let p = self.module.function_ptr(id);
+        // the verifier checked that the entrypoint takes no arguments
+        let f = unsafe { mem::transmute::<*const u8, extern "C" fn()>(p) };
+        self.entrypoint = Some(f);
+        Ok(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Value, Vm};
+    use crate::code::Code;
+    use crate::errors::VmError;
+    use crate::runtime::Array;
+
+    #[test]
+    fn new_rejects_missing_entrypoint() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/../../example/bin/test.hl");
+        let mut code = Code::from_path(path).expect("Could not decode hashlink binary");
+        assert!(Vm::new(code.clone()).is_ok());
+
+        code.entrypoint = u32::MAX;
+        assert!(matches!(Vm::new(code), Err(VmError::NoEntrypoint(_))));
+    }
+
+    /// The entrypoint throws unless fun 1 computes `n!`.
+    fn factorial_module(n: i32, expected: i32) -> Code {
+        Code::assemble(&format!(
+            "
+            fun 0 : fun() -> void
+                regs void, i32, i32, i32, dynamic
+                int r1, {n}
+                call1 r2, 1, r1
+                int r3, {expected}
+                jeq r2, r3, ok
+                null r4
+                throw r4
+            ok:
+                ret r0
+            end
+
+            fun 1 : fun(i32) -> i32
+                regs i32, i32, i32
+                int r1, 1
+                jslte r0, r1, done
+                mov r2, r0
+                decr r2
+                call1 r1, 1, r2
+                mul r1, r0, r1
+            done:
+                ret r1
+            end
+            "
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn run_entrypoint() {
+        let mut vm = Vm::new(factorial_module(5, 120)).unwrap();
+        assert!(vm.run(&[]).is_ok());
+        // a second run reuses the compiled entrypoint
+        assert!(vm.run(&[]).is_ok());
+    }
+
+    #[test]
+    fn globals() {
+        let code = Code::assemble(
+            "
+            global i32
+            global dynamic
+
+            fun 0 : fun() -> void
+                regs void
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let mut vm = Vm::new(code).unwrap();
+        assert_eq!(vm.global(0).unwrap(), Value::I32(0));
+        vm.set_global(0, Value::I32(42)).unwrap();
+        assert_eq!(vm.global(0).unwrap(), Value::I32(42));
+        assert!(matches!(
+            vm.set_global(1, Value::I32(1)),
+            Err(VmError::InvalidGlobal(1))
+        ));
+        assert_eq!(vm.gc_roots().count(), 1);
+    }
+
+    #[test]
+    fn run_reports_uncaught_exceptions() {
+        let mut vm = Vm::new(factorial_module(5, 24)).unwrap();
+        assert!(matches!(vm.run(&[]), Err(VmError::Uncaught(_))));
+
+        let code = Code::assemble(
+            "
+            fun 0 : fun() -> void
+                regs void, dynamic
+                null r1
+                call0 r0, 1
+                throw r1
+            end
+
+            fun 1 : fun() -> void
+                regs void, dynamic
+                null r1
+                throw r1
+            end
+            ",
+        )
+        .unwrap();
+        let mut vm = Vm::new(code).unwrap();
+        match vm.run(&[]) {
+            Err(VmError::Uncaught(message)) => assert_eq!(message, "null"),
+            r => panic!("expected an uncaught exception, got {:?}", r.err()),
+        }
+    }
+
+    /// Whether `args` holds the strings "a" and "b c".
+    extern "C" fn check_args(args: *const Array) -> bool {
+        let expected = ["a", "b c"];
+        unsafe {
+            let data = args.add(1) as *const *const u16;
+            (*args).size as usize == expected.len()
+                && expected.iter().enumerate().all(|(i, e)| {
+                    let s = *data.add(i);
+                    let len = (0..).take_while(|&j| *s.add(j) != 0).count();
+                    String::from_utf16_lossy(std::slice::from_raw_parts(s, len)) == *e
+                })
+        }
+    }
+
+    #[test]
+    fn natives_and_args() {
+        let code = Code::assemble(
+            "
+            native std sys_args 1 : fun() -> array
+            native test check_args 2 : fun(array) -> bool
+
+            fun 0 : fun() -> void
+                regs void, array, bool, dynamic
+                call0 r1, 1
+                call1 r2, 2, r1
+                jtrue r2, ok
+                null r3
+                throw r3
+            ok:
+                ret r0
+            end
+            ",
+        )
+        .unwrap();
+        let natives = [("test_check_args", check_args as *const u8)];
+        let mut vm = Vm::with_natives(code.clone(), &natives).unwrap();
+        let args = ["a".to_string(), "b c".to_string()];
+        assert!(vm.run(&args).is_ok());
+        assert!(matches!(vm.run(&args[..1]), Err(VmError::Uncaught(_))));
+
+        // without the native the call cannot be compiled
+        let mut vm = Vm::new(code).unwrap();
+        assert!(matches!(vm.run(&args), Err(VmError::Unsupported(_))));
+    }
+}